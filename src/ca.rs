@@ -0,0 +1,155 @@
+use std::{fs::OpenOptions, io::{ErrorKind, Write}, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}, str::FromStr, time::{Duration, SystemTime}};
+
+use p12_keystore::{EncryptionAlgorithm, KeyStore, KeyStoreEntry, MacAlgorithm, PrivateKeyChain};
+use rcgen::{BasicConstraints, CertificateParams, CidrSubnet, DistinguishedName, DnType, GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints};
+use ring::digest::{digest, SHA256};
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+
+use crate::{proxy::tls::operator::random_serial, CaExportArguments, CaInitArguments};
+
+/// Creates a root CA to be used as operator CA. The key is written next to the certificate with the `.key`
+/// extension.
+pub fn init_ca(args : &CaInitArguments) -> std::io::Result<()> {
+    let cert_path = PathBuf::from(&args.output);
+    let key_path = cert_path.with_extension("key");
+    if !args.force && (cert_path.exists() || key_path.exists()) {
+        return Err(invalid(format!("{} already exists, use --force to replace it", cert_path.to_string_lossy())))
+    }
+    let key = args.key_type.generate().map_err(|e| invalid(format!("Cannot generate the key: {e}")))?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = parse_subject(&args.subject)?;
+    // The operator CA signs the server certificates directly
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    params.serial_number = Some(random_serial().ok_or_else(|| invalid("Cannot generate the serial number".into()))?.into());
+    let now = SystemTime::now();
+    params.not_before = (now - Duration::from_secs(24 * 3600)).into();
+    params.not_after = (now + Duration::from_secs(args.days * 24 * 3600)).into();
+    if !args.permit.is_empty() || !args.exclude.is_empty() {
+        params.name_constraints = Some(NameConstraints {
+            permitted_subtrees : args.permit.iter().map(|v| subtree(v)).collect(),
+            excluded_subtrees : args.exclude.iter().map(|v| subtree(v)).collect(),
+        });
+    }
+    let cert = params.self_signed(&key).map_err(|e| invalid(format!("Cannot sign the CA: {e}")))?;
+    if let Some(parent) = cert_path.parent().filter(|v| !v.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&cert_path, cert.pem())?;
+    write_private(&key_path, key.serialize_pem().as_bytes())?;
+    log::info!("Created the CA {} in {}, with its key in {}", args.subject, cert_path.to_string_lossy(), key_path.to_string_lossy());
+    Ok(())
+}
+
+/// Writes the certificate of a CA as PEM, DER and PKCS#12 to install it in the clients. The key is only added to
+/// the PKCS#12 bundle when asked.
+pub fn export_ca(args : &CaExportArguments) -> std::io::Result<()> {
+    let cert_path = Path::new(&args.cert);
+    let der = CertificateDer::from_pem_file(cert_path).map_err(|e| invalid(format!("Invalid certificate {}: {e}", args.cert)))?;
+    let stem = cert_path.file_stem().map(|v| v.to_string_lossy().to_string()).unwrap_or_else(|| "ca".into());
+    let out_dir = PathBuf::from(&args.output);
+    std::fs::create_dir_all(&out_dir)?;
+    let pem = pem::encode(&pem::Pem::new("CERTIFICATE", der.to_vec()));
+    std::fs::write(out_dir.join(format!("{stem}-cert.pem")), pem)?;
+    std::fs::write(out_dir.join(format!("{stem}-cert.der")), der.as_ref())?;
+    let certificate = p12_keystore::Certificate::from_der(der.as_ref()).map_err(|e| invalid(format!("Invalid certificate {}: {e}", args.cert)))?;
+    let mut keystore = KeyStore::new();
+    let p12_path = if args.with_key {
+        let key_path = cert_path.with_extension("key");
+        let key = KeyPair::from_pem(&std::fs::read_to_string(&key_path)?).map_err(|e| invalid(format!("Invalid key {}: {e}", key_path.to_string_lossy())))?;
+        let key_id = digest(&SHA256, der.as_ref());
+        keystore.add_entry(&stem, KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(key.serialize_der(), key_id, [certificate])));
+        out_dir.join(format!("{stem}.p12"))
+    } else {
+        keystore.add_entry(&stem, KeyStoreEntry::Certificate(certificate));
+        out_dir.join(format!("{stem}-cert.p12"))
+    };
+    let mut writer = keystore.writer(&args.password);
+    if args.legacy {
+        // Older Android, iOS and Windows versions cannot read AES encrypted bundles
+        writer = writer.encryption_algorithm(EncryptionAlgorithm::PbeWithShaAnd3KeyTripleDesCbc).mac_algorithm(MacAlgorithm::HmacSha1);
+    }
+    let p12 = writer.write().map_err(|e| invalid(format!("Cannot create the PKCS#12 bundle: {e}")))?;
+    if args.with_key {
+        write_private(&p12_path, &p12)?;
+    } else {
+        std::fs::write(&p12_path, p12)?;
+    }
+    log::info!("Exported {} to {}", args.cert, out_dir.to_string_lossy());
+    Ok(())
+}
+
+/// Parses a subject like `CN=Lab CA,O=Example,C=ES`
+fn parse_subject(subject : &str) -> std::io::Result<DistinguishedName> {
+    let mut dn = DistinguishedName::new();
+    for part in subject.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+        let (key, value) = part.split_once('=').ok_or_else(|| invalid(format!("Invalid subject component: {part}")))?;
+        let key = match key.trim().to_uppercase().as_str() {
+            "CN" => DnType::CommonName,
+            "O" => DnType::OrganizationName,
+            "OU" => DnType::OrganizationalUnitName,
+            "C" => DnType::CountryName,
+            "ST" => DnType::StateOrProvinceName,
+            "L" => DnType::LocalityName,
+            v => return Err(invalid(format!("Unknown subject attribute: {v}")))
+        };
+        dn.push(key, value.trim());
+    }
+    if dn.get(&DnType::CommonName).is_none() {
+        return Err(invalid("The subject needs a common name (CN=...)".into()))
+    }
+    Ok(dn)
+}
+
+/// Name constraint for a network (`10.0.0.0/8`) or a domain and its subdomains
+fn subtree(value : &str) -> GeneralSubtree {
+    match CidrSubnet::from_str(value) {
+        Ok(v) => GeneralSubtree::IpAddress(v),
+        Err(_) => GeneralSubtree::DnsName(value.to_string())
+    }
+}
+
+fn write_private(path : &Path, data : &[u8]) -> std::io::Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?.write_all(data)
+}
+
+fn invalid(msg : String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn should_create_and_export_ca() {
+    use crate::proxy::tls::{operator::OperatorCa, sign::KeyType};
+    let dir = std::env::temp_dir().join(format!("oxiproxy-ca-{}", std::process::id()));
+    let cert = dir.join("lab.pem").to_string_lossy().to_string();
+    let init = CaInitArguments {
+        output : cert.clone(),
+        key_type : KeyType::Rsa2048,
+        days : 30,
+        subject : "CN=Lab CA,O=Example".into(),
+        permit : vec!["lab.example".into(), "10.0.0.0/8".into()],
+        exclude : Vec::new(),
+        force : false,
+        log_level : 3,
+    };
+    init_ca(&init).unwrap();
+    assert!(init_ca(&init).is_err());
+    let params = CertificateParams::from_ca_cert_der(&CertificateDer::from_pem_file(&cert).unwrap()).unwrap();
+    assert_eq!(IsCa::Ca(BasicConstraints::Constrained(0)), params.is_ca);
+    assert_eq!(2, params.name_constraints.unwrap().permitted_subtrees.len());
+    assert!(OperatorCa::from_file(&cert).unwrap().resolve("www.lab.example").is_some());
+    let export = CaExportArguments {
+        cert,
+        output : dir.join("export").to_string_lossy().to_string(),
+        password : "secret".into(),
+        with_key : true,
+        legacy : false,
+        log_level : 3,
+    };
+    export_ca(&export).unwrap();
+    let p12 = std::fs::read(dir.join("export/lab.p12")).unwrap();
+    let keystore = KeyStore::from_pkcs12(&p12, "secret").unwrap();
+    assert_eq!(1, keystore.private_key_chain().unwrap().1.chain().len());
+    assert!(dir.join("export/lab-cert.der").exists());
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,175 @@
+use std::io::ErrorKind;
+
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+use x509_parser::{certificate::X509Certificate, extensions::{ParsedExtension, X509Extension}, objects::{oid2sn, oid_registry}, oid_registry::Oid, public_key::PublicKey, x509::SubjectPublicKeyInfo};
+
+use crate::CertCompareArguments;
+
+/// Field with a different value in both certificates. `None` when the certificate does not have it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertDifference {
+    pub field : String,
+    pub original : Option<String>,
+    pub clone : Option<String>,
+}
+
+/// Prints every field of the clone that differs from the original. Returns the number of differences.
+pub fn compare_certs(args : &CertCompareArguments) -> std::io::Result<usize> {
+    let original = read_cert(&args.original)?;
+    let clone = read_cert(&args.clone)?;
+    let differences = cert_differences(&original, &clone)?;
+    for diff in &differences {
+        println!("{}", diff.field);
+        println!("  original: {}", diff.original.as_deref().unwrap_or("(missing)"));
+        println!("  clone:    {}", diff.clone.as_deref().unwrap_or("(missing)"));
+    }
+    if differences.is_empty() {
+        println!("No differences");
+    }
+    Ok(differences.len())
+}
+
+/// Compares the fields of two certificates. The public keys and signatures always differ between a certificate and
+/// its clone, so only their algorithms are compared.
+pub fn cert_differences(original : &[u8], clone : &[u8]) -> std::io::Result<Vec<CertDifference>> {
+    let (_, original) = x509_parser::parse_x509_certificate(original).map_err(|e| invalid(format!("Invalid original certificate: {e}")))?;
+    let (_, clone) = x509_parser::parse_x509_certificate(clone).map_err(|e| invalid(format!("Invalid cloned certificate: {e}")))?;
+    let mut differences = Vec::new();
+    let mut field = |name : &str, original : Option<String>, clone : Option<String>| {
+        if original != clone {
+            differences.push(CertDifference { field : name.to_string(), original, clone });
+        }
+    };
+    field("version", Some(original.version().0.to_string()), Some(clone.version().0.to_string()));
+    field("serial", Some(original.raw_serial_as_string()), Some(clone.raw_serial_as_string()));
+    field("signature algorithm", Some(oid_name(&original.signature_algorithm.algorithm)), Some(oid_name(&clone.signature_algorithm.algorithm)));
+    field("issuer", Some(original.issuer().to_string()), Some(clone.issuer().to_string()));
+    field("subject", Some(original.subject().to_string()), Some(clone.subject().to_string()));
+    field("not before", Some(original.validity().not_before.to_string()), Some(clone.validity().not_before.to_string()));
+    field("not after", Some(original.validity().not_after.to_string()), Some(clone.validity().not_after.to_string()));
+    field("public key", Some(describe_key(original.public_key())), Some(describe_key(clone.public_key())));
+    for oid in extension_oids(&original, &clone) {
+        let original = original.extensions().iter().find(|v| v.oid == oid);
+        let clone = clone.extensions().iter().find(|v| v.oid == oid);
+        // Compared as DER, the description is only shown
+        if original.map(|v| (v.critical, v.value)) != clone.map(|v| (v.critical, v.value)) {
+            let (mut original_desc, mut clone_desc) = (original.map(describe_extension), clone.map(describe_extension));
+            if original_desc == clone_desc {
+                // Same meaning with another encoding
+                (original_desc, clone_desc) = (original.map(|v| hex(v.value)), clone.map(|v| hex(v.value)));
+            }
+            differences.push(CertDifference { field : oid_name(&oid), original : original_desc, clone : clone_desc });
+        }
+    }
+    Ok(differences)
+}
+
+/// Extensions of the original followed by the ones only found in the clone
+fn extension_oids(original : &X509Certificate<'_>, clone : &X509Certificate<'_>) -> Vec<Oid<'static>> {
+    let mut oids : Vec<Oid<'static>> = Vec::new();
+    for ext in original.extensions().iter().chain(clone.extensions()) {
+        if !oids.contains(&ext.oid) {
+            oids.push(ext.oid.to_owned());
+        }
+    }
+    oids
+}
+
+fn describe_extension(ext : &X509Extension<'_>) -> String {
+    let value = match ext.parsed_extension() {
+        ParsedExtension::SubjectAlternativeName(v) => v.general_names.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "),
+        ParsedExtension::KeyUsage(v) => v.to_string(),
+        ParsedExtension::ExtendedKeyUsage(v) => {
+            let known = [(v.any, "any"), (v.server_auth, "serverAuth"), (v.client_auth, "clientAuth"), (v.code_signing, "codeSigning"),
+                (v.email_protection, "emailProtection"), (v.time_stamping, "timeStamping"), (v.ocsp_signing, "OCSPSigning")];
+            known.iter().filter(|(set, _)| *set).map(|(_, name)| name.to_string()).chain(v.other.iter().map(oid_name)).collect::<Vec<_>>().join(", ")
+        },
+        ParsedExtension::BasicConstraints(v) => match v.path_len_constraint {
+            Some(len) => format!("CA:{}, pathlen:{len}", v.ca),
+            None => format!("CA:{}", v.ca)
+        },
+        ParsedExtension::SubjectKeyIdentifier(v) => hex(v.0),
+        ParsedExtension::AuthorityKeyIdentifier(v) => match &v.key_identifier {
+            Some(id) => format!("keyid:{}", hex(id.0)),
+            None => format!("{v:?}")
+        },
+        ParsedExtension::UnsupportedExtension { .. } | ParsedExtension::ParseError { .. } => hex(ext.value),
+        v => format!("{v:?}")
+    };
+    if ext.critical {
+        format!("critical, {value}")
+    } else {
+        value
+    }
+}
+
+fn describe_key(spki : &SubjectPublicKeyInfo<'_>) -> String {
+    let algorithm = oid_name(&spki.algorithm.algorithm);
+    match spki.parsed() {
+        Ok(PublicKey::RSA(v)) => format!("{algorithm} {} bits", v.key_size()),
+        Ok(PublicKey::EC(_)) => match spki.algorithm.parameters.as_ref().and_then(|v| v.as_oid().ok()) {
+            Some(curve) => format!("{algorithm} {}", oid_name(&curve)),
+            None => algorithm
+        },
+        _ => algorithm
+    }
+}
+
+/// Short name of a known OID or its dotted form
+fn oid_name(oid : &Oid<'_>) -> String {
+    match oid2sn(oid, oid_registry()) {
+        Ok(v) => v.to_string(),
+        Err(_) => oid.to_id_string()
+    }
+}
+
+fn hex(data : &[u8]) -> String {
+    data.iter().map(|v| format!("{v:02x}")).collect()
+}
+
+/// Reads the first certificate of a PEM file, or a DER certificate
+fn read_cert(path : &str) -> std::io::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if data.trim_ascii_start().starts_with(b"-----") {
+        let cert = CertificateDer::from_pem_slice(&data).map_err(|e| invalid(format!("Invalid certificate {path}: {e}")))?;
+        return Ok(cert.to_vec())
+    }
+    Ok(data)
+}
+
+fn invalid(msg : String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn should_clone_every_field() {
+    use rcgen::{BasicConstraints, CertificateParams, CrlDistributionPoint, CustomExtension, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose};
+    use crate::proxy::tls::resolv::{clone_ca_cert, clone_end_cert};
+    let root_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "Upstream Root");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+    let root = params.self_signed(&root_key).unwrap();
+    let mut params = CertificateParams::new(vec!["example.com".to_string(), "*.example.com".to_string(), "10.0.0.1".to_string()]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "example.com");
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+    params.crl_distribution_points = vec![CrlDistributionPoint { uris : vec!["http://crl.example.com/root.crl".into()] }];
+    params.use_authority_key_identifier_extension = true;
+    // Certificate policies with the domain validated policy
+    params.custom_extensions = vec![CustomExtension::from_oid_content(&[2, 5, 29, 32], vec![0x30, 0x0a, 0x30, 0x08, 0x06, 0x06, 0x67, 0x81, 0x0c, 0x01, 0x02, 0x01])];
+    let leaf_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+    let leaf = params.signed_by(&leaf_key, &root, &root_key).unwrap();
+
+    let (root_clone, root_clone_key) = clone_ca_cert(root.der()).unwrap();
+    let (leaf_clone, _) = clone_end_cert(leaf.der(), &root_clone, &root_clone_key).unwrap();
+    assert_eq!(Vec::<CertDifference>::new(), cert_differences(root.der(), root_clone.der()).unwrap());
+    assert_eq!(Vec::<CertDifference>::new(), cert_differences(leaf.der(), leaf_clone.der()).unwrap());
+
+    // rcgen alone loses the policies and the CRL URLs
+    let rcgen_clone = CertificateParams::from_ca_cert_der(leaf.der()).unwrap().signed_by(&leaf_key, &root, &root_key).unwrap();
+    let fields : Vec<String> = cert_differences(leaf.der(), rcgen_clone.der()).unwrap().into_iter().map(|v| v.field).collect();
+    assert!(fields.contains(&"crlDistributionPoints".to_string()));
+    assert!(fields.contains(&"certificatePolicies".to_string()));
+}
@@ -0,0 +1,549 @@
+use std::{net::{IpAddr, SocketAddr, ToSocketAddrs}, str::FromStr, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{proxy::{conn::{common::ConnTimeouts, egress::EgressMode, route::DstPattern}, limit::ConnLimits, scap::common::{ScapFilter, ScapProtocol}, tls::store::{CertMode, CertModes}, ListenMode}, ProxyArguments};
+
+/// Configuration of the proxy. Loaded from the TOML file given with `--config`, with the command line flags
+/// overriding its values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Log level. 1=ERROR, 2=Warning, 3=Info, 4=Debug, 5=Trace
+    pub log_level : u8,
+    /// Folder with all the ROOT CA certificates
+    pub root_ca : Option<String>,
+    /// PEM certificate of the operator CA. Its key is read from the file with the `.key` extension
+    pub operator_ca : Option<String>,
+    /// Where to save SCAPs (Socket Captures). Nothing is saved if not set
+    pub trace_folder : Option<String>,
+    /// Folder keeping the generated certificates between restarts. Disabled if not set
+    pub cert_cache : Option<String>,
+    /// Domains and IPs that are never intercepted
+    pub pinned_domains : Vec<String>,
+    /// Address of the admin API: `host:port` or `unix:/path/to/socket`. Disabled if not set
+    pub admin : Option<String>,
+    /// Address serving only the Prometheus metrics, with the same format as `admin`
+    pub metrics : Option<String>,
+    #[serde(rename = "listener")]
+    pub listeners : Vec<ListenerConfig>,
+    pub egress : EgressConfig,
+    /// Destinations that use a different egress than the default one
+    #[serde(rename = "route")]
+    pub routes : Vec<RouteConfig>,
+    pub workers : WorkersConfig,
+    pub timeouts : TimeoutsConfig,
+    pub limits : LimitsConfig,
+    pub capture : CaptureConfig,
+    pub certificates : CertificatesConfig,
+    /// Command line the configuration was loaded with, to load it again on SIGHUP
+    #[serde(skip)]
+    pub arguments : Option<ProxyArguments>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    #[serde(default = "default_listen_mode")]
+    pub mode : ListenMode,
+    pub addr : String,
+    pub port : u16,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EgressConfig {
+    /// Defaults to socks5 when a SOCKS5 server is given and to direct otherwise
+    pub mode : Option<EgressMode>,
+    pub socks5_server : Option<String>,
+    pub socks5_user : Option<String>,
+    pub socks5_password : Option<String>,
+    pub http_proxy : Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Destination patterns, see `DstPattern`
+    pub hosts : Vec<String>,
+    pub egress : EgressMode,
+    /// Overrides the SOCKS5 server of the egress section
+    pub socks5_server : Option<String>,
+    /// Overrides the HTTP proxy of the egress section
+    pub http_proxy : Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkersConfig {
+    pub min : u16,
+    pub max : u16,
+    /// Connections waiting for a worker before new ones are reset
+    pub queue_high_water : usize,
+}
+
+/// Timeouts in seconds. 0 disables them, except the idle one
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub connect : u64,
+    pub handshake : u64,
+    pub idle : u64,
+    pub lifetime : u64,
+    /// Time given to the open connections to finish when stopping the proxy
+    pub shutdown : u64,
+}
+
+/// Connection limits. 0 means unlimited
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_conns : usize,
+    pub max_conns_per_source : usize,
+    pub max_conns_per_destination : usize,
+    pub conn_rate : f64,
+    pub conn_rate_per_source : f64,
+}
+
+/// How the certificates presented to the clients are obtained
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CertificatesConfig {
+    /// Mode of the destinations not listed
+    pub mode : CertMode,
+    /// Destinations whose certificates are signed by the operator CA, see `DstPattern`
+    pub operator : Vec<String>,
+    /// Destinations whose chains are cloned
+    pub clone : Vec<String>,
+}
+
+/// Which connections are captured. Addresses are an IP or IP:port
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    pub src_include : Vec<String>,
+    pub src_exclude : Vec<String>,
+    pub dst_include : Vec<String>,
+    pub dst_exclude : Vec<String>,
+    /// Only capture these protocols: Http, Tcp, Tls, Udp or Ssh
+    pub protocols : Vec<String>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            log_level : 3,
+            root_ca : None,
+            operator_ca : None,
+            trace_folder : None,
+            cert_cache : None,
+            pinned_domains : Vec::new(),
+            admin : None,
+            metrics : None,
+            listeners : Vec::new(),
+            egress : EgressConfig::default(),
+            routes : Vec::new(),
+            workers : WorkersConfig::default(),
+            timeouts : TimeoutsConfig::default(),
+            limits : LimitsConfig::default(),
+            capture : CaptureConfig::default(),
+            certificates : CertificatesConfig::default(),
+            arguments : None,
+        }
+    }
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        Self {
+            min : 8,
+            max : 128,
+            queue_high_water : 1024
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            connect : 10,
+            handshake : 10,
+            idle : 300,
+            lifetime : 0,
+            shutdown : 30
+        }
+    }
+}
+
+fn default_listen_mode() -> ListenMode {
+    ListenMode::Redirect
+}
+
+impl ProxyConfig {
+    /// Reads the configuration file, if any, and applies the command line flags over it
+    pub fn load(args : &ProxyArguments) -> std::io::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default()
+        };
+        config.merge_args(args)?;
+        config.validate()?;
+        config.arguments = Some(args.clone());
+        Ok(config)
+    }
+
+    /// Loads again the configuration file and the command line flags this configuration was loaded with
+    pub fn reload(&self) -> std::io::Result<Self> {
+        match &self.arguments {
+            Some(args) => Self::load(args),
+            None => Ok(self.clone())
+        }
+    }
+
+    /// Settings that differ from `other` and are only applied when the proxy starts
+    pub fn restart_required(&self, other : &ProxyConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.listeners != other.listeners {
+            changed.push("listener");
+        }
+        if self.workers != other.workers {
+            changed.push("workers");
+        }
+        if self.timeouts != other.timeouts {
+            changed.push("timeouts");
+        }
+        if self.limits != other.limits {
+            changed.push("limits");
+        }
+        if self.trace_folder != other.trace_folder {
+            changed.push("trace_folder");
+        }
+        if self.cert_cache != other.cert_cache {
+            changed.push("cert_cache");
+        }
+        if self.log_level != other.log_level {
+            changed.push("log_level");
+        }
+        if self.admin != other.admin {
+            changed.push("admin");
+        }
+        if self.metrics != other.metrics {
+            changed.push("metrics");
+        }
+        changed
+    }
+
+    pub fn from_file(path : &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(format!("Cannot read {path}: {e}")))?;
+        Self::from_toml(&text).map_err(|e| invalid(format!("{path}: {e}")))
+    }
+
+    /// Parses the TOML configuration. Errors name the key with the invalid value.
+    pub fn from_toml(text : &str) -> std::io::Result<Self> {
+        let deserializer = toml::Deserializer::new(text);
+        serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let path = e.path().to_string();
+            let inner = e.into_inner();
+            let message = inner.message().to_string();
+            let position = inner.span().map(|span| format!(" (line {})", text[..span.start].lines().count().max(1))).unwrap_or_default();
+            if path == "." {
+                invalid(format!("{message}{position}"))
+            } else {
+                invalid(format!("{path}: {message}{position}"))
+            }
+        })
+    }
+
+    fn merge_args(&mut self, args : &ProxyArguments) -> std::io::Result<()> {
+        if let Some(port) = args.port {
+            let addr = args.addr.clone().ok_or_else(|| invalid("addr: required when --port is given".into()))?;
+            // The listener of the command line replaces the ones of the file
+            self.listeners = vec![ListenerConfig {
+                mode : args.mode.unwrap_or(ListenMode::Redirect),
+                addr,
+                port
+            }];
+        } else if args.addr.is_some() || args.mode.is_some() {
+            return Err(invalid("port: required when --addr or --mode are given".into()))
+        }
+        if !args.pinned_domain.is_empty() {
+            self.pinned_domains = args.pinned_domain.clone();
+        }
+        set(&mut self.log_level, args.log_level);
+        set_option(&mut self.root_ca, &args.root_ca);
+        set_option(&mut self.operator_ca, &args.operator_ca);
+        set(&mut self.certificates.mode, args.cert_mode);
+        set_option(&mut self.trace_folder, &args.trace_folder);
+        set_option(&mut self.cert_cache, &args.cert_cache);
+        set_option(&mut self.admin, &args.admin);
+        set_option(&mut self.metrics, &args.metrics);
+        set(&mut self.workers.max, args.workers);
+        set(&mut self.workers.min, args.min_workers);
+        set(&mut self.workers.queue_high_water, args.queue_high_water);
+        set(&mut self.limits.max_conns, args.max_conns);
+        set(&mut self.limits.max_conns_per_source, args.max_conns_per_source);
+        set(&mut self.limits.max_conns_per_destination, args.max_conns_per_destination);
+        set(&mut self.limits.conn_rate, args.conn_rate);
+        set(&mut self.limits.conn_rate_per_source, args.conn_rate_per_source);
+        set_option(&mut self.egress.mode, &args.egress);
+        set_option(&mut self.egress.socks5_server, &args.socks5_server);
+        set_option(&mut self.egress.socks5_user, &args.socks5_user);
+        set_option(&mut self.egress.socks5_password, &args.socks5_password);
+        set_option(&mut self.egress.http_proxy, &args.http_proxy);
+        set(&mut self.timeouts.connect, args.connect_timeout);
+        set(&mut self.timeouts.handshake, args.handshake_timeout);
+        set(&mut self.timeouts.idle, args.idle_timeout);
+        set(&mut self.timeouts.lifetime, args.lifetime_timeout);
+        set(&mut self.timeouts.shutdown, args.shutdown_timeout);
+        Ok(())
+    }
+
+    /// Checks the values that the file format cannot express
+    pub fn validate(&self) -> std::io::Result<()> {
+        if self.listeners.is_empty() {
+            return Err(invalid("listener: at least one listener is needed, use [[listener]] or --port and --addr".into()))
+        }
+        let certificates = &self.certificates;
+        if self.root_ca.is_none() && (certificates.mode == CertMode::Clone || !certificates.clone.is_empty()) {
+            return Err(invalid("root_ca: missing, use root_ca or --root-ca".into()))
+        }
+        if self.operator_ca.is_none() && (certificates.mode == CertMode::Operator || !certificates.operator.is_empty()) {
+            return Err(invalid("operator_ca: missing, use operator_ca or --operator-ca".into()))
+        }
+        for (key, list) in [("operator", &certificates.operator), ("clone", &certificates.clone)] {
+            for (i, host) in list.iter().enumerate() {
+                DstPattern::from_str(host).map_err(|e| invalid(format!("certificates.{key}[{i}]: {e}")))?;
+            }
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if listener.addr.is_empty() {
+                return Err(invalid(format!("listener[{i}].addr: empty address")))
+            }
+        }
+        check_api_addr("admin", self.admin.as_ref())?;
+        check_api_addr("metrics", self.metrics.as_ref())?;
+        check_egress("egress", self.egress.mode(), self.egress.socks5_server.as_ref(), self.egress.http_proxy.as_ref())?;
+        for (i, route) in self.routes.iter().enumerate() {
+            for (j, host) in route.hosts.iter().enumerate() {
+                DstPattern::from_str(host).map_err(|e| invalid(format!("route[{i}].hosts[{j}]: {e}")))?;
+            }
+            let socks5 = route.socks5_server.as_ref().or(self.egress.socks5_server.as_ref());
+            let http = route.http_proxy.as_ref().or(self.egress.http_proxy.as_ref());
+            check_egress(&format!("route[{i}]"), route.egress, socks5, http)?;
+        }
+        if self.workers.max == 0 {
+            return Err(invalid("workers.max: at least one worker is needed".into()))
+        }
+        if self.workers.min > self.workers.max {
+            return Err(invalid("workers.min: greater than workers.max".into()))
+        }
+        if self.workers.queue_high_water == 0 {
+            return Err(invalid("workers.queue_high_water: must be greater than 0".into()))
+        }
+        if self.timeouts.idle == 0 {
+            return Err(invalid("timeouts.idle: must be greater than 0".into()))
+        }
+        if self.limits.conn_rate.is_nan() || self.limits.conn_rate < 0.0 {
+            return Err(invalid("limits.conn_rate: must be a positive number".into()))
+        }
+        if self.limits.conn_rate_per_source.is_nan() || self.limits.conn_rate_per_source < 0.0 {
+            return Err(invalid("limits.conn_rate_per_source: must be a positive number".into()))
+        }
+        self.capture.filter()?;
+        Ok(())
+    }
+
+    pub fn timeouts(&self) -> ConnTimeouts {
+        ConnTimeouts {
+            connect : optional_secs(self.timeouts.connect),
+            handshake : optional_secs(self.timeouts.handshake),
+            idle : Duration::from_secs(self.timeouts.idle),
+            lifetime : optional_secs(self.timeouts.lifetime)
+        }
+    }
+
+    /// Time given to the open connections to finish when stopping the proxy
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.shutdown)
+    }
+
+    pub fn limits(&self) -> ConnLimits {
+        let limit = |v : usize| if v == 0 { None } else { Some(v) };
+        let rate = |v : f64| if v <= 0.0 { None } else { Some(v) };
+        ConnLimits {
+            global : limit(self.limits.max_conns),
+            per_source : limit(self.limits.max_conns_per_source),
+            per_destination : limit(self.limits.max_conns_per_destination),
+            rate : rate(self.limits.conn_rate),
+            rate_per_source : rate(self.limits.conn_rate_per_source),
+        }
+    }
+}
+
+impl EgressConfig {
+    pub fn mode(&self) -> EgressMode {
+        self.mode.unwrap_or(if self.socks5_server.is_some() { EgressMode::Socks5 } else { EgressMode::Direct })
+    }
+}
+
+impl RouteConfig {
+    pub fn patterns(&self) -> Vec<DstPattern> {
+        self.hosts.iter().filter_map(|v| DstPattern::from_str(v).ok()).collect()
+    }
+}
+
+impl CertificatesConfig {
+    pub fn modes(&self) -> CertModes {
+        let rules = |list : &Vec<String>, mode : CertMode| list.iter().filter_map(|v| DstPattern::from_str(v).ok()).map(|v| (v, mode)).collect::<Vec<_>>();
+        let mut modes = rules(&self.clone, CertMode::Clone);
+        modes.extend(rules(&self.operator, CertMode::Operator));
+        CertModes {
+            default : self.mode,
+            rules : modes
+        }
+    }
+}
+
+impl CaptureConfig {
+    pub fn filter(&self) -> std::io::Result<ScapFilter> {
+        let addresses = |key : &str, list : &Vec<String>| -> std::io::Result<Vec<(IpAddr, u16)>> {
+            list.iter().enumerate().map(|(i, v)| filter_address(v).ok_or_else(|| invalid(format!("capture.{key}[{i}]: invalid address {v}")))).collect()
+        };
+        let mut protocols = Vec::with_capacity(self.protocols.len());
+        for (i, v) in self.protocols.iter().enumerate() {
+            protocols.push(protocol_of(v).ok_or_else(|| invalid(format!("capture.protocols[{i}]: unknown protocol {v}")))?);
+        }
+        Ok(ScapFilter {
+            src_in : addresses("src_include", &self.src_include)?,
+            src_ex : addresses("src_exclude", &self.src_exclude)?,
+            dst_in : addresses("dst_include", &self.dst_include)?,
+            dst_ex : addresses("dst_exclude", &self.dst_exclude)?,
+            protocols
+        })
+    }
+}
+
+fn check_egress(key : &str, mode : EgressMode, socks5 : Option<&String>, http : Option<&String>) -> std::io::Result<()> {
+    match mode {
+        EgressMode::Socks5 if socks5.is_none() => Err(invalid(format!("{key}.socks5_server: required by the socks5 egress"))),
+        EgressMode::Http if http.is_none() => Err(invalid(format!("{key}.http_proxy: required by the http egress"))),
+        _ => Ok(())
+    }
+}
+
+/// `host:port` or `unix:/path/to/socket`
+fn check_api_addr(key : &str, addr : Option<&String>) -> std::io::Result<()> {
+    let addr = match addr {
+        Some(v) => v,
+        None => return Ok(())
+    };
+    match addr.strip_prefix("unix:") {
+        Some("") => Err(invalid(format!("{key}: empty socket path"))),
+        None if addr.to_socket_addrs().is_err() => Err(invalid(format!("{key}: invalid address {addr}, expected host:port or unix:/path"))),
+        _ => Ok(())
+    }
+}
+
+/// IP or IP:port, port 0 meaning any
+fn filter_address(value : &str) -> Option<(IpAddr, u16)> {
+    if let Ok(addr) = SocketAddr::from_str(value) {
+        return Some((addr.ip(), addr.port()))
+    }
+    IpAddr::from_str(value).ok().map(|ip| (ip, 0))
+}
+
+fn protocol_of(value : &str) -> Option<ScapProtocol> {
+    Some(match value.to_lowercase().as_str() {
+        "http" => ScapProtocol::Http,
+        "tcp" => ScapProtocol::Tcp,
+        "tls" => ScapProtocol::Tls,
+        "udp" => ScapProtocol::Udp,
+        "dns" => ScapProtocol::Dns,
+        "ssh" => ScapProtocol::Ssh,
+        _ => return None
+    })
+}
+
+/// Seconds to a timeout, 0 meaning no timeout
+pub fn optional_secs(secs : u64) -> Option<Duration> {
+    if secs == 0 { None } else { Some(Duration::from_secs(secs)) }
+}
+
+fn set<T : Copy>(value : &mut T, arg : Option<T>) {
+    if let Some(v) = arg {
+        *value = v;
+    }
+}
+
+fn set_option<T : Clone>(value : &mut Option<T>, arg : &Option<T>) {
+    if arg.is_some() {
+        value.clone_from(arg);
+    }
+}
+
+fn invalid(msg : String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+#[test]
+fn should_load_config_file() {
+    let config = ProxyConfig::from_toml(r#"
+root_ca = "./outcerts"
+pinned_domains = ["example.com"]
+
+[[listener]]
+mode = "socks5"
+addr = "127.0.0.1"
+port = 1080
+
+[[listener]]
+addr = "0.0.0.0"
+port = 8443
+
+[egress]
+socks5_server = "127.0.0.1:9050"
+
+[[route]]
+hosts = ["*.internal", "10.0.0.0/8"]
+egress = "direct"
+
+[timeouts]
+idle = 60
+
+[capture]
+dst_exclude = ["10.0.0.1:443"]
+protocols = ["http", "tls"]
+"#).unwrap();
+    config.validate().unwrap();
+    assert_eq!(2, config.listeners.len());
+    assert_eq!(ListenMode::Redirect, config.listeners[1].mode);
+    assert_eq!(EgressMode::Socks5, config.egress.mode());
+    assert_eq!(Duration::from_secs(60), config.timeouts().idle);
+    assert_eq!(Some(Duration::from_secs(10)), config.timeouts().connect);
+    assert_eq!(2, config.routes[0].patterns().len());
+    assert_eq!(vec![ScapProtocol::Http, ScapProtocol::Tls], config.capture.filter().unwrap().protocols);
+}
+
+#[test]
+fn should_name_the_invalid_key() {
+    let err = ProxyConfig::from_toml("[[listener]]\naddr = \"127.0.0.1\"\nport = \"http\"\n").unwrap_err();
+    assert!(err.to_string().starts_with("listener[0].port:"), "{err}");
+    let err = ProxyConfig::from_toml("[timeouts]\nidel = 5\n").unwrap_err();
+    assert!(err.to_string().starts_with("timeouts.idel:"), "{err}");
+    let mut config = ProxyConfig::from_toml("root_ca = \"ca\"\n[[listener]]\naddr = \"127.0.0.1\"\nport = 1080\n[[route]]\nhosts = [\"10.0.0.0/40\"]\negress = \"direct\"\n").unwrap();
+    assert!(config.validate().unwrap_err().to_string().starts_with("route[0].hosts[0]:"));
+    config.routes.clear();
+    config.capture.src_exclude.push("10.0.0.300".into());
+    assert!(config.validate().unwrap_err().to_string().starts_with("capture.src_exclude[0]:"));
+}
+
+#[test]
+fn should_select_certificate_modes() {
+    use crate::proxy::conn::dst::Destination;
+    let config = ProxyConfig::from_toml("operator_ca = \"ca.pem\"\n[[listener]]\naddr = \"127.0.0.1\"\nport = 1080\n[certificates]\nmode = \"operator\"\nclone = [\"*.example.com\"]\n").unwrap();
+    assert!(config.validate().unwrap_err().to_string().starts_with("root_ca:"));
+    let modes = config.certificates.modes();
+    let dst = Destination::Addr("10.0.0.1:443".parse().unwrap());
+    assert_eq!(CertMode::Clone, modes.mode_of(&dst, "api.example.com"));
+    assert_eq!(CertMode::Operator, modes.mode_of(&dst, "lab.local"));
+}
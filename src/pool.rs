@@ -1,71 +1,160 @@
-use std::thread::JoinHandle;
+use std::{panic::AssertUnwindSafe, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
 
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
 
+/// Elastic pool of worker threads. Starts with `min_workers` and spawns a new one each time a job arrives and no
+/// worker is idle, up to `max_workers`. Workers over the minimum exit after `keep_alive` without work. A job is
+/// rejected when `high_water` jobs are already waiting.
 pub struct ProxyThreadPool<T : Send + 'static, F : WorkGen<T>> {
-    pub workers : u16,
-    pub handles : Vec<JoinHandle<()>>,
+    pub min_workers : u16,
+    pub max_workers : u16,
+    pub keep_alive : Duration,
+    pub sender : Sender<T>,
     pub channel : Receiver<T>,
-    pub spawner : F
+    pub spawner : Arc<F>,
+    pub counters : Arc<PoolCounters>,
+}
+
+/// Live state of the pool shared with the workers
+#[derive(Debug, Default)]
+pub struct PoolCounters {
+    /// Running worker threads
+    pub alive : AtomicUsize,
+    /// Workers waiting for a job
+    pub idle : AtomicUsize,
+    /// Identifier of the next spawned worker
+    next_id : AtomicUsize,
 }
 
 pub trait Runner<T : Send + 'static> {
     fn run(&mut self, v : T);
 }
 
-pub trait WorkGen<T : Send + 'static> {
+pub trait WorkGen<T : Send + 'static> : Send + Sync + 'static {
     fn gen(&self) -> impl Runner<T> + Send + 'static;
 }
 
 impl<T : Send + 'static,  F : WorkGen<T>> ProxyThreadPool<T, F> {
-    pub fn new(workers : u16, channel : Receiver<T>, f : F) -> Self {
+    pub fn new(min_workers : u16, max_workers : u16, high_water : usize, f : F) -> Self {
+        let (sender, channel) = bounded(high_water);
         Self {
-            workers,
-            handles : Vec::with_capacity(workers as usize),
+            min_workers : min_workers.min(max_workers),
+            max_workers,
+            keep_alive : Duration::from_secs(60),
+            sender,
             channel,
-            spawner : f
+            spawner : Arc::new(f),
+            counters : Arc::new(PoolCounters::default())
         }
     }
 
     pub fn init(&mut self) -> Result<(), std::io::Error> {
-        for i in 0..self.workers {
-            let handle = self.spawn_worker(i)?;
-            self.handles.push(handle);
+        for _ in 0..self.min_workers {
+            self.spawn_worker()?;
         }
         Ok(())
     }
-    fn spawn_worker(&self, id : u16) -> Result<JoinHandle<()>, std::io::Error> {
+
+    /// Queues a job, growing the pool if every worker is busy. Returns the job back if the queue is full.
+    pub fn submit(&self, work : T) -> Result<(), T> {
+        if let Err(e) = self.sender.try_send(work) {
+            return Err(match e {
+                TrySendError::Full(v) | TrySendError::Disconnected(v) => v
+            })
+        }
+        if self.counters.idle.load(Ordering::Acquire) == 0 && self.counters.alive.load(Ordering::Acquire) < self.max_workers as usize {
+            if let Err(e) = self.spawn_worker() {
+                log::error!("Cannot spawn a new worker: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Jobs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.channel.len()
+    }
+
+    fn spawn_worker(&self) -> Result<(), std::io::Error> {
         let receiver = self.channel.clone();
-        let mut worker = self.spawner.gen();
-        std::thread::Builder::new().name(format!("PxyWorker{}",id)).spawn(move || {
+        let spawner = self.spawner.clone();
+        let counters = self.counters.clone();
+        let min_workers = self.min_workers as usize;
+        let keep_alive = self.keep_alive;
+        let id = counters.next_id.fetch_add(1, Ordering::Relaxed);
+        counters.alive.fetch_add(1, Ordering::AcqRel);
+        counters.idle.fetch_add(1, Ordering::AcqRel);
+        let res = std::thread::Builder::new().name(format!("PxyWorker{}",id)).spawn(move || {
+            let mut worker = spawner.gen();
             loop {
-                let work = match receiver.recv() {
+                let work = match receiver.recv_timeout(keep_alive) {
                     Ok(v) => v,
-                    Err(_) => break
+                    Err(RecvTimeoutError::Timeout) => {
+                        // Only the workers over the minimum leave
+                        let left = counters.alive.fetch_update(Ordering::AcqRel, Ordering::Acquire, |v| if v > min_workers { Some(v - 1) } else { None });
+                        if left.is_ok() {
+                            log::debug!("Worker {} stopped after being idle", id);
+                            counters.idle.fetch_sub(1, Ordering::AcqRel);
+                            return
+                        }
+                        continue
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break
                 };
-                worker.run(work);
-            }
-        })
-    }
-    pub fn revive(&mut self) {
-        let mut pos = 0;
-        let mut finished_threads = Vec::new();
-        for worker in self.handles.iter_mut() {
-            if worker.is_finished() {
-                finished_threads.push(pos);
+                counters.idle.fetch_sub(1, Ordering::AcqRel);
+                if std::panic::catch_unwind(AssertUnwindSafe(|| worker.run(work))).is_err() {
+                    // The state of the worker cannot be trusted after a panic
+                    log::error!("Worker {} panicked, replacing it", id);
+                    worker = spawner.gen();
+                }
+                counters.idle.fetch_add(1, Ordering::AcqRel);
             }
-            pos += 1;
+            counters.idle.fetch_sub(1, Ordering::AcqRel);
+            counters.alive.fetch_sub(1, Ordering::AcqRel);
+        });
+        if let Err(e) = res {
+            self.counters.alive.fetch_sub(1, Ordering::AcqRel);
+            self.counters.idle.fetch_sub(1, Ordering::AcqRel);
+            return Err(e)
         }
-        for id in finished_threads {
-            let handle = match self.spawn_worker(pos) {
-                Ok(v) => v,
-                Err(_) => return
-            };
-            let worker = match self.handles.get_mut(id as usize) {
-                Some(v) => v,
-                None => continue
-            };
-            *worker = handle;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct PanicSpawner(Sender<u32>);
+
+#[cfg(test)]
+struct PanicRunner(Sender<u32>);
+
+#[cfg(test)]
+impl WorkGen<u32> for PanicSpawner {
+    fn gen(&self) -> impl Runner<u32> + Send + 'static {
+        PanicRunner(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+impl Runner<u32> for PanicRunner {
+    fn run(&mut self, v : u32) {
+        if v == 0 {
+            panic!("Job failed");
         }
+        let _ = self.0.send(v);
+    }
+}
+
+#[test]
+fn should_survive_panics_and_grow() {
+    let (sender, receiver) = bounded(16);
+    let mut pool = ProxyThreadPool::new(1, 4, 16, PanicSpawner(sender));
+    pool.init().unwrap();
+    pool.submit(0).unwrap();
+    for i in 1..=8 {
+        pool.submit(i).unwrap();
     }
+    let mut done : Vec<u32> = (0..8).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+    done.sort();
+    assert_eq!((1..=8).collect::<Vec<u32>>(), done);
+    assert!(pool.counters.alive.load(Ordering::Acquire) <= 4);
 }
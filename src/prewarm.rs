@@ -0,0 +1,54 @@
+use std::{collections::BTreeSet, net::{TcpStream, ToSocketAddrs}, sync::{Arc, Mutex}, time::Duration};
+
+use rustls::ClientConnection;
+use rustls_pki_types::{DnsName, ServerName};
+
+use crate::{proxy::tls::store::TlsCertStore, PrewarmArguments};
+
+/// Connects to each domain and stores its cloned chain in the certificate cache, so the proxy does not generate it
+/// on the first connection. The servers are reached directly, without the egress of the proxy.
+pub fn prewarm_certs(args : &PrewarmArguments) -> std::io::Result<()> {
+    let mut domains = args.domain.clone();
+    if let Some(input) = &args.input {
+        let text = std::fs::read_to_string(input)?;
+        domains.extend(text.lines().map(|v| v.trim()).filter(|v| !v.is_empty() && !v.starts_with('#')).map(|v| v.to_string()));
+    }
+    let tls = TlsCertStore::new(Some(&args.root_ca), Arc::new(Mutex::new(BTreeSet::new())), Some(&args.cert_cache))?;
+    let timeout = Duration::from_secs(args.timeout);
+    let mut cached = 0;
+    for domain in &domains {
+        match prewarm_domain(&tls, domain, timeout) {
+            Ok(_) => {
+                log::info!("Cached the certificate of {domain}");
+                cached += 1;
+            },
+            Err(e) => log::warn!("Cannot cache the certificate of {domain}: {e}")
+        }
+    }
+    log::info!("{cached} of {} certificates cached in {}", domains.len(), args.cert_cache);
+    Ok(())
+}
+
+/// Handshakes with `host[:port]` (443 by default) and clones its chain
+fn prewarm_domain(tls : &TlsCertStore, domain : &str, timeout : Duration) -> std::io::Result<()> {
+    let (host, port) = match domain.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid(format!("Invalid port: {port}")))?),
+        None => (domain, 443)
+    };
+    let host = host.to_lowercase();
+    let name = DnsName::try_from(host.as_str()).map_err(|_| invalid(format!("Invalid DNS name: {host}")))?.to_owned();
+    let addr = (host.as_str(), port).to_socket_addrs()?.next().ok_or_else(|| invalid(format!("Cannot resolve {host}")))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut conn = ClientConnection::new(tls.cconfig.clone(), ServerName::DnsName(name)).map_err(|e| invalid(e.to_string()))?;
+    conn.complete_io(&mut stream)?;
+    match tls.resolver.resolve(&conn, &host) {
+        Some(_) => Ok(()),
+        None => Err(invalid("the chain cannot be cloned with the ROOT CAs".into()))
+    }
+}
+
+fn invalid(msg : String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,243 @@
+use std::{io::{Error, ErrorKind, Read, Write}, net::{TcpListener, TcpStream}, os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}}, sync::Arc, time::Duration};
+
+use httparse::{Status, EMPTY_HEADER};
+use serde_json::{json, Value};
+
+use super::{conn::registry::{ActiveConn, ConnRegistry}, http::server::MAX_HEAD_SIZE, metrics::metrics, scap::common::ScapStore, tls::store::TlsCertStore};
+
+/// Maximum size of a request body sent to the admin API
+const MAX_BODY_SIZE : usize = 16_384;
+
+/// Time allowed to send a request to the admin API
+const REQUEST_TIMEOUT : Duration = Duration::from_secs(5);
+
+/// Local HTTP API to inspect and control the running proxy. Every response is JSON except the metrics:
+///
+/// - `GET /connections`, `GET /connections/{id}`, `DELETE /connections/{id}`
+/// - `GET /pinned`, `PUT /pinned/{domain}`, `DELETE /pinned/{domain}`
+/// - `GET /certificates`, `DELETE /certificates/{name}`
+/// - `GET /capture`, `PUT /capture` with `{"enabled": bool}`
+/// - `GET /metrics` in the Prometheus text format
+#[derive(Clone)]
+pub struct AdminApi {
+    pub registry : Arc<ConnRegistry>,
+    pub tls : TlsCertStore,
+    pub scap : ScapStore,
+    /// Only serves `/metrics`, for listeners reachable by the Prometheus server
+    pub metrics_only : bool,
+}
+
+struct AdminRequest {
+    method : String,
+    path : String,
+    body : Vec<u8>,
+}
+
+struct AdminResponse {
+    code : u16,
+    content_type : &'static str,
+    body : String,
+}
+
+impl AdminApi {
+    pub fn new(registry : Arc<ConnRegistry>, tls : TlsCertStore, scap : ScapStore) -> Self {
+        Self { registry, tls, scap, metrics_only : false }
+    }
+
+    /// Same API restricted to `/metrics`
+    pub fn metrics_only(mut self) -> Self {
+        self.metrics_only = true;
+        self
+    }
+
+    /// Serves the API in its own thread. The address is `host:port` or `unix:/path/to/socket`.
+    pub fn spawn(self, addr : &str) -> std::io::Result<()> {
+        let name = if self.metrics_only { "Metrics" } else { "Admin" };
+        let builder = std::thread::Builder::new().name(name.into());
+        if let Some(path) = addr.strip_prefix("unix:") {
+            // A socket left by a previous run would make the bind fail
+            if std::fs::symlink_metadata(path).is_ok_and(|v| v.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            log::info!("{name} API listening on {addr}");
+            builder.spawn(move || self.accept_loop(listener.incoming(), UnixStream::set_read_timeout))?;
+        } else {
+            let listener = TcpListener::bind(addr)?;
+            log::info!("{name} API listening on {addr}");
+            builder.spawn(move || self.accept_loop(listener.incoming(), TcpStream::set_read_timeout))?;
+        }
+        Ok(())
+    }
+
+    fn accept_loop<S, I>(&self, incoming : I, set_timeout : fn(&S, Option<Duration>) -> std::io::Result<()>)
+    where
+        S : Read + Write,
+        I : Iterator<Item = std::io::Result<S>>
+    {
+        for stream in incoming {
+            match stream {
+                Ok(mut stream) => {
+                    let _ = set_timeout(&stream, Some(REQUEST_TIMEOUT));
+                    self.serve(&mut stream);
+                },
+                Err(e) => log::error!("Cannot accept admin connection: {e}")
+            }
+        }
+    }
+
+    /// Answers a single request and closes the connection
+    fn serve<S : Read + Write>(&self, stream : &mut S) {
+        let res = match read_request(stream) {
+            Ok(req) => {
+                let res = self.handle(&req);
+                log::debug!("Admin API: {} {} -> {}", req.method, req.path, res.code);
+                res
+            },
+            Err(e) => AdminResponse::error(400, &e.to_string())
+        };
+        if let Err(e) = write_response(stream, &res) {
+            log::debug!("Cannot send admin response: {e}");
+        }
+    }
+
+    fn handle(&self, req : &AdminRequest) -> AdminResponse {
+        let path = req.path.split('?').next().unwrap_or_default();
+        let segments : Vec<&str> = path.split('/').filter(|v| !v.is_empty()).collect();
+        if self.metrics_only && segments != ["metrics"] {
+            return AdminResponse::error(404, "Not found")
+        }
+        match (req.method.as_str(), segments.as_slice()) {
+            ("GET", ["metrics"]) => {
+                metrics().active.set(self.registry.len() as i64);
+                metrics().scap_queued.set(self.scap.queued() as i64);
+                AdminResponse::text(metrics().render())
+            },
+            ("GET", ["connections"]) => AdminResponse::ok(json!(self.registry.list())),
+            ("GET", ["connections", id]) => match self.connection(id) {
+                Some(conn) => AdminResponse::ok(json!(conn.summary())),
+                None => AdminResponse::error(404, "Unknown connection")
+            },
+            ("DELETE", ["connections", id]) => match self.connection(id) {
+                Some(conn) => {
+                    log::info!("Closing connection {} from {} by admin request", conn.id, conn.client);
+                    conn.kill();
+                    AdminResponse::ok(json!(conn.summary()))
+                },
+                None => AdminResponse::error(404, "Unknown connection")
+            },
+            ("GET", ["pinned"]) => AdminResponse::ok(json!(self.tls.pinned_list())),
+            ("PUT", ["pinned", domain]) => {
+                self.tls.disable_addr(domain.to_lowercase());
+                AdminResponse::ok(json!(self.tls.pinned_list()))
+            },
+            ("DELETE", ["pinned", domain]) => {
+                if !self.tls.enable_addr(&domain.to_lowercase()) {
+                    return AdminResponse::error(404, "Not pinned")
+                }
+                AdminResponse::ok(json!(self.tls.pinned_list()))
+            },
+            ("GET", ["certificates"]) => AdminResponse::ok(json!(self.tls.generated())),
+            ("DELETE", ["certificates", name]) => {
+                if !self.tls.evict(&name.to_lowercase()) {
+                    return AdminResponse::error(404, "No certificate generated for the name")
+                }
+                AdminResponse::ok(json!(self.tls.generated()))
+            },
+            ("GET", ["capture"]) => AdminResponse::ok(self.capture_status()),
+            ("PUT", ["capture"]) => {
+                let enabled = match serde_json::from_slice::<Value>(&req.body).ok().and_then(|v| v.get("enabled").and_then(Value::as_bool)) {
+                    Some(v) => v,
+                    None => return AdminResponse::error(400, "Expected {\"enabled\": true|false}")
+                };
+                log::info!("Capture {} by admin request", if enabled { "enabled" } else { "disabled" });
+                self.scap.set_enabled(enabled);
+                AdminResponse::ok(self.capture_status())
+            },
+            (_, ["connections", ..] | ["pinned", ..] | ["certificates", ..] | ["capture"] | ["metrics"]) => AdminResponse::error(405, "Method not allowed"),
+            _ => AdminResponse::error(404, "Not found")
+        }
+    }
+
+    fn connection(&self, id : &str) -> Option<Arc<ActiveConn>> {
+        self.registry.get(id.parse().ok()?)
+    }
+
+    fn capture_status(&self) -> Value {
+        json!({
+            "enabled" : self.scap.is_enabled(),
+            "queued" : self.scap.queued()
+        })
+    }
+}
+
+impl AdminResponse {
+    fn ok(body : Value) -> Self {
+        Self::json(200, body)
+    }
+
+    fn error(code : u16, message : &str) -> Self {
+        Self::json(code, json!({ "error" : message }))
+    }
+
+    fn json(code : u16, body : Value) -> Self {
+        Self { code, content_type : "application/json", body : format!("{body}\n") }
+    }
+
+    fn text(body : String) -> Self {
+        Self { code : 200, content_type : "text/plain; version=0.0.4", body }
+    }
+}
+
+fn read_request<S : Read>(stream : &mut S) -> std::io::Result<AdminRequest> {
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let readed = stream.read(&mut chunk)?;
+        if readed == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed before the request head"))
+        }
+        buffer.extend_from_slice(&chunk[0..readed]);
+        let mut headers = [EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        let body_start = match request.parse(&buffer) {
+            Ok(Status::Complete(v)) => v,
+            Ok(Status::Partial) if buffer.len() > MAX_HEAD_SIZE => return Err(Error::new(ErrorKind::InvalidData, "Request head too large")),
+            Ok(Status::Partial) => continue,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid request: {e}")))
+        };
+        let length = request.headers.iter()
+            .find(|v| v.name.eq_ignore_ascii_case("content-length"))
+            .and_then(|v| std::str::from_utf8(v.value).ok()?.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        if length > MAX_BODY_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "Request body too large"))
+        }
+        let method = request.method.unwrap_or_default().to_uppercase();
+        let path = request.path.unwrap_or_default().to_string();
+        let mut body = buffer[body_start..].to_vec();
+        while body.len() < length {
+            let readed = stream.read(&mut chunk)?;
+            if readed == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed before the request body"))
+            }
+            body.extend_from_slice(&chunk[0..readed]);
+        }
+        body.truncate(length);
+        return Ok(AdminRequest { method, path, body })
+    }
+}
+
+fn write_response<S : Write>(stream : &mut S, res : &AdminResponse) -> std::io::Result<()> {
+    let reason = match res.code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Error"
+    };
+    let head = format!("HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", res.code, res.content_type, res.body.len());
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(res.body.as_bytes())?;
+    stream.flush()
+}
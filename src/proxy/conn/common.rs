@@ -1,24 +1,50 @@
 use std::{
     io::{ErrorKind, Read, Write},
-    net::{SocketAddr, TcpStream},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::proxy::{
     conn::stream::original_dst,
-    scap::common::{ScapProtocol, ScapSender, ScapStoreRef},
-    socks5::client::Socks5Client,
+    limit::{ConnLimiter, ConnPermit},
+    metrics::{error_reason, metrics, tls_failure, ByteCounters},
+    http::{forward::{CloseResponse, RequestFraming, SingleRequest}, server::HttpProxyServer},
+    scap::common::{ScapProtocol, ScapSender, ScapStoreRef, ScapTimeout},
+    socks5::{common::REP_SUCCEEDED, server::{Socks5Command, Socks5Server}},
     tls::store::TlsCertStore,
+    ListenMode,
 };
-use rustls::{ClientConnection, ServerConnection, StreamOwned as TlsStream};
+use rustls::{server::{Accepted, Acceptor}, ClientConnection, StreamOwned as TlsStream};
 use rustls_pki_types::{DnsName, ServerName};
 
-use super::{mitm::MitmStreamer, stream::NonBlock};
+use super::{dst::Destination, egress::{UpstreamConnector, UpstreamStream}, mitm::MitmStreamer, registry::{ActiveConn, ConnRegistry}, sniff::{sniff, SNIFF_TIMEOUT}, splice::SpliceStreamer, stream::{NonBlock, Rewind}, udp::UdpRelay};
 
 pub struct ConnectionState {
     pub buffer: Vec<u8>,
     pub scap: ScapStoreRef,
     pub conn_buffers: ConnectionBuffers,
     pub tls: TlsCertStore,
+    pub timeouts: ConnTimeouts,
+    /// When the current connection was accepted
+    pub started: Instant,
+    /// Entry of the current connection in the registry
+    pub conn: Option<Arc<ActiveConn>>,
+    /// Byte metrics of the protocol of the current connection
+    pub counters: Option<ByteCounters>,
+}
+
+/// Timeouts applied to the proxied connections
+#[derive(Debug, Clone, Copy)]
+pub struct ConnTimeouts {
+    /// Connection to the destination, including the negotiation with the upstream proxy
+    pub connect: Option<Duration>,
+    /// Each read or write of the proxy and TLS handshakes
+    pub handshake: Option<Duration>,
+    /// Time without traffic in any direction before the connection is closed
+    pub idle: Duration,
+    /// Maximum duration of a connection
+    pub lifetime: Option<Duration>,
 }
 
 pub struct ConnectionBuffers {
@@ -31,9 +57,30 @@ pub struct ConnectionBuffers {
     pub times_zero: usize,
 }
 
+/// Client accepted by the listener, already connected to the upstream
+pub struct AcceptedClient {
+    pub dst: Destination,
+    pub upstream: Box<dyn UpstreamStream>,
+    pub stream: Rewind<TcpStream>,
+    /// Protocol announced by the client, if the listener knows it
+    pub protocol: Option<ScapProtocol>,
+    /// Plain request of the explicit HTTP proxy: only that request is relayed
+    pub framing: Option<RequestFraming>,
+}
+
 pub struct ProxyConnectionManager {
     state: ConnectionState,
-    socks5: String,
+    egress: Arc<dyn UpstreamConnector>,
+    limiter: Arc<ConnLimiter>,
+    registry: Arc<ConnRegistry>,
+    /// Slot of the destination of the current connection
+    dst_permit: Option<ConnPermit>,
+}
+
+impl Default for ConnectionBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConnectionBuffers {
@@ -56,77 +103,258 @@ impl ConnectionBuffers {
     }
 }
 
+impl AcceptedClient {
+    pub fn new(dst: Destination, upstream: Box<dyn UpstreamStream>, stream: Rewind<TcpStream>, protocol: Option<ScapProtocol>) -> Self {
+        Self {
+            dst,
+            upstream,
+            stream,
+            protocol,
+            framing: None,
+        }
+    }
+}
+
+impl Default for ConnTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(10)),
+            handshake: Some(Duration::from_secs(10)),
+            idle: Duration::from_secs(300),
+            lifetime: None,
+        }
+    }
+}
+
 impl ConnectionState {
-    pub fn new(scap: ScapStoreRef, tls: TlsCertStore) -> Self {
+    pub fn new(scap: ScapStoreRef, tls: TlsCertStore, timeouts: ConnTimeouts) -> Self {
         Self {
             buffer: vec![0; 4096],
             conn_buffers: ConnectionBuffers::new(),
             scap,
             tls,
+            timeouts,
+            started: Instant::now(),
+            conn: None,
+            counters: None,
         }
     }
     pub fn clear(&mut self) {
         self.conn_buffers.clear();
     }
+
+    /// Counts the bytes relayed from the client to the server
+    pub fn count_sent(&self, bytes: usize) {
+        if let Some(conn) = &self.conn {
+            conn.add_sent(bytes);
+        }
+        if let Some(counters) = &self.counters {
+            counters.sent.inc_by(bytes as u64);
+        }
+    }
+
+    /// Counts the bytes relayed from the server to the client
+    pub fn count_received(&self, bytes: usize) {
+        if let Some(conn) = &self.conn {
+            conn.add_received(bytes);
+        }
+        if let Some(counters) = &self.counters {
+            counters.received.inc_by(bytes as u64);
+        }
+    }
 }
 
 impl ProxyConnectionManager {
-    pub fn new(pcap_store: ScapStoreRef, tls_store: TlsCertStore, socks5: String) -> Self {
+    pub fn new(pcap_store: ScapStoreRef, tls_store: TlsCertStore, egress: Arc<dyn UpstreamConnector>, timeouts: ConnTimeouts, limiter: Arc<ConnLimiter>, registry: Arc<ConnRegistry>) -> Self {
         Self {
-            state: ConnectionState::new(pcap_store, tls_store),
-            socks5,
+            state: ConnectionState::new(pcap_store, tls_store, timeouts),
+            egress,
+            limiter,
+            registry,
+            dst_permit: None,
         }
     }
-    pub fn from_state(mut state: ConnectionState, socks5: String) -> Self {
+    pub fn from_state(mut state: ConnectionState, egress: Arc<dyn UpstreamConnector>, limiter: Arc<ConnLimiter>, registry: Arc<ConnRegistry>) -> Self {
         state.clear();
-        Self { state, socks5 }
+        Self { state, egress, limiter, registry, dst_permit: None }
     }
     pub fn keep_state(self) -> ConnectionState {
         self.state
     }
 
-    /// Initializes a Socks5 TCP proxy
-    fn init_proxy(&mut self, dst: &SocketAddr) -> std::io::Result<Socks5Client> {
-        Socks5Client::connect(&self.socks5, dst.clone())
+    /// Opens the connection to the destination using the configured egress
+    fn init_proxy(&mut self, dst: &Destination, source: (IpAddr, u16)) -> std::io::Result<Box<dyn UpstreamStream>> {
+        // Taken by the listener or by a previous connection to the same destination, like the one replaced by a fallback
+        if self.dst_permit.is_none() {
+            self.dst_permit = Some(self.limiter.acquire_destination(dst).inspect_err(|e| {
+                log::warn!("Rejecting connection from {} to {}: {}", source.0, dst, e);
+            })?);
+        }
+        let mut upstream = self.egress.connect(dst).inspect_err(|e| {
+            self.report_timeout(e, ScapTimeout::Connect, ScapProtocol::Tcp, (dst.capture_ip(), dst.port()), source)
+        })?;
+        if let Some(conn) = &self.state.conn {
+            conn.set_destination(dst);
+            upstream = conn.track_upstream(upstream);
+        }
+        Ok(upstream)
     }
 
-    pub fn handle_client(&mut self, client_stream: TcpStream) -> std::io::Result<()> {
-        let dst = original_dst(&client_stream)?;
-        let mut proxy_connection = self.init_proxy(&dst)?;
-        proxy_connection.greet()?;
-        proxy_connection.tcp_proxy()?;
+    /// Records the timeout of a connection that ended before relaying any data
+    fn report_timeout(&self, err: &std::io::Error, timeout: ScapTimeout, protocol: ScapProtocol, remote: (IpAddr, u16), source: (IpAddr, u16)) {
+        if !matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
+            return;
+        }
+        log::debug!("{:?} timeout to {}:{}", timeout, remote.0, remote.1);
+        self.state.scap.sender(protocol, remote, source).timeout(timeout);
+    }
 
+    /// Obtains the destination requested by the client and connects to it. Returns None if the client was
+    /// already served, like a SOCKS5 UDP association.
+    fn accept_client(&mut self, mut client_stream: TcpStream, mode: ListenMode) -> std::io::Result<Option<AcceptedClient>> {
         let cp = client_stream.peer_addr()?;
+        let source = (cp.ip(), cp.port());
+        match mode {
+            ListenMode::Redirect => {
+                let dst = Destination::from(original_dst(&client_stream)?);
+                let upstream = self.init_proxy(&dst, source)?;
+                Ok(Some(AcceptedClient::new(dst, upstream, Rewind::new(client_stream), None)))
+            }
+            ListenMode::Tproxy => {
+                // The socket keeps the original destination as its local address
+                let dst = Destination::from(client_stream.local_addr()?);
+                let upstream = self.init_proxy(&dst, source)?;
+                Ok(Some(AcceptedClient::new(dst, upstream, Rewind::new(client_stream), None)))
+            }
+            ListenMode::Socks5 => {
+                let mut server = Socks5Server::new(&mut client_stream, &mut self.state.buffer);
+                server.greet()?;
+                let dst = match server.request()? {
+                    Socks5Command::Connect(dst) => dst,
+                    Socks5Command::UdpAssociate(src) => {
+                        self.udp_associate(client_stream, src)?;
+                        return Ok(None)
+                    }
+                };
+                let res = self.init_proxy(&dst, source);
+                let mut server = Socks5Server::new(&mut client_stream, &mut self.state.buffer);
+                match res {
+                    Ok(upstream) => {
+                        server.reply(REP_SUCCEEDED, upstream.local_addr().ok())?;
+                        Ok(Some(AcceptedClient::new(dst, upstream, Rewind::new(client_stream), None)))
+                    }
+                    Err(e) => {
+                        let _ = server.reply_error(&e);
+                        Err(e)
+                    }
+                }
+            }
+            ListenMode::Http => {
+                let mut server = HttpProxyServer::new(&mut client_stream);
+                let req = server.request()?;
+                let upstream = match self.init_proxy(&req.dst, source) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let _ = server.reply_error(&e);
+                        return Err(e)
+                    }
+                };
+                // The protocol inside a CONNECT tunnel is detected like in the transparent modes
+                let protocol = if req.connect {
+                    server.reply_established()?;
+                    None
+                } else {
+                    Some(ScapProtocol::Http)
+                };
+                let stream = Rewind::with_prefix(client_stream, req.pending);
+                let mut accepted = AcceptedClient::new(req.dst, upstream, stream, protocol);
+                accepted.framing = req.framing;
+                Ok(Some(accepted))
+            }
+        }
+    }
 
-        let dst_ip = dst.ip().to_string();
-        // Iniciar el proxy entre el cliente y el servidor
-        let err = if dst.port() == 443 {
-            if self.state.tls.is_disabled(&dst_ip) {
-                let mut scap = self.state.scap.sender(
-                    ScapProtocol::Tls,
-                    (dst.ip(), dst.port()),
-                    (cp.ip(), cp.port()),
-                );
-                self.mitm(dst, client_stream, proxy_connection, &mut scap)
-            } else {
-                let mut scap = self.state.scap.sender(
-                    ScapProtocol::Http,
-                    (dst.ip(), dst.port()),
-                    (cp.ip(), cp.port()),
-                );
-                self.mitm(dst, client_stream, proxy_connection, &mut scap)
+    /// Serves a SOCKS5 UDP ASSOCIATE until the client closes the control connection
+    fn udp_associate(&mut self, mut client_stream: TcpStream, src: Destination) -> std::io::Result<()> {
+        let res = self.egress.udp().and_then(|upstream| {
+            let socket = UdpSocket::bind(SocketAddr::new(client_stream.local_addr()?.ip(), 0))?;
+            Ok((upstream, socket))
+        });
+        let mut server = Socks5Server::new(&mut client_stream, &mut self.state.buffer);
+        let (upstream, socket) = match res {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = server.reply_error(&e);
+                return Err(e)
             }
-        } else if dst.port() == 80 {
+        };
+        let local = socket.local_addr()?;
+        log::debug!("UDP association at: {}", local);
+        server.reply(REP_SUCCEEDED, Some(local))?;
+        // The client can announce the address it will send the datagrams from
+        let client_addr = match src {
+            Destination::Addr(v) if !v.ip().is_unspecified() && v.port() != 0 => Some(v),
+            _ => None
+        };
+        let mut relay = UdpRelay::new(socket, &mut client_stream, upstream, self.state.scap.clone(), client_addr, self.state.timeouts.idle);
+        relay.relay()
+    }
+
+    /// Serves a client. `dst_permit` is the slot of the destination when the listener already took it.
+    pub fn handle_client(&mut self, client_stream: TcpStream, mode: ListenMode, dst_permit: Option<ConnPermit>) -> std::io::Result<()> {
+        self.dst_permit = dst_permit;
+        self.state.conn = Some(self.registry.register(&client_stream, mode)?);
+        let res = self.serve_client(client_stream, mode);
+        if let Err(e) = &res {
+            metrics().failed.with_label_values(&[error_reason(e)]).inc();
+        }
+        self.state.conn = None;
+        self.state.counters = None;
+        self.dst_permit = None;
+        res
+    }
+
+    fn serve_client(&mut self, client_stream: TcpStream, mode: ListenMode) -> std::io::Result<()> {
+        let cp = client_stream.peer_addr()?;
+        self.state.started = Instant::now();
+        // Bounds the proxy negotiation and the TLS handshake, the relay switches to non-blocking mode
+        client_stream.set_timeouts(self.state.timeouts.handshake)?;
+        let AcceptedClient { dst, upstream: proxy_connection, stream: client_stream, protocol, framing } = match self.accept_client(client_stream, mode)? {
+            Some(v) => v,
+            None => return Ok(())
+        };
+
+        let remote = (dst.capture_ip(), dst.port());
+        let protocol = match protocol {
+            Some(v) => v,
+            None => sniff(&client_stream, SNIFF_TIMEOUT)?
+        };
+        log::debug!("Detected {:?} to {}", protocol, dst);
+        if let Some(conn) = &self.state.conn {
+            conn.set_protocol(protocol);
+        }
+        self.state.counters = Some(metrics().byte_counters(protocol));
+        // Iniciar el proxy entre el cliente y el servidor
+        let err = if protocol == ScapProtocol::Tls {
+            self.mitm(&dst, client_stream, proxy_connection, remote, (cp.ip(), cp.port()))
+        } else if let Some(framing) = framing {
             let mut scap = self.state.scap.sender(
                 ScapProtocol::Http,
-                (dst.ip(), dst.port()),
+                remote,
+                (cp.ip(), cp.port()),
+            );
+            self.proxy(SingleRequest::new(client_stream, framing), CloseResponse::new(proxy_connection), &mut scap)
+        } else if protocol == ScapProtocol::Http {
+            let mut scap = self.state.scap.sender(
+                ScapProtocol::Http,
+                remote,
                 (cp.ip(), cp.port()),
             );
             self.proxy(client_stream, proxy_connection, &mut scap)
         } else {
             let mut scap = self.state.scap.sender(
-                ScapProtocol::Tcp,
-                (dst.ip(), dst.port()),
+                protocol,
+                remote,
                 (cp.ip(), cp.port()),
             );
             self.proxy(client_stream, proxy_connection, &mut scap)
@@ -140,26 +368,30 @@ impl ProxyConnectionManager {
             }
         }
         log::trace!("Connection finished!");
+        metrics().observe_connection(protocol, self.state.started.elapsed());
         Ok(())
     }
 
-    fn get_sn_of_server<B>(server: &mut TlsStream<ServerConnection, B>) -> std::io::Result<String>
+    fn proxy<C, S>(
+        &mut self,
+        mut cstream: S,
+        mut sstream: C,
+        scap: &mut ScapSender,
+    ) -> std::io::Result<()>
     where
-        B: Read + Write,
+        C: Read + Write + Send + NonBlock + 'static,
+        S: Read + Write + Send + NonBlock + 'static,
     {
-        let name;
-        loop {
-            let n = match server.conn.server_name() {
-                Some(v) => v,
-                None => continue,
-            };
-            name = n.to_string();
-            break;
+        log::debug!("No MITM");
+        if !scap.capture {
+            return self.relay_raw(cstream, sstream, scap);
         }
-        Ok(name)
+        let mut mitm = MitmStreamer::new(&mut self.state, scap);
+        mitm.intercept(&mut cstream, &mut sstream)
     }
 
-    fn proxy<C, S>(
+    /// Relays a connection whose data is not needed, with splice() when both sides are plain sockets
+    fn relay_raw<C, S>(
         &mut self,
         mut cstream: S,
         mut sstream: C,
@@ -169,54 +401,148 @@ impl ProxyConnectionManager {
         C: Read + Write + Send + NonBlock + 'static,
         S: Read + Write + Send + NonBlock + 'static,
     {
-        log::debug!("No MITM");
-        let mut mitm = MitmStreamer::new(&mut self.state, scap);
-        mitm.intercept(&mut cstream, &mut sstream)
+        if !cstream.spliceable() || !sstream.spliceable() {
+            let mut mitm = MitmStreamer::new(&mut self.state, scap);
+            return mitm.intercept(&mut cstream, &mut sstream);
+        }
+        log::trace!("Splicing connection");
+        // Bytes already read while detecting the protocol
+        let buffered = cstream.take_buffered();
+        sstream.write_all(&buffered)?;
+        self.state.count_sent(buffered.len());
+        let buffered = sstream.take_buffered();
+        cstream.write_all(&buffered)?;
+        self.state.count_received(buffered.len());
+        let mut splice = SpliceStreamer::new(&self.state, scap);
+        splice.relay(&mut cstream, &mut sstream)
+    }
+    /// Relays the TLS connection untouched, sending first the bytes already read from the client. The reason is
+    /// only used in the metrics.
+    fn passthrough<C, S>(
+        &mut self,
+        cstream: S,
+        mut sstream: C,
+        hello: &[u8],
+        remote: (IpAddr, u16),
+        source: (IpAddr, u16),
+        reason: &str,
+    ) -> std::io::Result<()>
+    where
+        C: Read + Write + Send + NonBlock + 'static,
+        S: Read + Write + Send + NonBlock + 'static,
+    {
+        metrics().tls_passthrough.with_label_values(&[reason]).inc();
+        let mut scap = self.state.scap.sender(ScapProtocol::Tls, remote, source);
+        if !hello.is_empty() {
+            sstream.write_all(hello)?;
+            scap.from_server().write_all(hello)?;
+            self.state.count_sent(hello.len());
+        }
+        // The payload of TLS captures is not stored
+        self.relay_raw(cstream, sstream, &mut scap)
     }
+
+    /// Reads the ClientHello without writing anything to the client. Returns the bytes read, so the connection can
+    /// still fall back to passthrough, and the accepted hello if it is valid.
+    fn read_client_hello<S>(cstream: &mut S) -> std::io::Result<(Vec<u8>, Option<Accepted>)>
+    where
+        S: Read,
+    {
+        let mut acceptor = Acceptor::default();
+        let mut hello = Vec::with_capacity(4096);
+        let mut buffer = [0u8; 4096];
+        loop {
+            let readed = cstream.read(&mut buffer)?;
+            if readed == 0 {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "Connection closed during the ClientHello"));
+            }
+            hello.extend_from_slice(&buffer[0..readed]);
+            let mut pending = &buffer[0..readed];
+            while !pending.is_empty() {
+                acceptor.read_tls(&mut pending)?;
+            }
+            match acceptor.accept() {
+                Ok(Some(accepted)) => return Ok((hello, Some(accepted))),
+                Ok(None) => continue,
+                Err((e, _)) => {
+                    log::debug!("Invalid ClientHello: {e}");
+                    return Ok((hello, None));
+                }
+            }
+        }
+    }
+
+    /// Relays the connection untouched through a new upstream connection, used when the first one was already
+    /// consumed by a failed interception
+    fn passthrough_new<S>(
+        &mut self,
+        dst: &Destination,
+        cstream: S,
+        hello: &[u8],
+        remote: (IpAddr, u16),
+        source: (IpAddr, u16),
+        reason: &str,
+    ) -> std::io::Result<()>
+    where
+        S: Read + Write + Send + NonBlock + 'static,
+    {
+        log::debug!("TLS passthrough to {}", dst);
+        let sstream = self.init_proxy(dst, source)?;
+        self.passthrough(cstream, sstream, hello, remote, source, reason)
+    }
+
+    /// Intercepts a TLS connection with a single upstream handshake. The ClientHello of the client is read first,
+    /// then the real server is contacted offering the same name and ALPN protocols, its chain is cloned and the
+    /// client handshake is finished with it. Until the client receives our certificate, any failure falls back to
+    /// passthrough.
     fn mitm<C, S>(
         &mut self,
-        dst: SocketAddr,
+        dst: &Destination,
         mut cstream: S,
         mut sstream: C,
-        scap: &mut ScapSender,
+        remote: (IpAddr, u16),
+        source: (IpAddr, u16),
     ) -> std::io::Result<()>
     where
         C: Read + Write + Send + NonBlock + 'static,
         S: Read + Write + Send + NonBlock + 'static,
     {
-        let dst_ip = dst.ip().to_string();
+        let dst_ip = dst.host();
         if self.state.tls.is_disabled(&dst_ip) {
-            return self.proxy(cstream, sstream, scap);
+            return self.passthrough(cstream, sstream, &[], remote, source, "pinned");
         }
-        let mut sconn = match ServerConnection::new(self.state.tls.sconfig.clone()) {
-            Ok(v) => v,
-            Err(_) => return self.proxy(cstream, sstream, scap),
+        let (hello, accepted) = Self::read_client_hello(&mut cstream).inspect_err(|e| {
+            self.report_timeout(e, ScapTimeout::Handshake, ScapProtocol::Tls, remote, source)
+        })?;
+        let accepted = match accepted {
+            Some(v) => v,
+            None => return self.passthrough(cstream, sstream, &hello, remote, source, "invalid_hello"),
         };
-        if let Err(e) = sconn.complete_io(&mut cstream) {
-            log::trace!("CompleteIO error: {e}");
-            let server_name = sconn.server_name().unwrap_or_default();
-            if !server_name.is_empty() {
-                if self.state.tls.is_disabled(&server_name) {
-                    self.state.tls.disable_addr(dst_ip.clone());
-                }
-            }
-            let err = e.to_string();
-            if err.contains("UnknownCA") {
-                log::trace!("UnknownCA for {}", server_name);
-                self.state.tls.disable_addr(server_name.to_string());
-                self.state.tls.disable_addr(dst_ip);
+        let handshake_started = Instant::now();
+        let client_hello = accepted.client_hello();
+        let name = match client_hello.server_name() {
+            Some(v) if !self.state.tls.is_disabled(v) => v.to_string(),
+            v => {
+                log::debug!("TLS passthrough to {}", dst);
+                let reason = if v.is_some() { "pinned" } else { "no_sni" };
+                return self.passthrough(cstream, sstream, &hello, remote, source, reason);
             }
-            return Err(e);
+        };
+        if let Some(conn) = &self.state.conn {
+            conn.set_sni(&name);
         }
-        let mut fake_server = TlsStream::new(sconn, cstream);
-        let name = Self::get_sn_of_server(&mut fake_server)?;
+        // HTTP/2 is not offered to the server, the captures only dissect HTTP/1.x
+        let alpn: Vec<Vec<u8>> = match client_hello.alpn() {
+            Some(v) => v.filter(|p| *p != b"h2").map(|p| p.to_vec()).collect(),
+            None => Vec::new(),
+        };
         let sn = ServerName::DnsName(
             DnsName::try_from(name.as_str())
                 .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Invalid DNS Name"))?
                 .to_lowercase_owned(),
         );
         log::trace!("Now connecting to: {}", sn.to_str());
-        let mut conn = match ClientConnection::new(self.state.tls.cconfig.clone(), sn) {
+        let mut conn = match ClientConnection::new(self.state.tls.client_config(alpn), sn) {
             Ok(v) => v,
             Err(e) => {
                 return Err(std::io::Error::new(
@@ -225,19 +551,199 @@ impl ProxyConnectionManager {
                 ))
             }
         };
-        log::trace!("Server name: {}", name);
+        sstream.set_timeouts(self.state.timeouts.handshake)?;
         if let Err(e) = conn.complete_io(&mut sstream) {
             log::trace!("Real Server CompleteIO error: {e}");
+            metrics().handshake_failures.with_label_values(&["server", &tls_failure(&e)]).inc();
+            self.report_timeout(&e, ScapTimeout::Handshake, ScapProtocol::Tls, remote, source);
+            drop(sstream);
+            return self.passthrough_new(dst, cstream, &hello, remote, source, "server_handshake");
+        }
+        let key = match self.state.tls.certificate(dst, &conn, &name) {
+            Some(v) => v,
+            None => {
+                drop(sstream);
+                return self.passthrough_new(dst, cstream, &hello, remote, source, "uncloneable");
+            }
+        };
+        let sconfig = self.state.tls.server_config(key, conn.alpn_protocol().map(|v| v.to_vec()));
+        let mut sconn = match accepted.into_connection(sconfig) {
+            Ok(v) => v,
+            Err((e, _)) => {
+                log::debug!("Cannot accept the ClientHello: {e}");
+                drop(sstream);
+                return self.passthrough_new(dst, cstream, &hello, remote, source, "client_hello");
+            }
+        };
+        // From here on the client has received our certificate and the connection cannot be rescued
+        if let Err(e) = sconn.complete_io(&mut cstream) {
+            log::trace!("CompleteIO error: {e}");
+            metrics().handshake_failures.with_label_values(&["client", &tls_failure(&e)]).inc();
+            self.report_timeout(&e, ScapTimeout::Handshake, ScapProtocol::Tls, remote, source);
             let err = e.to_string();
             if err.contains("UnknownCA") {
-                self.state.tls.disable_addr(dst.to_string());
+                log::trace!("UnknownCA for {}", name);
                 self.state.tls.disable_addr(name);
+                self.state.tls.disable_addr(dst_ip);
             }
             return Err(e);
         }
         log::debug!("Starting MITM");
+        metrics().tls_intercepted.inc();
+        metrics().handshake_duration.observe(handshake_started.elapsed().as_secs_f64());
+        if let Some(conn) = &self.state.conn {
+            conn.set_intercepted();
+        }
+        let mut fake_server = TlsStream::new(sconn, cstream);
         let mut real_server = TlsStream::new(conn, sstream);
-        let mut mitm = MitmStreamer::new(&mut self.state, scap);
+        let mut scap = self.state.scap.sender(ScapProtocol::Http, remote, source);
+        let mut mitm = MitmStreamer::new(&mut self.state, &mut scap);
         mitm.intercept(&mut fake_server, &mut real_server)
     }
 }
+
+#[test]
+fn should_send_pipelined_requests_only_to_their_host() {
+    use std::{collections::BTreeSet, net::TcpListener, sync::Mutex};
+    use crate::proxy::{conn::egress::DirectConnector, limit::ConnLimits, scap::common::ScapStore};
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+    let origin = std::thread::spawn(move || {
+        let (mut stream, _) = first.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok").unwrap();
+        received
+    });
+    let mut client = TcpStream::connect(proxy.local_addr().unwrap()).unwrap();
+    let auth = "Proxy-Authorization: Basic dXNlcjpwYXNz\r\nProxy-Connection: keep-alive\r\n";
+    client.write_all(format!("GET http://{first_addr}/a HTTP/1.1\r\nHost: {first_addr}\r\n{auth}\r\nGET http://{second_addr}/b HTTP/1.1\r\nHost: {second_addr}\r\n{auth}\r\n").as_bytes()).unwrap();
+    let (accepted, _) = proxy.accept().unwrap();
+
+    let (sender, _receiver) = crossbeam_channel::bounded(64);
+    let tls = TlsCertStore::new(None, Arc::new(Mutex::new(BTreeSet::new())), None).unwrap();
+    let egress = Arc::new(DirectConnector { timeout: Some(Duration::from_secs(5)) });
+    let mut manager = ProxyConnectionManager::new(ScapStore::new(sender).reference(), tls, egress, ConnTimeouts::default(), ConnLimiter::new(ConnLimits::default()), ConnRegistry::new());
+    manager.handle_client(accepted, ListenMode::Http, None).unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"[..], &response[..]);
+    let received = origin.join().unwrap();
+    assert_eq!(format!("GET /a HTTP/1.1\r\nHost: {first_addr}\r\nConnection: close\r\n\r\n").as_bytes(), &received[..]);
+    second.set_nonblocking(true).unwrap();
+    assert_eq!(ErrorKind::WouldBlock, second.accept().unwrap_err().kind());
+}
+
+/// Serves in another thread a client of the HTTP proxy tunneling to `origin`. Returns the client once the tunnel
+/// is established.
+#[cfg(test)]
+fn spawn_tunnel(tls: TlsCertStore, origin: SocketAddr) -> (TcpStream, std::thread::JoinHandle<std::io::Result<()>>) {
+    use std::net::TcpListener;
+    use crate::proxy::{conn::egress::DirectConnector, limit::ConnLimits, scap::common::ScapStore};
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(proxy.local_addr().unwrap()).unwrap();
+    client.write_all(format!("CONNECT {origin} HTTP/1.1\r\nHost: {origin}\r\n\r\n").as_bytes()).unwrap();
+    let (accepted, _) = proxy.accept().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let egress = Arc::new(DirectConnector { timeout: Some(Duration::from_secs(5)) });
+        let mut manager = ProxyConnectionManager::new(ScapStore::new(sender).reference(), tls, egress, ConnTimeouts::default(), ConnLimiter::new(ConnLimits::default()), ConnRegistry::new());
+        manager.handle_client(accepted, ListenMode::Http, None)
+    });
+    let mut established = [0u8; 39];
+    client.read_exact(&mut established).unwrap();
+    assert_eq!(&b"HTTP/1.1 200 Connection Established\r\n\r\n"[..], &established[..]);
+    (client, handle)
+}
+
+#[test]
+fn should_pass_through_the_original_client_hello() {
+    use std::{collections::BTreeSet, net::TcpListener, sync::Mutex};
+    let pinned = Arc::new(Mutex::new(BTreeSet::from(["pinned.example".to_string()])));
+    let tls = TlsCertStore::new(None, pinned, None).unwrap();
+    let cases = [
+        (ServerName::IpAddress(IpAddr::from([127, 0, 0, 1]).into()), false),
+        (ServerName::try_from("pinned.example").unwrap(), false),
+        // The server does not finish the handshake, the client is relayed through a new connection
+        (ServerName::try_from("localhost").unwrap(), true),
+    ];
+    for (name, failed_handshake) in cases {
+        let mut conn = ClientConnection::new(tls.cconfig.clone(), name.clone()).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, proxy) = spawn_tunnel(tls.clone(), origin.local_addr().unwrap());
+        client.write_all(&hello).unwrap();
+        if failed_handshake {
+            let (mut rejected, _) = origin.accept().unwrap();
+            let mut buffer = [0u8; 5];
+            rejected.read_exact(&mut buffer).unwrap();
+        }
+        let (mut stream, _) = origin.accept().unwrap();
+        let mut received = vec![0u8; hello.len()];
+        stream.read_exact(&mut received).unwrap();
+        assert!(received == hello, "{name:?}");
+        drop(stream);
+        // The end of the server reaches the client, which closes its side too
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        drop(client);
+        proxy.join().unwrap().unwrap();
+        origin.set_nonblocking(true).unwrap();
+        assert_eq!(ErrorKind::WouldBlock, origin.accept().unwrap_err().kind(), "{name:?}");
+    }
+}
+
+#[test]
+fn should_intercept_with_a_single_upstream_handshake() {
+    use std::{collections::BTreeSet, net::TcpListener, sync::Mutex};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{pki_types::PrivatePkcs8KeyDer, ClientConfig, RootCertStore, ServerConfig, ServerConnection};
+    use crate::proxy::tls::{operator::OperatorCa, store::{CertMode, CertModes}};
+    let dir = std::env::temp_dir().join(format!("oxiproxy-mitm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    std::fs::write(dir.join("ca.key"), ca_key.serialize_pem()).unwrap();
+    let tls = TlsCertStore::new(None, Arc::new(Mutex::new(BTreeSet::new())), None).unwrap();
+    tls.set_operator(Some(OperatorCa::from_file(&dir.join("ca.pem").to_string_lossy()).unwrap()));
+    tls.set_modes(CertModes { default: CertMode::Operator, rules: Vec::new() });
+    let _ = std::fs::remove_dir_all(dir);
+
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let server_config = ServerConfig::builder().with_no_client_auth()
+        .with_single_cert(vec![generated.cert.der().clone()], PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()).into()).unwrap();
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (client, proxy) = spawn_tunnel(tls, origin.local_addr().unwrap());
+    let origin = std::thread::spawn(move || {
+        let (stream, _) = origin.accept().unwrap();
+        let mut stream = TlsStream::new(ServerConnection::new(Arc::new(server_config)).unwrap(), stream);
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(b"pong").unwrap();
+        stream.conn.send_close_notify();
+        stream.flush().unwrap();
+        (origin, request)
+    });
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.der().clone()).unwrap();
+    let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let mut client = TlsStream::new(ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap()).unwrap(), client);
+    client.write_all(b"ping").unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert_eq!(b"pong", &reply[..]);
+    drop(client);
+    proxy.join().unwrap().unwrap();
+    let (origin, request) = origin.join().unwrap();
+    assert_eq!(b"ping", &request);
+    // The server saw only the connection of the interception
+    origin.set_nonblocking(true).unwrap();
+    assert_eq!(ErrorKind::WouldBlock, origin.accept().unwrap_err().kind());
+}
@@ -0,0 +1,75 @@
+use std::{fmt::Display, net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs}};
+
+use crate::proxy::socks5::common::Socks5Address;
+
+/// Destination of a proxied connection. Explicit proxy clients (SOCKS5, HTTP) can ask for a domain name
+/// instead of an IP, in which case the name resolution is left to the egress.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Destination {
+    pub fn from_socks5(addr : &Socks5Address, port : u16) -> Self {
+        match addr {
+            Socks5Address::V4(v) => Self::Addr(SocketAddr::new(IpAddr::V4(*v), port)),
+            Socks5Address::V6(v) => Self::Addr(SocketAddr::new(IpAddr::V6(*v), port)),
+            Socks5Address::Domain(v) => Self::Domain(v.to_lowercase(), port),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Destination::Addr(v) => v.port(),
+            Destination::Domain(_, port) => *port,
+        }
+    }
+
+    /// IP address or domain name without the port
+    pub fn host(&self) -> String {
+        match self {
+            Destination::Addr(v) => v.ip().to_string(),
+            Destination::Domain(v, _) => v.clone(),
+        }
+    }
+
+    /// IP address used in the socket captures. Domains are not resolved locally, so they are stored as 0.0.0.0
+    pub fn capture_ip(&self) -> IpAddr {
+        match self {
+            Destination::Addr(v) => v.ip(),
+            Destination::Domain(_, _) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+
+    pub fn to_socks5(&self) -> Socks5Address {
+        match self {
+            Destination::Addr(v) => v.ip().into(),
+            Destination::Domain(v, _) => Socks5Address::Domain(v.clone()),
+        }
+    }
+
+    pub fn resolve(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Destination::Addr(v) => Ok(*v),
+            Destination::Domain(v, port) => (v.as_str(), *port).to_socket_addrs()?.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot resolve {v}"))
+            }),
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(value: SocketAddr) -> Self {
+        Self::Addr(value)
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Destination::Addr(v) => f.write_fmt(format_args!("{}", v)),
+            Destination::Domain(v, port) => f.write_fmt(format_args!("{}:{}", v, port)),
+        }
+    }
+}
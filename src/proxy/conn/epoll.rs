@@ -0,0 +1,60 @@
+use std::{os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, time::Duration};
+
+pub use libc::{epoll_event, EPOLLERR, EPOLLHUP, EPOLLIN, EPOLLOUT};
+
+/// Minimal wrapper over a level-triggered epoll instance
+pub struct Epoll {
+    fd : OwnedFd,
+}
+
+impl Epoll {
+    pub fn new() -> std::io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(Self { fd : unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    pub fn add(&self, fd : RawFd, token : u64, events : i32) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    pub fn modify(&self, fd : RawFd, token : u64, events : i32) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    pub fn delete(&self, fd : RawFd) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn ctl(&self, op : i32, fd : RawFd, token : u64, events : i32) -> std::io::Result<()> {
+        let mut event = epoll_event { events : events as u32, u64 : token };
+        if unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Waits for readiness events. Returns the number of events filled, 0 on timeout or interruption.
+    pub fn wait(&self, events : &mut [epoll_event], timeout : Option<Duration>) -> std::io::Result<usize> {
+        let timeout = match timeout {
+            // Round up so the timeout is not reported before it expires
+            Some(v) => v.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+            None => -1
+        };
+        let res = unsafe { libc::epoll_wait(self.fd.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, timeout) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(0)
+            }
+            return Err(err)
+        }
+        Ok(res as usize)
+    }
+}
+
+pub fn empty_events<const N : usize>() -> [epoll_event; N] {
+    [epoll_event { events : 0, u64 : 0 }; N]
+}
@@ -1,9 +1,16 @@
-use std::{io::{Read, Write}, time::Duration};
+use std::{io::{ErrorKind, Read, Write}, time::Instant};
 
-use crate::proxy::scap::common::ScapSender;
+use crate::proxy::scap::common::{ScapSender, ScapTimeout};
 
-use super::{common::ConnectionState, stream::{read_no_wait, write_no_wait, NonBlock}};
+use super::{common::ConnectionState, epoll::{empty_events, Epoll, EPOLLIN, EPOLLOUT}, stream::{flush_no_wait, read_no_wait, write_no_wait, NonBlock}};
 
+const CLIENT : u64 = 0;
+const SERVER : u64 = 1;
+
+/// Relays two streams waiting on epoll for their readiness. `client_buffer` holds the data read from the server
+/// waiting to be written to the client and `server_buffer` the data going the other way. A side is only read
+/// when its buffer is empty, and the read is retried before waiting again because TLS streams can hold already
+/// decrypted data that epoll does not report.
 pub struct MitmStreamer<'a>{
     pub state : &'a mut ConnectionState,
     pub scap : &'a mut ScapSender,
@@ -11,7 +18,6 @@ pub struct MitmStreamer<'a>{
     pub sreaded : usize,
     pub cpos : usize,
     pub spos : usize,
-    pub zero_counter : u64,
 }
 
 impl<'a> MitmStreamer<'a> {
@@ -23,7 +29,6 @@ impl<'a> MitmStreamer<'a> {
             sreaded : 0,
             cpos : 0,
             spos : 0,
-            zero_counter : 0
         }
     }
     /// Intercept any Stream connection
@@ -34,47 +39,257 @@ impl<'a> MitmStreamer<'a> {
     {
         cstream.set_non_blocking(true)?;
         sstream.set_non_blocking(true)?;
+        let epoll = Epoll::new()?;
+        let mut cinterest = EPOLLIN;
+        let mut sinterest = EPOLLIN;
+        epoll.add(cstream.raw_fd(), CLIENT, cinterest)?;
+        epoll.add(sstream.raw_fd(), SERVER, sinterest)?;
+        let mut events = empty_events::<4>();
+        // Read side closed by the peer and FIN (or close_notify) already propagated to the other side
+        let mut client_eof = false;
+        let mut server_eof = false;
+        let mut client_shut = false;
+        let mut server_shut = false;
+        let idle_timeout = self.state.timeouts.idle;
+        let deadline = self.state.timeouts.lifetime.map(|v| self.state.started + v);
+        let mut last_activity = Instant::now();
         loop {
-            if self.creaded == 0 {
+            let mut progress = false;
+            // Server to client
+            if self.creaded == 0 && !server_eof {
                 let mut scap = self.scap.from_client();
-                self.creaded = read_no_wait(sstream, &mut self.state.conn_buffers.client_buffer, &mut scap)?;
-            } else {
-                if self.cpos < self.creaded {
-                    self.cpos +=
-                        write_no_wait(cstream, &&self.state.conn_buffers.client_buffer[self.cpos..self.creaded])?;
-                } else {
+                match read_no_wait(sstream, &mut self.state.conn_buffers.client_buffer, &mut scap)? {
+                    Some(0) => server_eof = true,
+                    Some(v) => {
+                        self.creaded = v;
+                        self.state.count_received(v);
+                        progress = true;
+                    },
+                    None => {}
+                }
+            }
+            if self.cpos < self.creaded {
+                let writed = write_no_wait(cstream, &self.state.conn_buffers.client_buffer[self.cpos..self.creaded])?;
+                self.cpos += writed;
+                progress |= writed > 0;
+                if self.cpos == self.creaded {
                     self.creaded = 0;
                     self.cpos = 0;
                 }
             }
+            if server_eof && !client_shut && self.creaded == 0 {
+                log::trace!("Server finished sending");
+                shutdown_write(cstream)?;
+                client_shut = true;
+            }
+            flush_no_wait(cstream)?;
 
-            if self.sreaded == 0 {
+            // Client to server
+            if self.sreaded == 0 && !client_eof {
                 let mut scap = self.scap.from_server();
-                self.sreaded = read_no_wait(cstream, &mut self.state.conn_buffers.server_buffer, &mut scap)?;
-            } else {
-                if self.spos < self.sreaded {
-                    self.spos += write_no_wait(
-                        sstream,
-                        &&self.state.conn_buffers.server_buffer[self.spos..self.sreaded],
-                    )?;
-                } else {
+                match read_no_wait(cstream, &mut self.state.conn_buffers.server_buffer, &mut scap)? {
+                    Some(0) => client_eof = true,
+                    Some(v) => {
+                        self.sreaded = v;
+                        self.state.count_sent(v);
+                        progress = true;
+                    },
+                    None => {}
+                }
+            }
+            if self.spos < self.sreaded {
+                let writed = write_no_wait(sstream, &self.state.conn_buffers.server_buffer[self.spos..self.sreaded])?;
+                self.spos += writed;
+                progress |= writed > 0;
+                if self.spos == self.sreaded {
                     self.sreaded = 0;
                     self.spos = 0;
                 }
             }
-            if self.creaded == 0 && self.sreaded == 0 {
-                self.zero_counter += 1;
-            } else {
-                self.zero_counter = 0;
+            if client_eof && !server_shut && self.sreaded == 0 {
+                log::trace!("Client finished sending");
+                shutdown_write(sstream)?;
+                server_shut = true;
+            }
+            flush_no_wait(sstream)?;
+
+            if client_shut && server_shut && !cstream.pending_write() && !sstream.pending_write() {
+                break
+            }
+            if deadline.is_some_and(|v| v <= Instant::now()) {
+                log::debug!("Connection lifetime reached");
+                self.scap.timeout(ScapTimeout::Lifetime);
+                break
+            }
+            if progress {
+                last_activity = Instant::now();
+                continue
             }
 
-            if self.zero_counter > 0 {
-                if self.zero_counter > 32 {
+            let interest = |read : bool, write : bool| (if read { EPOLLIN } else { 0 }) | (if write { EPOLLOUT } else { 0 });
+            let new_cinterest = interest(self.sreaded == 0 && !client_eof, self.creaded > 0 || cstream.pending_write());
+            let new_sinterest = interest(self.creaded == 0 && !server_eof, self.sreaded > 0 || sstream.pending_write());
+            if new_cinterest != cinterest {
+                epoll.modify(cstream.raw_fd(), CLIENT, new_cinterest)?;
+                cinterest = new_cinterest;
+            }
+            if new_sinterest != sinterest {
+                epoll.modify(sstream.raw_fd(), SERVER, new_sinterest)?;
+                sinterest = new_sinterest;
+            }
+            let remaining = match idle_timeout.checked_sub(last_activity.elapsed()) {
+                Some(v) if !v.is_zero() => v,
+                _ => {
+                    log::debug!("Idle timeout reached");
+                    self.scap.timeout(ScapTimeout::Idle);
                     break
                 }
-                std::thread::sleep(Duration::from_millis(self.zero_counter * 10));
-            }
+            };
+            let remaining = match deadline {
+                Some(deadline) => remaining.min(deadline.saturating_duration_since(Instant::now())),
+                None => remaining
+            };
+            epoll.wait(&mut events, Some(remaining))?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Propagates the end of the data. The peer may have closed the connection completely.
+fn shutdown_write<S : NonBlock>(stream : &mut S) -> std::io::Result<()> {
+    match stream.shutdown_write() {
+        Err(e) if e.kind() != ErrorKind::NotConnected => Err(e),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) fn tcp_pair() -> (std::net::TcpStream, std::net::TcpStream) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    (stream, listener.accept().unwrap().0)
+}
+
+/// Relays both streams in another thread. Returns how long the relay lasted and the capture events.
+#[cfg(test)]
+fn spawn_relay<C, S>(mut cstream : C, mut sstream : S, timeouts : super::common::ConnTimeouts) -> (std::thread::JoinHandle<std::time::Duration>, crossbeam_channel::Receiver<crate::proxy::scap::common::ScapEvent>)
+where
+    C: Read + Write + Send + NonBlock + 'static,
+    S: Read + Write + Send + NonBlock + 'static
+{
+    use std::{collections::BTreeSet, sync::{Arc, Mutex}};
+    use crate::proxy::{scap::common::{ScapProtocol, ScapStore}, tls::store::TlsCertStore};
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let store = ScapStore::new(sender);
+    let tls = TlsCertStore::new(None, Arc::new(Mutex::new(BTreeSet::new())), None).unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut state = ConnectionState::new(store.reference(), tls, timeouts);
+        let mut scap = store.reference().sender(ScapProtocol::Tcp, ([10, 0, 0, 1].into(), 443), ([10, 0, 0, 2].into(), 40000));
+        MitmStreamer::new(&mut state, &mut scap).intercept(&mut cstream, &mut sstream).unwrap();
+        state.started.elapsed()
+    });
+    (handle, receiver)
+}
+
+#[cfg(test)]
+fn recorded_timeout(receiver : &crossbeam_channel::Receiver<crate::proxy::scap::common::ScapEvent>) -> Option<ScapTimeout> {
+    use crate::proxy::scap::common::ScapEvent;
+    receiver.try_iter().find_map(|v| match v {
+        ScapEvent::Timeout(_, timeout) => Some(timeout),
+        _ => None
+    })
+}
+
+#[test]
+fn should_relay_the_reply_after_the_client_finished_sending() {
+    use std::{net::Shutdown, time::Duration};
+    use super::common::ConnTimeouts;
+    let (mut client, proxy_client) = tcp_pair();
+    let (proxy_server, mut origin) = tcp_pair();
+    let timeouts = ConnTimeouts { idle : Duration::from_millis(500), lifetime : None, ..Default::default() };
+    let (relay, events) = spawn_relay(proxy_client, proxy_server, timeouts);
+    client.write_all(b"request").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    // The FIN reaches the server, which answers keeping its side open
+    let mut request = Vec::new();
+    origin.read_to_end(&mut request).unwrap();
+    assert_eq!(b"request", &request[..]);
+    origin.write_all(b"reply").unwrap();
+    let mut reply = [0u8; 5];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(b"reply", &reply);
+    relay.join().unwrap();
+    assert_eq!(Some(ScapTimeout::Idle), recorded_timeout(&events));
+
+    // Traffic does not extend the lifetime
+    let (mut client, proxy_client) = tcp_pair();
+    let (proxy_server, _origin) = tcp_pair();
+    let timeouts = ConnTimeouts { idle : Duration::from_secs(60), lifetime : Some(Duration::from_millis(300)), ..Default::default() };
+    let (relay, events) = spawn_relay(proxy_client, proxy_server, timeouts);
+    for _ in 0..5 {
+        // The writes after the lifetime may find the connection reset
+        let _ = client.write_all(b"ping");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(relay.join().unwrap() < Duration::from_secs(5));
+    assert_eq!(Some(ScapTimeout::Lifetime), recorded_timeout(&events));
+}
+
+#[test]
+fn should_map_close_notify_to_half_close() {
+    use std::sync::Arc;
+    use rustls::{pki_types::PrivatePkcs8KeyDer, ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let server_config = ServerConfig::builder().with_no_client_auth()
+        .with_single_cert(vec![generated.cert.der().clone()], PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()).into()).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(generated.cert.der().clone()).unwrap();
+    let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+
+    let (client, proxy_client) = tcp_pair();
+    let (proxy_server, mut origin) = tcp_pair();
+    let client = std::thread::spawn(move || {
+        let mut tls = StreamOwned::new(ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap(), client);
+        tls.write_all(b"request").unwrap();
+        tls.conn.send_close_notify();
+        tls.flush().unwrap();
+        let mut reply = Vec::new();
+        // Ends with the close_notify sent for the FIN of the server
+        tls.read_to_end(&mut reply).unwrap();
+        reply
+    });
+    let mut tls = StreamOwned::new(ServerConnection::new(Arc::new(server_config)).unwrap(), proxy_client);
+    tls.conn.complete_io(&mut tls.sock).unwrap();
+    let (relay, _events) = spawn_relay(tls, proxy_server, Default::default());
+    let mut request = Vec::new();
+    origin.read_to_end(&mut request).unwrap();
+    assert_eq!(b"request", &request[..]);
+    origin.write_all(b"reply").unwrap();
+    drop(origin);
+    assert_eq!(b"reply", &client.join().unwrap()[..]);
+    relay.join().unwrap();
+}
+
+#[test]
+fn should_keep_idle_connections_until_the_idle_timeout() {
+    use std::time::{Duration, Instant};
+    use super::common::ConnTimeouts;
+    let (mut client, proxy_client) = tcp_pair();
+    let (proxy_server, mut origin) = tcp_pair();
+    let timeouts = ConnTimeouts { idle : Duration::from_secs(7), lifetime : None, ..Default::default() };
+    let (relay, events) = spawn_relay(proxy_client, proxy_server, timeouts);
+    // Silent for longer than the 5 seconds the connections used to last
+    std::thread::sleep(Duration::from_millis(5500));
+    client.write_all(b"ping").unwrap();
+    let mut ping = [0u8; 4];
+    origin.read_exact(&mut ping).unwrap();
+    assert_eq!(b"ping", &ping);
+    let last_activity = Instant::now();
+    let elapsed = relay.join().unwrap();
+    assert!(last_activity.elapsed() >= Duration::from_millis(6500), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(15), "{elapsed:?}");
+    assert_eq!(Some(ScapTimeout::Idle), recorded_timeout(&events));
+    // Both sides see the end of the connection
+    assert_eq!(0, client.read(&mut ping).unwrap());
+    assert_eq!(0, origin.read(&mut ping).unwrap());
+}
@@ -1,3 +1,12 @@
 pub mod common;
 pub mod stream;
-pub mod mitm;
\ No newline at end of file
+pub mod mitm;
+pub mod dst;
+pub mod egress;
+pub mod udp;
+pub mod tproxy;
+pub mod sniff;
+pub mod epoll;
+pub mod splice;
+pub mod route;
+pub mod registry;
\ No newline at end of file
@@ -0,0 +1,273 @@
+use std::{collections::BTreeMap, io::{Read, Write}, net::{SocketAddr, TcpStream}, os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, Weak}, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::proxy::{scap::common::ScapProtocol, ListenMode};
+
+use super::{dst::Destination, egress::UpstreamStream, stream::NonBlock};
+
+/// Connections being served by the workers, listed and closed from the admin API
+#[derive(Default)]
+pub struct ConnRegistry {
+    next_id : AtomicU64,
+    conns : Mutex<BTreeMap<u64, Weak<ActiveConn>>>,
+}
+
+/// Connection being served. It leaves the registry when the worker drops it.
+pub struct ActiveConn {
+    pub id : u64,
+    pub client : SocketAddr,
+    pub mode : ListenMode,
+    pub started : Instant,
+    /// Bytes from the client to the server. For intercepted TLS, the decrypted ones.
+    pub sent : AtomicU64,
+    /// Bytes from the server to the client
+    pub received : AtomicU64,
+    details : Mutex<ConnDetails>,
+    /// Duplicates of the client and upstream sockets, shut down to close the connection. The client is the first one.
+    sockets : Mutex<BTreeMap<u64, OwnedFd>>,
+    next_socket : AtomicU64,
+    registry : Arc<ConnRegistry>,
+}
+
+/// What is learned about a connection while it is served
+#[derive(Debug, Clone, Default)]
+struct ConnDetails {
+    destination : Option<Destination>,
+    sni : Option<String>,
+    protocol : Option<ScapProtocol>,
+    intercepted : bool,
+}
+
+/// Snapshot of a connection returned by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnSummary {
+    pub id : u64,
+    pub client : SocketAddr,
+    pub mode : ListenMode,
+    pub destination : Option<String>,
+    pub sni : Option<String>,
+    pub protocol : Option<ScapProtocol>,
+    pub intercepted : bool,
+    pub sent : u64,
+    pub received : u64,
+    /// Seconds since the connection was accepted
+    pub age : u64,
+}
+
+impl ConnRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Adds a new client connection
+    pub fn register(self : &Arc<Self>, stream : &TcpStream, mode : ListenMode) -> std::io::Result<Arc<ActiveConn>> {
+        let conn = Arc::new(ActiveConn {
+            id : self.next_id.fetch_add(1, Ordering::Relaxed),
+            client : stream.peer_addr()?,
+            mode,
+            started : Instant::now(),
+            sent : AtomicU64::new(0),
+            received : AtomicU64::new(0),
+            details : Mutex::new(ConnDetails::default()),
+            sockets : Mutex::new(BTreeMap::from([(0, stream.as_fd().try_clone_to_owned()?)])),
+            next_socket : AtomicU64::new(1),
+            registry : self.clone(),
+        });
+        self.conns.lock().unwrap_or_else(|e| e.into_inner()).insert(conn.id, Arc::downgrade(&conn));
+        Ok(conn)
+    }
+
+    pub fn get(&self, id : u64) -> Option<Arc<ActiveConn>> {
+        self.conns.lock().unwrap_or_else(|e| e.into_inner()).get(&id).and_then(|v| v.upgrade())
+    }
+
+    pub fn list(&self) -> Vec<ConnSummary> {
+        let conns : Vec<Arc<ActiveConn>> = self.conns.lock().unwrap_or_else(|e| e.into_inner()).values().filter_map(|v| v.upgrade()).collect();
+        conns.iter().map(|v| v.summary()).collect()
+    }
+
+    /// Closes every connection
+    pub fn kill_all(&self) {
+        let conns : Vec<Arc<ActiveConn>> = self.conns.lock().unwrap_or_else(|e| e.into_inner()).values().filter_map(|v| v.upgrade()).collect();
+        for conn in conns {
+            conn.kill();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ActiveConn {
+    /// Adds the socket to the destination, so it is also closed by `kill`. The duplicate is closed with the returned
+    /// stream: a fallback can drop an upstream long before the client connection ends, and the server must get its FIN.
+    pub fn track_upstream(self : &Arc<Self>, upstream : Box<dyn UpstreamStream>) -> Box<dyn UpstreamStream> {
+        // The descriptor belongs to the upstream stream, which outlives this call
+        let fd = unsafe { BorrowedFd::borrow_raw(upstream.raw_fd()) };
+        let key = match fd.try_clone_to_owned() {
+            Ok(v) => {
+                let key = self.next_socket.fetch_add(1, Ordering::Relaxed);
+                self.sockets.lock().unwrap_or_else(|e| e.into_inner()).insert(key, v);
+                key
+            },
+            Err(e) => {
+                log::warn!("Cannot track the upstream socket of connection {}: {e}", self.id);
+                return upstream
+            }
+        };
+        Box::new(TrackedUpstream { stream : upstream, conn : Arc::downgrade(self), key })
+    }
+
+    pub fn set_destination(&self, dst : &Destination) {
+        self.details().destination = Some(dst.clone());
+    }
+
+    pub fn set_protocol(&self, protocol : ScapProtocol) {
+        self.details().protocol = Some(protocol);
+    }
+
+    pub fn set_sni(&self, sni : &str) {
+        self.details().sni = Some(sni.to_string());
+    }
+
+    pub fn set_intercepted(&self) {
+        self.details().intercepted = true;
+    }
+
+    pub fn add_sent(&self, bytes : usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes : usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Closes both sides of the connection. The worker notices it on the next read or write.
+    pub fn kill(&self) {
+        for socket in self.sockets.lock().unwrap_or_else(|e| e.into_inner()).values() {
+            unsafe { libc::shutdown(socket.as_raw_fd(), libc::SHUT_RDWR) };
+        }
+    }
+
+    pub fn summary(&self) -> ConnSummary {
+        let details = self.details().clone();
+        ConnSummary {
+            id : self.id,
+            client : self.client,
+            mode : self.mode,
+            destination : details.destination.map(|v| v.to_string()),
+            sni : details.sni,
+            protocol : details.protocol,
+            intercepted : details.intercepted,
+            sent : self.sent.load(Ordering::Relaxed),
+            received : self.received.load(Ordering::Relaxed),
+            age : self.started.elapsed().as_secs(),
+        }
+    }
+
+    fn details(&self) -> std::sync::MutexGuard<'_, ConnDetails> {
+        self.details.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for ActiveConn {
+    fn drop(&mut self) {
+        self.registry.conns.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
+
+/// Upstream stream whose socket can be closed by `ActiveConn::kill`
+struct TrackedUpstream {
+    stream : Box<dyn UpstreamStream>,
+    conn : Weak<ActiveConn>,
+    key : u64,
+}
+
+impl Drop for TrackedUpstream {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.upgrade() {
+            conn.sockets.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.key);
+        }
+    }
+}
+
+impl Read for TrackedUpstream {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TrackedUpstream {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl NonBlock for TrackedUpstream {
+    fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
+        self.stream.set_non_blocking(nonblocking)
+    }
+    fn raw_fd(&self) -> RawFd {
+        self.stream.raw_fd()
+    }
+    fn pending_write(&self) -> bool {
+        self.stream.pending_write()
+    }
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        self.stream.flush_pending()
+    }
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.stream.shutdown_write()
+    }
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_timeouts(timeout)
+    }
+    fn spliceable(&self) -> bool {
+        self.stream.spliceable()
+    }
+    fn take_buffered(&mut self) -> Vec<u8> {
+        self.stream.take_buffered()
+    }
+}
+
+impl UpstreamStream for TrackedUpstream {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+}
+
+#[test]
+fn should_list_and_kill_connections() {
+    use std::{io::Read, net::TcpListener};
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let registry = ConnRegistry::new();
+    let conn = registry.register(&server, ListenMode::Socks5).unwrap();
+    conn.set_destination(&Destination::Domain("example.com".into(), 443));
+    conn.add_sent(10);
+    let list = registry.list();
+    assert_eq!(1, list.len());
+    assert_eq!(Some("example.com:443".to_string()), list[0].destination);
+    assert_eq!(10, list[0].sent);
+    // A dropped upstream is closed at once, not when the client connection ends
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream = TcpStream::connect(upstream_listener.local_addr().unwrap()).unwrap();
+    let (mut origin, _) = upstream_listener.accept().unwrap();
+    drop(conn.track_upstream(Box::new(upstream)));
+    assert_eq!(0, origin.read(&mut [0u8; 16]).unwrap());
+    assert_eq!(1, conn.sockets.lock().unwrap().len());
+    registry.get(conn.id).unwrap().kill();
+    assert_eq!(0, client.read(&mut [0u8; 16]).unwrap());
+    drop(conn);
+    assert!(registry.is_empty());
+}
@@ -0,0 +1,188 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr, sync::{Arc, RwLock}};
+
+use super::{dst::Destination, egress::{UdpUpstream, UpstreamConnector, UpstreamStream}};
+
+/// Destinations matched by a rule: `example.com`, `*.example.com` (the domain and its subdomains), an IP, a
+/// network like `10.0.0.0/8`, and any of them followed by `:port`. IPv6 addresses with a port go between brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DstPattern {
+    pub host : HostPattern,
+    pub port : Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Domain(String),
+    /// Domain and all its subdomains
+    Suffix(String),
+    Net(IpAddr, u8),
+}
+
+/// Sends each destination through the egress of the first route that matches it, or the default one
+pub struct RoutedConnector {
+    pub routes : Vec<Route>,
+    pub default : Arc<dyn UpstreamConnector>,
+}
+
+pub struct Route {
+    pub patterns : Vec<DstPattern>,
+    pub egress : Arc<dyn UpstreamConnector>,
+}
+
+/// Connector that can be replaced while the proxy runs, used to reload the routes. The open connections keep
+/// the egress they were established with.
+pub struct ReloadableConnector {
+    inner : RwLock<Arc<dyn UpstreamConnector>>,
+}
+
+impl DstPattern {
+    pub fn matches(&self, dst : &Destination) -> bool {
+        if self.port.is_some_and(|v| v != dst.port()) {
+            return false
+        }
+        match (&self.host, dst) {
+            (HostPattern::Domain(v), Destination::Domain(name, _)) => v == name,
+            (HostPattern::Suffix(v), Destination::Domain(name, _)) => name == v || (name.ends_with(v.as_str()) && name[..name.len() - v.len()].ends_with('.')),
+            (HostPattern::Net(net, prefix), Destination::Addr(addr)) => in_network(addr.ip(), *net, *prefix),
+            _ => false
+        }
+    }
+}
+
+/// Checks if the IP is inside the network. IPv4 mapped IPv6 addresses are compared as IPv4.
+pub fn in_network(ip : IpAddr, net : IpAddr, prefix : u8) -> bool {
+    let ip = ip.to_canonical();
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        },
+        _ => false
+    }
+}
+
+impl FromStr for DstPattern {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(|| format!("missing ] in {s}"))?;
+            match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(format!("invalid port in {s}"))
+            }
+        } else if s.matches(':').count() == 1 {
+            let (host, port) = s.split_once(':').unwrap_or((s, ""));
+            (host, Some(port))
+        } else {
+            (s, None)
+        };
+        let port = match port {
+            Some(v) => Some(v.parse::<u16>().map_err(|_| format!("invalid port in {s}"))?),
+            None => None
+        };
+        if host.is_empty() {
+            return Err("empty host".into())
+        }
+        let host = if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Suffix(domain.to_lowercase())
+        } else if let Some((net, prefix)) = host.split_once('/') {
+            let net = IpAddr::from_str(net).map_err(|_| format!("invalid network {host}"))?;
+            let max = if net.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix.parse::<u8>().ok().filter(|v| *v <= max).ok_or_else(|| format!("invalid prefix in {host}"))?;
+            HostPattern::Net(net, prefix)
+        } else if let Ok(ip) = IpAddr::from_str(host) {
+            HostPattern::Net(ip, if ip.is_ipv4() { 32 } else { 128 })
+        } else {
+            HostPattern::Domain(host.to_lowercase())
+        };
+        Ok(Self { host, port })
+    }
+}
+
+impl Display for DstPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let host = match &self.host {
+            HostPattern::Domain(v) => v.clone(),
+            HostPattern::Suffix(v) => format!("*.{v}"),
+            HostPattern::Net(ip, prefix) if *prefix == if ip.is_ipv4() { 32 } else { 128 } => ip.to_string(),
+            HostPattern::Net(ip, prefix) => format!("{ip}/{prefix}"),
+        };
+        match self.port {
+            Some(port) if host.contains(':') => write!(f, "[{host}]:{port}"),
+            Some(port) => write!(f, "{host}:{port}"),
+            None => f.write_str(&host)
+        }
+    }
+}
+
+impl RoutedConnector {
+    pub fn egress_for(&self, dst : &Destination) -> &Arc<dyn UpstreamConnector> {
+        self.routes.iter().find(|route| route.patterns.iter().any(|p| p.matches(dst))).map(|route| &route.egress).unwrap_or(&self.default)
+    }
+}
+
+impl UpstreamConnector for RoutedConnector {
+    fn connect(&self, dst : &Destination) -> std::io::Result<Box<dyn UpstreamStream>> {
+        self.egress_for(dst).connect(dst)
+    }
+
+    /// The destinations of the datagrams are not known when the socket is opened
+    fn udp(&self) -> std::io::Result<Box<dyn UdpUpstream>> {
+        self.default.udp()
+    }
+}
+
+impl ReloadableConnector {
+    pub fn new(inner : Arc<dyn UpstreamConnector>) -> Self {
+        Self { inner : RwLock::new(inner) }
+    }
+
+    pub fn replace(&self, inner : Arc<dyn UpstreamConnector>) {
+        *self.inner.write().unwrap_or_else(|e| e.into_inner()) = inner;
+    }
+
+    fn current(&self) -> Arc<dyn UpstreamConnector> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl UpstreamConnector for ReloadableConnector {
+    fn connect(&self, dst : &Destination) -> std::io::Result<Box<dyn UpstreamStream>> {
+        self.current().connect(dst)
+    }
+
+    fn udp(&self) -> std::io::Result<Box<dyn UdpUpstream>> {
+        self.current().udp()
+    }
+}
+
+#[test]
+fn should_match_destination_patterns() {
+    let dst = |v : &str| match v.parse() {
+        Ok(addr) => Destination::Addr(addr),
+        Err(_) => {
+            let (host, port) = v.rsplit_once(':').unwrap();
+            Destination::Domain(host.into(), port.parse().unwrap())
+        }
+    };
+    let pattern = |v : &str| DstPattern::from_str(v).unwrap();
+    assert!(pattern("*.example.com").matches(&dst("example.com:443")));
+    assert!(pattern("*.example.com").matches(&dst("api.example.com:80")));
+    assert!(!pattern("*.example.com").matches(&dst("badexample.com:80")));
+    assert!(pattern("Example.com:443").matches(&dst("example.com:443")));
+    assert!(!pattern("example.com:443").matches(&dst("example.com:80")));
+    assert!(pattern("10.0.0.0/8").matches(&dst("10.1.2.3:22")));
+    assert!(!pattern("10.0.0.0/8").matches(&dst("11.1.2.3:22")));
+    assert!(pattern("[::1]:8080").matches(&dst("[::1]:8080")));
+    assert!(pattern("fd00::/8").matches(&dst("[fd12::1]:53")));
+    assert_eq!("[fd00::/8]:53", pattern("[fd00::/8]:53").to_string());
+    assert!(DstPattern::from_str("10.0.0.0/33").is_err());
+    assert!(DstPattern::from_str("example.com:http").is_err());
+}
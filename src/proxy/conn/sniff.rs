@@ -0,0 +1,98 @@
+use std::{io::ErrorKind, net::TcpStream, time::{Duration, Instant}};
+
+use crate::proxy::scap::common::ScapProtocol;
+
+use super::stream::Rewind;
+
+/// Maximum time waiting for the first bytes of the client. Server-first protocols are relayed as raw TCP after it.
+pub const SNIFF_TIMEOUT : Duration = Duration::from_millis(500);
+
+/// Bytes needed to classify any of the known protocols
+const SNIFF_SIZE : usize = 16;
+
+const HTTP_METHODS : [&[u8]; 9] = [b"GET", b"POST", b"PUT", b"HEAD", b"DELETE", b"OPTIONS", b"PATCH", b"CONNECT", b"TRACE"];
+
+/// Classifies the first bytes sent by the client. Returns None if more bytes are needed. Unknown protocols are
+/// classified as Tcp.
+pub fn classify(data : &[u8]) -> Option<ScapProtocol> {
+    if data.is_empty() {
+        return None
+    }
+    // TLS record: handshake (0x16), version 3.x, and a ClientHello (0x01) as first message
+    if data[0] == 0x16 {
+        if data.len() < 6 {
+            return None
+        }
+        if data[1] == 0x03 && data[2] <= 0x04 && data[5] == 0x01 {
+            return Some(ScapProtocol::Tls)
+        }
+        return Some(ScapProtocol::Tcp)
+    }
+    if is_prefix_of(data, b"SSH-") {
+        return if data.len() < 4 { None } else { Some(ScapProtocol::Ssh) }
+    }
+    let mut partial = false;
+    for method in HTTP_METHODS {
+        if data.len() > method.len() {
+            if data.starts_with(method) && data[method.len()] == b' ' {
+                return Some(ScapProtocol::Http)
+            }
+        } else if method.starts_with(data) {
+            partial = true;
+        }
+    }
+    if partial {
+        return None
+    }
+    Some(ScapProtocol::Tcp)
+}
+
+fn is_prefix_of(data : &[u8], value : &[u8]) -> bool {
+    let len = data.len().min(value.len());
+    data[0..len] == value[0..len]
+}
+
+/// Peeks the first bytes of the client without consuming them and classifies the connection.
+pub fn sniff(stream : &Rewind<TcpStream>, timeout : Duration) -> std::io::Result<ScapProtocol> {
+    let mut buffer = [0u8; SNIFF_SIZE];
+    let prefix = &stream.prefix[stream.pos..];
+    let start = Instant::now();
+    let old_timeout = stream.inner.read_timeout()?;
+    let res = loop {
+        let len = prefix.len().min(SNIFF_SIZE);
+        buffer[0..len].copy_from_slice(&prefix[0..len]);
+        let mut readed = len;
+        if readed < SNIFF_SIZE {
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(v) if !v.is_zero() => v,
+                _ => break Ok(classify(&buffer[0..readed]).unwrap_or(ScapProtocol::Tcp))
+            };
+            stream.inner.set_read_timeout(Some(remaining))?;
+            match stream.inner.peek(&mut buffer[readed..]) {
+                Ok(0) => break Ok(classify(&buffer[0..readed]).unwrap_or(ScapProtocol::Tcp)),
+                Ok(v) => readed += v,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e)
+            }
+        }
+        if let Some(protocol) = classify(&buffer[0..readed]) {
+            break Ok(protocol)
+        }
+        // Only part of the data has arrived, wait for the rest
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    stream.inner.set_read_timeout(old_timeout)?;
+    res
+}
+
+#[test]
+fn should_classify_first_bytes() {
+    assert_eq!(Some(ScapProtocol::Tls), classify(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00]));
+    assert_eq!(Some(ScapProtocol::Http), classify(b"POST /api HTTP/1.1\r\n"));
+    assert_eq!(Some(ScapProtocol::Ssh), classify(b"SSH-2.0-OpenSSH_9.6\r\n"));
+    assert_eq!(Some(ScapProtocol::Tcp), classify(b"\x00\x00\x00\x01random"));
+    assert_eq!(Some(ScapProtocol::Tcp), classify(b"GETX"));
+    assert_eq!(None, classify(b"OPT"));
+    assert_eq!(None, classify(&[0x16, 0x03]));
+}
@@ -0,0 +1,214 @@
+use std::{os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, time::Instant};
+
+use crate::proxy::scap::common::{ScapSender, ScapTimeout};
+
+use super::{common::ConnectionState, epoll::{empty_events, Epoll, EPOLLIN, EPOLLOUT}, stream::NonBlock};
+
+const CLIENT : u64 = 0;
+const SERVER : u64 = 1;
+
+/// Bytes requested on each splice call, the default capacity of a pipe
+const PIPE_SIZE : usize = 64 * 1024;
+
+/// Relays two plain sockets moving the bytes with splice() through a pipe per direction, without copying them to
+/// userland. Only used when nothing needs to see the data: no capture is stored and no protocol is dissected.
+pub struct SpliceStreamer<'a> {
+    pub state : &'a ConnectionState,
+    pub scap : &'a ScapSender,
+}
+
+/// One direction of the relay
+struct Pipe {
+    read : OwnedFd,
+    write : OwnedFd,
+    /// Bytes inside the pipe waiting to be written to the destination socket
+    len : usize,
+    /// The source socket has been closed by the peer
+    eof : bool,
+    /// The end of the data has been propagated to the destination socket
+    shut : bool,
+}
+
+impl<'a> SpliceStreamer<'a> {
+    pub fn new(state : &'a ConnectionState, scap : &'a ScapSender) -> Self {
+        Self { state, scap }
+    }
+
+    pub fn relay<C, S>(&mut self, cstream : &mut C, sstream : &mut S) -> std::io::Result<()>
+    where
+        C: NonBlock,
+        S: NonBlock
+    {
+        cstream.set_non_blocking(true)?;
+        sstream.set_non_blocking(true)?;
+        let cfd = cstream.raw_fd();
+        let sfd = sstream.raw_fd();
+        // Client to server and server to client
+        let mut upload = Pipe::new()?;
+        let mut download = Pipe::new()?;
+        let epoll = Epoll::new()?;
+        let mut cinterest = EPOLLIN;
+        let mut sinterest = EPOLLIN;
+        epoll.add(cfd, CLIENT, cinterest)?;
+        epoll.add(sfd, SERVER, sinterest)?;
+        let mut events = empty_events::<4>();
+        let idle_timeout = self.state.timeouts.idle;
+        let deadline = self.state.timeouts.lifetime.map(|v| self.state.started + v);
+        let mut last_activity = Instant::now();
+        loop {
+            let (received, mut progress) = download.transfer(sfd, cfd)?;
+            let (sent, uploaded) = upload.transfer(cfd, sfd)?;
+            progress |= uploaded;
+            self.state.count_received(received);
+            self.state.count_sent(sent);
+            if upload.shut && download.shut {
+                break
+            }
+            if deadline.is_some_and(|v| v <= Instant::now()) {
+                log::debug!("Connection lifetime reached");
+                self.scap.timeout(ScapTimeout::Lifetime);
+                break
+            }
+            if progress {
+                last_activity = Instant::now();
+                continue
+            }
+            let interest = |read : bool, write : bool| (if read { EPOLLIN } else { 0 }) | (if write { EPOLLOUT } else { 0 });
+            let new_cinterest = interest(upload.wants_read(), download.len > 0);
+            let new_sinterest = interest(download.wants_read(), upload.len > 0);
+            if new_cinterest != cinterest {
+                epoll.modify(cfd, CLIENT, new_cinterest)?;
+                cinterest = new_cinterest;
+            }
+            if new_sinterest != sinterest {
+                epoll.modify(sfd, SERVER, new_sinterest)?;
+                sinterest = new_sinterest;
+            }
+            let remaining = match idle_timeout.checked_sub(last_activity.elapsed()) {
+                Some(v) if !v.is_zero() => v,
+                _ => {
+                    log::debug!("Idle timeout reached");
+                    self.scap.timeout(ScapTimeout::Idle);
+                    break
+                }
+            };
+            let remaining = match deadline {
+                Some(deadline) => remaining.min(deadline.saturating_duration_since(Instant::now())),
+                None => remaining
+            };
+            epoll.wait(&mut events, Some(remaining))?;
+        }
+        Ok(())
+    }
+}
+
+impl Pipe {
+    fn new() -> std::io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(Self {
+            read : unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            write : unsafe { OwnedFd::from_raw_fd(fds[1]) },
+            len : 0,
+            eof : false,
+            shut : false,
+        })
+    }
+
+    fn wants_read(&self) -> bool {
+        !self.eof && self.len < PIPE_SIZE
+    }
+
+    /// Moves what is possible without blocking from `src` to `dst`. Returns the bytes read from `src` and whether
+    /// any byte was moved.
+    fn transfer(&mut self, src : RawFd, dst : RawFd) -> std::io::Result<(usize, bool)> {
+        let mut progress = false;
+        let mut readed = 0;
+        if self.wants_read() {
+            match splice(src, self.write.as_raw_fd(), PIPE_SIZE - self.len)? {
+                Some(0) => self.eof = true,
+                Some(v) => {
+                    self.len += v;
+                    readed = v;
+                    progress = true;
+                },
+                None => {}
+            }
+        }
+        if self.len > 0 {
+            if let Some(v) = splice(self.read.as_raw_fd(), dst, self.len)? {
+                self.len -= v;
+                progress |= v > 0;
+            }
+        }
+        if self.eof && self.len == 0 && !self.shut {
+            log::trace!("Peer finished sending");
+            if unsafe { libc::shutdown(dst, libc::SHUT_WR) } != 0 {
+                let err = std::io::Error::last_os_error();
+                // The peer may have closed the connection completely
+                if err.kind() != std::io::ErrorKind::NotConnected {
+                    return Err(err)
+                }
+            }
+            self.shut = true;
+        }
+        Ok((readed, progress))
+    }
+}
+
+/// Moves up to `len` bytes between two descriptors. Returns None if it would block.
+fn splice(src : RawFd, dst : RawFd, len : usize) -> std::io::Result<Option<usize>> {
+    loop {
+        let res = unsafe { libc::splice(src, std::ptr::null_mut(), dst, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK) };
+        if res >= 0 {
+            return Ok(Some(res as usize))
+        }
+        let err = std::io::Error::last_os_error();
+        match err.kind() {
+            std::io::ErrorKind::WouldBlock => return Ok(None),
+            std::io::ErrorKind::Interrupted => continue,
+            _ => return Err(err)
+        }
+    }
+}
+
+#[test]
+fn should_splice_payloads_larger_than_the_pipe() {
+    use std::{collections::BTreeSet, io::{Read, Write}, net::Shutdown, sync::{Arc, Mutex}};
+    use crate::proxy::{scap::common::{ScapProtocol, ScapStore}, tls::store::TlsCertStore};
+    use super::mitm::tcp_pair;
+    let (mut client, mut proxy_client) = tcp_pair();
+    let (mut proxy_server, mut origin) = tcp_pair();
+    let (sender, _receiver) = crossbeam_channel::unbounded();
+    let store = ScapStore::new(sender);
+    let tls = TlsCertStore::new(None, Arc::new(Mutex::new(BTreeSet::new())), None).unwrap();
+    let relay = std::thread::spawn(move || {
+        let state = ConnectionState::new(store.reference(), tls, Default::default());
+        let scap = store.reference().sender(ScapProtocol::Tcp, ([10, 0, 0, 1].into(), 443), ([10, 0, 0, 2].into(), 40000));
+        SpliceStreamer::new(&state, &scap).relay(&mut proxy_client, &mut proxy_server).unwrap();
+    });
+    let payload : Vec<u8> = (0..4 * PIPE_SIZE + 123).map(|v| (v % 251) as u8).collect();
+    let upload = payload.clone();
+    let writer = std::thread::spawn(move || {
+        client.write_all(&upload).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        client
+    });
+    let mut received = Vec::new();
+    origin.read_to_end(&mut received).unwrap();
+    assert!(received == payload);
+    let mut client = writer.join().unwrap();
+    // The other direction keeps working after the half-close
+    let download : Vec<u8> = payload.iter().rev().copied().collect();
+    let reader = std::thread::spawn(move || {
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        received
+    });
+    origin.write_all(&download).unwrap();
+    drop(origin);
+    assert!(reader.join().unwrap() == download);
+    relay.join().unwrap();
+}
@@ -1,21 +1,83 @@
-use std::{io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}, os::fd::AsRawFd};
+use std::{io::{ErrorKind, Read, Write}, net::{Shutdown, SocketAddr, TcpStream}, os::fd::{AsRawFd, RawFd}, time::Duration};
 
 use libc::{sockaddr_in, sockaddr_in6, socklen_t, IP6T_SO_ORIGINAL_DST, SOL_IP, SOL_IPV6, SO_ORIGINAL_DST};
 use rustls::{ClientConnection, ServerConnection, StreamOwned as TlsStream};
 
 pub enum ConnStream {
-    Tls(TlsStream<ClientConnection, TcpStream>),
+    Tls(Box<TlsStream<ClientConnection, TcpStream>>),
     Tcp(TcpStream),
 }
 
 pub trait NonBlock {
     fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()>;
+    /// Socket used to wait for readiness
+    fn raw_fd(&self) -> RawFd;
+    /// Data accepted by `write` but not yet sent to the socket, like buffered TLS records
+    fn pending_write(&self) -> bool {
+        false
+    }
+    /// Tries to send the pending data. Returns WouldBlock if the socket is full.
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+    /// Signals the end of the data sent to the peer (FIN or TLS close_notify) keeping the read side open
+    fn shutdown_write(&mut self) -> std::io::Result<()>;
+    /// Read and write timeouts of the socket while it is used in blocking mode
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()>;
+    /// The socket carries the bytes of the stream unmodified, so they can be moved with splice()
+    fn spliceable(&self) -> bool {
+        false
+    }
+    /// Takes the bytes already read from the socket that `read` has not returned yet
+    fn take_buffered(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+impl<T : NonBlock + ?Sized> NonBlock for Box<T> {
+    fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
+        self.as_ref().set_non_blocking(nonblocking)
+    }
+    fn raw_fd(&self) -> RawFd {
+        self.as_ref().raw_fd()
+    }
+    fn pending_write(&self) -> bool {
+        self.as_ref().pending_write()
+    }
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        self.as_mut().flush_pending()
+    }
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.as_mut().shutdown_write()
+    }
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.as_ref().set_timeouts(timeout)
+    }
+    fn spliceable(&self) -> bool {
+        self.as_ref().spliceable()
+    }
+    fn take_buffered(&mut self) -> Vec<u8> {
+        self.as_mut().take_buffered()
+    }
 }
 
 impl NonBlock for TcpStream {
     fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
         self.set_nonblocking(nonblocking)
     }
+    fn raw_fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+    fn spliceable(&self) -> bool {
+        true
+    }
 }
 //impl<S> NonBlock for TlsStream<ClientConnection, &mut S> where S: Read + Write + Send + NonBlock + 'static {
 //    fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
@@ -32,11 +94,141 @@ impl<S> NonBlock for TlsStream<ClientConnection, S> where S: Read + Write + Send
     fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
         self.sock.set_non_blocking(nonblocking)
     }
+    fn raw_fd(&self) -> RawFd {
+        self.sock.raw_fd()
+    }
+    fn pending_write(&self) -> bool {
+        self.conn.wants_write() || self.sock.pending_write()
+    }
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        self.sock.flush_pending()
+    }
+    /// Half-close is signaled with close_notify. The socket stays open, the alert is sent with the pending data.
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.conn.send_close_notify();
+        match self.flush_pending() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(())
+        }
+    }
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_timeouts(timeout)
+    }
 }
 impl<S> NonBlock for TlsStream<ServerConnection, S> where S: Read + Write + Send + NonBlock + 'static {
     fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
         self.sock.set_non_blocking(nonblocking)
     }
+    fn raw_fd(&self) -> RawFd {
+        self.sock.raw_fd()
+    }
+    fn pending_write(&self) -> bool {
+        self.conn.wants_write() || self.sock.pending_write()
+    }
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        self.sock.flush_pending()
+    }
+    /// Half-close is signaled with close_notify. The socket stays open, the alert is sent with the pending data.
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.conn.send_close_notify();
+        match self.flush_pending() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(())
+        }
+    }
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_timeouts(timeout)
+    }
+}
+
+/// Stream that returns some already consumed bytes before reading again from the socket
+pub struct Rewind<S> {
+    pub prefix : Vec<u8>,
+    pub pos : usize,
+    pub inner : S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(inner : S) -> Self {
+        Self {
+            prefix : Vec::new(),
+            pos : 0,
+            inner
+        }
+    }
+    pub fn with_prefix(inner : S, prefix : Vec<u8>) -> Self {
+        Self {
+            prefix,
+            pos : 0,
+            inner
+        }
+    }
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S : Read> Read for Rewind<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let len = buf.len().min(self.prefix.len() - self.pos);
+            buf[0..len].copy_from_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            if self.pos == self.prefix.len() {
+                self.prefix.clear();
+                self.pos = 0;
+            }
+            return Ok(len)
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S : Write> Write for Rewind<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S : NonBlock> NonBlock for Rewind<S> {
+    fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
+        self.inner.set_non_blocking(nonblocking)
+    }
+    fn raw_fd(&self) -> RawFd {
+        self.inner.raw_fd()
+    }
+    fn pending_write(&self) -> bool {
+        self.inner.pending_write()
+    }
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        self.inner.flush_pending()
+    }
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.inner.shutdown_write()
+    }
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_timeouts(timeout)
+    }
+    fn spliceable(&self) -> bool {
+        self.inner.spliceable()
+    }
+    fn take_buffered(&mut self) -> Vec<u8> {
+        let mut buffered = self.prefix.split_off(self.pos);
+        self.prefix.clear();
+        self.pos = 0;
+        buffered.extend(self.inner.take_buffered());
+        buffered
+    }
 }
 
 impl ConnStream {
@@ -85,18 +277,20 @@ where
     let writed = match stream.write(buffer) {
         Ok(v) => v,
         Err(e) => match e.kind() {
-            std::io::ErrorKind::WouldBlock => return Ok(0),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => return Ok(0),
             _ => return Err(e),
         },
     };
     Ok(writed)
 }
 
+/// Reads without blocking and copies the data to the capture. Returns None if there is nothing to read and
+/// Some(0) when the peer closed the connection.
 pub fn read_no_wait<'a, S, P>(
     stream: &'a mut S,
     buffer: &'a mut [u8],
     pcap: &mut P,
-) -> std::io::Result<usize>
+) -> std::io::Result<Option<usize>>
 where
     S: Read + Write + Send + 'static,
     P: Write + ?Sized,
@@ -104,18 +298,36 @@ where
     let readed = match stream.read(buffer) {
         Ok(v) => v,
         Err(e) => match e.kind() {
-            std::io::ErrorKind::WouldBlock => return Ok(0),
+            ErrorKind::WouldBlock | ErrorKind::Interrupted => return Ok(None),
+            // TLS peers closing the socket without close_notify
+            ErrorKind::UnexpectedEof => return Ok(Some(0)),
             _ => return Err(e),
         },
     };
-    if readed == 0 {
-        return Err(std::io::Error::new(
-            ErrorKind::WriteZero,
-            "Connection Closed",
-        ));
-    }
     pcap.write_all(&buffer[0..readed])?;
-    Ok(readed)
+    Ok(Some(readed))
+}
+
+/// Sends the data pending in the stream without blocking
+pub fn flush_no_wait<S>(stream: &mut S) -> std::io::Result<()>
+where
+    S: NonBlock,
+{
+    if !stream.pending_write() {
+        return Ok(());
+    }
+    match stream.flush_pending() {
+        Err(e) if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::Interrupted => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Closes the connection with a RST instead of a FIN, so the client does not wait for any data
+pub fn reset_connection(stream : TcpStream) {
+    let linger = libc::linger { l_onoff : 1, l_linger : 0 };
+    unsafe {
+        libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER, &linger as *const libc::linger as *const libc::c_void, std::mem::size_of::<libc::linger>() as socklen_t);
+    }
 }
 
 pub fn original_dst(stream : &TcpStream) -> std::io::Result<SocketAddr> {
@@ -127,20 +339,20 @@ pub fn original_dst(stream : &TcpStream) -> std::io::Result<SocketAddr> {
         let mut addr_len: socklen_t = std::mem::size_of::<sockaddr_in>() as socklen_t;
         let ret = unsafe { libc::getsockopt(fd, SOL_IP, SO_ORIGINAL_DST, &mut addr as *mut _ as *mut _, &mut addr_len as *mut _,) };
         if ret != 0 {
-            return Err(std::io::Error::from_raw_os_error(ret))
+            return Err(std::io::Error::last_os_error())
         }
         let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
         let port = u16::from_be(addr.sin_port);
-        Ok(std::net::SocketAddr::new(std::net::IpAddr::V4(ip), 443))
+        Ok(std::net::SocketAddr::new(std::net::IpAddr::V4(ip), port))
     }else {
         let mut addr: sockaddr_in6 = unsafe { std::mem::zeroed() };
-        let mut addr_len: socklen_t = std::mem::size_of::<sockaddr_in>() as socklen_t;
+        let mut addr_len: socklen_t = std::mem::size_of::<sockaddr_in6>() as socklen_t;
         let ret = unsafe { libc::getsockopt(fd, SOL_IPV6, IP6T_SO_ORIGINAL_DST, &mut addr as *mut _ as *mut _, &mut addr_len as *mut _,) };
         if ret != 0 {
-            return Err(std::io::Error::from_raw_os_error(ret))
+            return Err(std::io::Error::last_os_error())
         }
         let ip = std::net::Ipv6Addr::from(u128::from_be_bytes(addr.sin6_addr.s6_addr));
         let port = u16::from_be(addr.sin6_port);
-        Ok(std::net::SocketAddr::new(std::net::IpAddr::V6(ip), 443))
+        Ok(std::net::SocketAddr::new(std::net::IpAddr::V6(ip), port))
     }
 }
\ No newline at end of file
@@ -0,0 +1,340 @@
+use std::{collections::HashMap, io::{ErrorKind, Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket}, os::{fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, unix::net::UnixStream}, sync::Arc, time::{Duration, Instant}};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use libc::{c_int, c_void, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t, AF_INET, AF_INET6, IPV6_ORIGDSTADDR, IPV6_RECVORIGDSTADDR, IPV6_TRANSPARENT, IP_ORIGDSTADDR, IP_RECVORIGDSTADDR, IP_TRANSPARENT, SOCK_DGRAM, SOCK_STREAM, SOL_IP, SOL_IPV6, SOL_SOCKET, SO_REUSEADDR};
+
+use crate::proxy::scap::common::{ScapProtocol, ScapSender, ScapStoreRef};
+
+use super::{dst::Destination, egress::{UdpUpstream, UpstreamConnector}, udp::{poll_fd, MAX_DATAGRAM_SIZE}};
+
+/// UDP sessions without traffic during this time are closed
+pub const UDP_SESSION_TIMEOUT : Duration = Duration::from_secs(60);
+
+/// Sessions being set up at the same time. Datagrams of new flows over this are dropped
+const MAX_PENDING_SESSIONS : usize = 256;
+
+/// Datagrams kept for a session while it is set up
+const MAX_PENDING_DATAGRAMS : usize = 32;
+
+/// Creates a TCP listener for TPROXY rules. The local address of the accepted sockets is the original destination.
+/// Needs CAP_NET_ADMIN.
+pub fn tcp_listener(addr : SocketAddr) -> std::io::Result<TcpListener> {
+    let fd = transparent_socket(addr, SOCK_STREAM)?;
+    if unsafe { libc::listen(fd.as_raw_fd(), 1024) } != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    Ok(TcpListener::from(fd))
+}
+
+/// Creates a UDP socket for TPROXY rules. Each datagram carries its original destination, see `recv_orig_dst`.
+pub fn udp_listener(addr : SocketAddr) -> std::io::Result<UdpSocket> {
+    let fd = transparent_socket(addr, SOCK_DGRAM)?;
+    match addr {
+        SocketAddr::V4(_) => set_opt(fd.as_raw_fd(), SOL_IP, IP_RECVORIGDSTADDR)?,
+        SocketAddr::V6(_) => {
+            set_opt(fd.as_raw_fd(), SOL_IPV6, IPV6_RECVORIGDSTADDR)?;
+            // Dual stack sockets also receive IPv4 datagrams
+            let _ = set_opt(fd.as_raw_fd(), SOL_IP, IP_RECVORIGDSTADDR);
+        }
+    }
+    Ok(UdpSocket::from(fd))
+}
+
+/// UDP socket bound to a non local address, used to answer the client with the original destination as source
+pub fn udp_spoofed(addr : SocketAddr) -> std::io::Result<UdpSocket> {
+    Ok(UdpSocket::from(transparent_socket(addr, SOCK_DGRAM)?))
+}
+
+fn transparent_socket(addr : SocketAddr, ty : c_int) -> std::io::Result<OwnedFd> {
+    let family = if addr.is_ipv4() { AF_INET } else { AF_INET6 };
+    let fd = unsafe { libc::socket(family, ty | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    set_opt(fd.as_raw_fd(), SOL_SOCKET, SO_REUSEADDR)?;
+    match addr {
+        SocketAddr::V4(_) => set_opt(fd.as_raw_fd(), SOL_IP, IP_TRANSPARENT)?,
+        SocketAddr::V6(_) => {
+            set_opt(fd.as_raw_fd(), SOL_IPV6, IPV6_TRANSPARENT)?;
+            let _ = set_opt(fd.as_raw_fd(), SOL_IP, IP_TRANSPARENT);
+        }
+    }
+    let (storage, len) = to_sockaddr(&addr);
+    if unsafe { libc::bind(fd.as_raw_fd(), &storage as *const _ as *const libc::sockaddr, len) } != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    Ok(fd)
+}
+
+fn set_opt(fd : RawFd, level : c_int, name : c_int) -> std::io::Result<()> {
+    let value : c_int = 1;
+    let ret = unsafe { libc::setsockopt(fd, level, name, &value as *const _ as *const c_void, std::mem::size_of::<c_int>() as socklen_t) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+/// Receives a datagram from a TPROXY socket. Returns the size, the client address and the original destination.
+pub fn recv_orig_dst(socket : &UdpSocket, buffer : &mut [u8]) -> std::io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src : sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base : buffer.as_mut_ptr() as *mut c_void,
+        iov_len : buffer.len()
+    };
+    let mut msg : libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut src as *mut _ as *mut c_void;
+    msg.msg_namelen = std::mem::size_of::<sockaddr_storage>() as socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let readed = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if readed < 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    let src = from_sockaddr(&src).ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Unknown source address family"))?;
+    let mut orig = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        if (hdr.cmsg_level == SOL_IP && hdr.cmsg_type == IP_ORIGDSTADDR) || (hdr.cmsg_level == SOL_IPV6 && hdr.cmsg_type == IPV6_ORIGDSTADDR) {
+            let mut storage : sockaddr_storage = unsafe { std::mem::zeroed() };
+            let len = (hdr.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize).min(std::mem::size_of::<sockaddr_storage>());
+            unsafe { std::ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), &mut storage as *mut _ as *mut u8, len) };
+            orig = from_sockaddr(&storage);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    let orig = orig.ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "Datagram without original destination"))?;
+    Ok((readed as usize, src, orig))
+}
+
+fn to_sockaddr(addr : &SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage : sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(v) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut sockaddr_in) };
+            sin.sin_family = AF_INET as _;
+            sin.sin_port = v.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*v.ip()).to_be();
+            std::mem::size_of::<sockaddr_in>()
+        }
+        SocketAddr::V6(v) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut sockaddr_in6) };
+            sin6.sin6_family = AF_INET6 as _;
+            sin6.sin6_port = v.port().to_be();
+            sin6.sin6_addr.s6_addr = v.ip().octets();
+            sin6.sin6_scope_id = v.scope_id();
+            std::mem::size_of::<sockaddr_in6>()
+        }
+    };
+    (storage, len as socklen_t)
+}
+
+fn from_sockaddr(storage : &sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as c_int {
+        AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be(sin.sin_port)))
+        }
+        AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr).to_canonical();
+            Some(SocketAddr::new(ip, u16::from_be(sin6.sin6_port)))
+        }
+        _ => None
+    }
+}
+
+/// Flow of datagrams between a client and an original destination
+struct UdpSession {
+    upstream : Box<dyn UdpUpstream>,
+    /// Socket bound to the original destination used to answer the client
+    reply : UdpSocket,
+    scap : ScapSender,
+    last_seen : Instant,
+}
+
+type SessionKey = (SocketAddr, SocketAddr);
+
+/// Upstream and reply sockets of a new session, opened outside the listener thread
+type SessionSetup = (SessionKey, std::io::Result<(Box<dyn UdpUpstream>, UdpSocket)>);
+
+/// Relays the UDP traffic captured by a TPROXY rule. Each (client, original destination) pair gets its own upstream
+/// socket, and the replies are sent back from the original destination address.
+pub struct TproxyUdpListener {
+    socket : UdpSocket,
+    egress : Arc<dyn UpstreamConnector>,
+    scap : ScapStoreRef,
+    sessions : HashMap<SessionKey, UdpSession>,
+    /// Datagrams of the sessions being set up. Opening the upstream can block, like a SOCKS5 UDP ASSOCIATE, so it
+    /// runs in its own thread and the other sessions keep flowing.
+    pending : HashMap<SessionKey, Vec<Vec<u8>>>,
+    setup_sender : Sender<SessionSetup>,
+    setup_receiver : Receiver<SessionSetup>,
+    /// Wakes up the listener when a setup finishes
+    wake : (UnixStream, UnixStream),
+    buffer : Vec<u8>,
+}
+
+impl TproxyUdpListener {
+    pub fn new(socket : UdpSocket, egress : Arc<dyn UpstreamConnector>, scap : ScapStoreRef) -> std::io::Result<Self> {
+        let (setup_sender, setup_receiver) = unbounded();
+        let wake = UnixStream::pair()?;
+        wake.0.set_nonblocking(true)?;
+        wake.1.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            egress,
+            scap,
+            sessions : HashMap::new(),
+            pending : HashMap::new(),
+            setup_sender,
+            setup_receiver,
+            wake,
+            buffer : vec![0; MAX_DATAGRAM_SIZE]
+        })
+    }
+
+    pub fn run(&mut self) -> std::io::Result<()> {
+        loop {
+            let keys : Vec<SessionKey> = self.sessions.keys().cloned().collect();
+            let mut fds = Vec::with_capacity(keys.len() * 2 + 2);
+            fds.push(poll_fd(self.socket.as_raw_fd()));
+            fds.push(poll_fd(self.wake.0.as_raw_fd()));
+            for key in &keys {
+                let session = &self.sessions[key];
+                fds.push(poll_fd(session.upstream.raw_fd()));
+                fds.push(poll_fd(session.upstream.control_fd().unwrap_or(-1)));
+            }
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 1000) };
+            if res < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue
+                }
+                return Err(err)
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                match recv_orig_dst(&self.socket, &mut self.buffer) {
+                    Ok((readed, client, orig)) => self.client_datagram(client, orig, readed),
+                    Err(e) => log::trace!("Cannot receive datagram: {e}")
+                }
+            }
+            if fds[1].revents & libc::POLLIN != 0 {
+                self.finish_setups();
+            }
+            for (i, key) in keys.iter().enumerate() {
+                if fds[3 + i * 2].revents != 0 {
+                    log::debug!("UDP association of {} closed by the upstream", key.0);
+                    self.sessions.remove(key);
+                    continue
+                }
+                if fds[2 + i * 2].revents & libc::POLLIN != 0 {
+                    self.upstream_datagram(key);
+                }
+            }
+            self.sessions.retain(|_, session| session.last_seen.elapsed() < UDP_SESSION_TIMEOUT);
+        }
+    }
+
+    fn client_datagram(&mut self, client : SocketAddr, orig : SocketAddr, readed : usize) {
+        let key = (client, orig);
+        if let Some(session) = self.sessions.get_mut(&key) {
+            forward(session, orig, &self.buffer[0..readed]);
+            return
+        }
+        if let Some(queue) = self.pending.get_mut(&key) {
+            if queue.len() < MAX_PENDING_DATAGRAMS {
+                queue.push(self.buffer[0..readed].to_vec());
+            }
+            return
+        }
+        if self.pending.len() >= MAX_PENDING_SESSIONS {
+            log::debug!("Dropping datagram from {} to {}: {} UDP sessions being set up", client, orig, self.pending.len());
+            return
+        }
+        log::debug!("New UDP session {} -> {}", client, orig);
+        self.pending.insert(key, vec![self.buffer[0..readed].to_vec()]);
+        let egress = self.egress.clone();
+        let sender = self.setup_sender.clone();
+        let wake = match self.wake.1.try_clone() {
+            Ok(v) => v,
+            Err(e) => {
+                log::debug!("Cannot open UDP session to {}: {e}", orig);
+                self.pending.remove(&key);
+                return
+            }
+        };
+        let spawned = std::thread::Builder::new().name("TproxyUdpSetup".into()).spawn(move || {
+            let res = egress.udp().and_then(|upstream| Ok((upstream, udp_spoofed(orig)?)));
+            let _ = sender.send((key, res));
+            let _ = (&wake).write(&[1]);
+        });
+        if let Err(e) = spawned {
+            log::debug!("Cannot open UDP session to {}: {e}", orig);
+            self.pending.remove(&key);
+        }
+    }
+
+    /// Starts the sessions already set up, sending the datagrams queued meanwhile
+    fn finish_setups(&mut self) {
+        let mut drain = [0u8; 64];
+        while matches!((&self.wake.0).read(&mut drain), Ok(v) if v > 0) {}
+        while let Ok(((client, orig), res)) = self.setup_receiver.try_recv() {
+            let queue = self.pending.remove(&(client, orig)).unwrap_or_default();
+            let (upstream, reply) = match res {
+                Ok(v) => v,
+                Err(e) => {
+                    log::debug!("Cannot open UDP session to {}: {e}", orig);
+                    continue
+                }
+            };
+            let mut session = UdpSession {
+                upstream,
+                reply,
+                scap : self.scap.sender(ScapProtocol::Udp, (orig.ip(), orig.port()), (client.ip(), client.port())),
+                last_seen : Instant::now()
+            };
+            for datagram in queue {
+                forward(&mut session, orig, &datagram);
+            }
+            self.sessions.insert((client, orig), session);
+        }
+    }
+
+    fn upstream_datagram(&mut self, key : &SessionKey) {
+        let session = match self.sessions.get_mut(key) {
+            Some(v) => v,
+            None => return
+        };
+        let readed = match session.upstream.recv_from(&mut self.buffer) {
+            Ok((_, v)) => v,
+            Err(e) => {
+                log::trace!("Cannot receive datagram: {e}");
+                return
+            }
+        };
+        session.last_seen = Instant::now();
+        if let Err(e) = session.reply.send_to(&self.buffer[0..readed], key.0) {
+            log::trace!("Cannot send datagram to {}: {e}", key.0);
+            return
+        }
+        let _ = session.scap.from_client().write_all(&self.buffer[0..readed]);
+    }
+}
+
+/// Sends a datagram of the client to its original destination
+fn forward(session : &mut UdpSession, orig : SocketAddr, datagram : &[u8]) {
+    session.last_seen = Instant::now();
+    if let Err(e) = session.upstream.send_to(&Destination::Addr(orig), datagram) {
+        log::trace!("Cannot send datagram to {}: {e}", orig);
+        return
+    }
+    let _ = session.scap.from_server().write_all(datagram);
+}
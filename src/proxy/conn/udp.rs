@@ -0,0 +1,247 @@
+use std::{collections::HashMap, io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream, UdpSocket}, os::fd::{AsRawFd, RawFd}, time::{Duration, Instant}};
+
+use crate::proxy::{scap::common::{ScapProtocol, ScapSender, ScapStoreRef, ScapTimeout}, socks5::common::Socks5UdpHeader};
+
+use super::{dst::Destination, egress::UdpUpstream};
+
+/// Maximum size of a datagram
+pub const MAX_DATAGRAM_SIZE : usize = 65_536;
+
+/// Relays the datagrams of a SOCKS5 UDP association. The client sends them wrapped in the RFC 1928 UDP request header,
+/// they are unwrapped and sent to the upstream, and the replies are wrapped again with the address they came from.
+/// The relay ends when the control connection of the client or the upstream gets closed, or after the idle timeout
+/// without datagrams.
+pub struct UdpRelay<'a> {
+    pub client : UdpSocket,
+    pub control : &'a mut TcpStream,
+    pub upstream : Box<dyn UdpUpstream>,
+    pub scap : ScapStoreRef,
+    /// Address the client sends the datagrams from. Learned from the first datagram if the client did not announce it.
+    pub client_addr : Option<SocketAddr>,
+    /// Time without datagrams before the association is closed. Each destination silent this long is stored.
+    pub idle : Duration,
+    buffer : Vec<u8>,
+    /// Capture of each destination, with its last datagram
+    captures : HashMap<Destination, (ScapSender, Instant)>,
+}
+
+impl<'a> UdpRelay<'a> {
+    pub fn new(client : UdpSocket, control : &'a mut TcpStream, upstream : Box<dyn UdpUpstream>, scap : ScapStoreRef, client_addr : Option<SocketAddr>, idle : Duration) -> Self {
+        Self {
+            client,
+            control,
+            upstream,
+            scap,
+            client_addr,
+            idle,
+            buffer : vec![0; MAX_DATAGRAM_SIZE],
+            captures : HashMap::new()
+        }
+    }
+
+    pub fn relay(&mut self) -> std::io::Result<()> {
+        let control_ip = self.control.peer_addr()?.ip();
+        let mut fds = vec![
+            poll_fd(self.client.as_raw_fd()),
+            poll_fd(self.upstream.raw_fd()),
+            poll_fd(self.control.as_raw_fd()),
+        ];
+        if let Some(fd) = self.upstream.control_fd() {
+            fds.push(poll_fd(fd));
+        }
+        let mut last_seen = Instant::now();
+        loop {
+            for fd in fds.iter_mut() {
+                fd.revents = 0;
+            }
+            self.expire_captures();
+            let left = self.idle.saturating_sub(last_seen.elapsed());
+            if left.is_zero() {
+                log::debug!("UDP association idle for {:?}", self.idle);
+                for (scap, _) in self.captures.values() {
+                    scap.timeout(ScapTimeout::Idle);
+                }
+                return Ok(())
+            }
+            // Woken up at least every second to store the silent destinations
+            let timeout = left.min(Duration::from_secs(1)).as_millis().max(1) as libc::c_int;
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if res < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue
+                }
+                return Err(err)
+            }
+            // A failed datagram is dropped, only the closing of the control connection ends the association
+            if fds[0].revents & libc::POLLIN != 0 {
+                last_seen = Instant::now();
+                match self.client.recv_from(&mut self.buffer) {
+                    Ok((readed, from)) => self.client_datagram(control_ip, from, readed),
+                    Err(e) => log::trace!("Cannot receive datagram from the client: {e}")
+                }
+            }
+            if fds[1].revents & libc::POLLIN != 0 {
+                last_seen = Instant::now();
+                self.upstream_datagram();
+            }
+            if fds[2].revents != 0 {
+                // Nothing is expected on the control connection, only its closing
+                let readed = match self.control.read(&mut self.buffer) {
+                    Ok(v) => v,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => 0
+                };
+                if readed == 0 {
+                    log::debug!("UDP association closed by the client");
+                    return Ok(())
+                }
+            }
+            if fds.len() > 3 && fds[3].revents != 0 {
+                log::debug!("UDP association closed by the upstream");
+                return Ok(())
+            }
+        }
+    }
+
+    /// Drops the captures of the destinations without datagrams during the idle timeout, so they are stored
+    fn expire_captures(&mut self) {
+        let idle = self.idle;
+        self.captures.retain(|dst, (scap, last_seen)| {
+            if last_seen.elapsed() < idle {
+                return true
+            }
+            log::trace!("UDP destination {} idle, storing its capture", dst);
+            scap.timeout(ScapTimeout::Idle);
+            false
+        });
+    }
+
+    fn client_datagram(&mut self, control_ip : std::net::IpAddr, from : SocketAddr, readed : usize) {
+        match self.client_addr {
+            Some(v) if v != from => {
+                log::trace!("Dropping datagram from unknown source: {}", from);
+                return
+            },
+            Some(_) => {},
+            None if from.ip() != control_ip => {
+                log::trace!("Dropping datagram from unknown source: {}", from);
+                return
+            },
+            None => self.client_addr = Some(from)
+        }
+        let (header, pos) = match Socks5UdpHeader::read_from(&self.buffer[0..readed]) {
+            Ok(v) => v,
+            Err(e) => {
+                log::trace!("Invalid UDP request header: {e}");
+                return
+            }
+        };
+        if header.frag != 0 {
+            log::trace!("Dropping fragmented datagram");
+            return
+        }
+        let dst = Destination::from_socks5(&header.dst_addr, header.dst_port);
+        if let Err(e) = self.upstream.send_to(&dst, &self.buffer[pos..readed]) {
+            log::trace!("Cannot send datagram to {}: {e}", dst);
+            return
+        }
+        let scap = capture(&mut self.captures, &self.scap, dst, from);
+        if let Err(e) = scap.from_server().write_all(&self.buffer[pos..readed]) {
+            log::trace!("Cannot capture datagram: {e}");
+        }
+    }
+
+    fn upstream_datagram(&mut self) {
+        let (src, readed) = match self.upstream.recv_from(&mut self.buffer) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => return,
+            Err(e) => {
+                log::trace!("Cannot receive datagram: {e}");
+                return
+            }
+        };
+        let client_addr = match self.client_addr {
+            Some(v) => v,
+            None => return
+        };
+        let header = Socks5UdpHeader {
+            frag : 0,
+            dst_addr : src.to_socks5(),
+            dst_port : src.port()
+        };
+        let mut datagram = Vec::with_capacity(readed + 22);
+        if let Err(e) = header.write_to(&mut datagram) {
+            log::trace!("Cannot wrap datagram from {}: {e}", src);
+            return
+        }
+        datagram.extend_from_slice(&self.buffer[0..readed]);
+        if let Err(e) = self.client.send_to(&datagram, client_addr) {
+            log::trace!("Cannot send datagram to the client: {e}");
+            return
+        }
+        let scap = capture(&mut self.captures, &self.scap, src, client_addr);
+        if let Err(e) = scap.from_client().write_all(&self.buffer[0..readed]) {
+            log::trace!("Cannot capture datagram: {e}");
+        }
+    }
+}
+
+/// Each destination of the association is recorded as a different capture
+fn capture<'a>(captures : &'a mut HashMap<Destination, (ScapSender, Instant)>, scap : &ScapStoreRef, dst : Destination, client : SocketAddr) -> &'a ScapSender {
+    let (scap, last_seen) = captures.entry(dst).or_insert_with_key(|dst| {
+        (scap.sender(ScapProtocol::Udp, (dst.capture_ip(), dst.port()), (client.ip(), client.port())), Instant::now())
+    });
+    *last_seen = Instant::now();
+    scap
+}
+
+pub fn poll_fd(fd : RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events : libc::POLLIN,
+        revents : 0
+    }
+}
+
+#[test]
+fn should_close_idle_destinations_and_association() {
+    use std::net::TcpListener;
+    use crate::proxy::{conn::egress::DirectUdp, scap::common::{ScapEvent, ScapStore}};
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let store = ScapStore::new(sender);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client_control = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut control, _) = listener.accept().unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let relay_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let relay_addr = relay_socket.local_addr().unwrap();
+    let quiet = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let busy = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let wrapped = |dst : SocketAddr| {
+        let mut datagram = Vec::new();
+        Socks5UdpHeader { frag : 0, dst_addr : dst.ip().into(), dst_port : dst.port() }.write_to(&mut datagram).unwrap();
+        datagram.extend_from_slice(b"ping");
+        datagram
+    };
+    let upstream = Box::new(DirectUdp { socket : UdpSocket::bind("127.0.0.1:0").unwrap(), dual_stack : false });
+    let mut relay = UdpRelay::new(relay_socket, &mut control, upstream, store.reference(), Some(client.local_addr().unwrap()), Duration::from_millis(400));
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            client.send_to(&wrapped(quiet.local_addr().unwrap()), relay_addr).unwrap();
+            // Keeps the association alive after the quiet destination expired
+            for _ in 0..10 {
+                client.send_to(&wrapped(busy.local_addr().unwrap()), relay_addr).unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+        relay.relay().unwrap();
+    });
+    assert!(started.elapsed() >= Duration::from_millis(1200), "{:?}", started.elapsed());
+    let closed : Vec<u16> = receiver.try_iter().filter_map(|v| match v {
+        ScapEvent::Close(addr) => Some(addr.rport),
+        _ => None
+    }).collect();
+    assert_eq!(vec![quiet.local_addr().unwrap().port(), busy.local_addr().unwrap().port()], closed);
+}
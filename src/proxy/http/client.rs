@@ -0,0 +1,109 @@
+use std::{io::{Error, ErrorKind, Read, Write}, net::TcpStream, os::fd::{AsRawFd, RawFd}, time::Duration};
+
+use httparse::{Status, EMPTY_HEADER};
+
+use crate::proxy::conn::{dst::Destination, stream::NonBlock};
+
+use super::server::MAX_HEAD_SIZE;
+
+/// Tunnel through an upstream HTTP proxy using the CONNECT method
+pub struct HttpConnectClient {
+    pub dst : Destination,
+    pub conn : TcpStream,
+}
+
+impl HttpConnectClient {
+    pub fn connect(addr : &str, dst : Destination) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self::from_stream(stream, dst))
+    }
+
+    /// Uses an already established connection to the HTTP proxy
+    pub fn from_stream(conn : TcpStream, dst : Destination) -> Self {
+        Self { conn, dst }
+    }
+
+    pub fn tunnel(&mut self) -> std::io::Result<()> {
+        let authority = match &self.dst {
+            Destination::Addr(v) => v.to_string(),
+            Destination::Domain(v, port) => format!("{v}:{port}"),
+        };
+        log::debug!("Sending CONNECT to: {}", authority);
+        let req = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+        self.conn.write_all(req.as_bytes())?;
+        // Read byte by byte so nothing from the tunnel is consumed with the response head
+        let mut head = Vec::with_capacity(256);
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > MAX_HEAD_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "CONNECT response head too large"))
+            }
+            self.conn.read_exact(&mut byte)?;
+            head.push(byte[0]);
+        }
+        let mut headers = [EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&head) {
+            Ok(Status::Complete(_)) => {},
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid CONNECT response"))
+        }
+        Self::raise_response(response.code.unwrap_or(0))
+    }
+
+    pub fn raise_response(code : u16) -> std::io::Result<()> {
+        Err(match code {
+            200..=299 => return Ok(()),
+            403 | 407 => Error::new(ErrorKind::PermissionDenied, format!("Proxy denied the tunnel: {code}")),
+            502 => Error::new(ErrorKind::ConnectionRefused, "Bad gateway"),
+            503 => Error::new(ErrorKind::ConnectionRefused, "Service unavailable"),
+            504 => Error::new(ErrorKind::TimedOut, "Gateway timeout"),
+            // Not Interrupted: the relay retries those, and the tunnel was refused for good
+            _ => Error::other(format!("Proxy failure: {code}")),
+        })
+    }
+}
+
+impl Write for HttpConnectClient {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+impl Read for HttpConnectClient {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.conn.read(buf)
+    }
+}
+
+impl NonBlock for HttpConnectClient {
+    fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
+        self.conn.set_nonblocking(nonblocking)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.conn.shutdown_write()
+    }
+
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.conn.set_timeouts(timeout)
+    }
+
+    fn spliceable(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn should_fail_for_good_on_proxy_errors() {
+    assert!(HttpConnectClient::raise_response(200).is_ok());
+    assert_eq!(ErrorKind::ConnectionRefused, HttpConnectClient::raise_response(503).unwrap_err().kind());
+    assert_eq!(ErrorKind::Other, HttpConnectClient::raise_response(500).unwrap_err().kind());
+}
@@ -0,0 +1,278 @@
+use std::{io::{ErrorKind, Read, Write}, os::fd::RawFd, time::Duration};
+
+use crate::proxy::conn::stream::NonBlock;
+
+use super::server::MAX_HEAD_SIZE;
+
+/// Headers of the client connection that are not forwarded to the destination
+pub const HOP_HEADERS : [&str; 4] = ["connection", "keep-alive", "proxy-connection", "proxy-authorization"];
+
+/// Where the first request of a plain proxy connection ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFraming {
+    /// Bytes left of the head and the body
+    Length(u64),
+    /// Bytes left of the head before a chunked body
+    Chunked(u64),
+}
+
+impl RequestFraming {
+    /// Framing of a request with a head of `head_len` bytes, from its Transfer-Encoding and Content-Length
+    pub fn new(head_len : usize, headers : &[httparse::Header]) -> Self {
+        let chunked = headers.iter()
+            .filter(|v| v.name.eq_ignore_ascii_case("transfer-encoding"))
+            .any(|v| String::from_utf8_lossy(v.value).to_lowercase().contains("chunked"));
+        if chunked {
+            return RequestFraming::Chunked(head_len as u64)
+        }
+        let body = headers.iter()
+            .find(|v| v.name.eq_ignore_ascii_case("content-length"))
+            .and_then(|v| std::str::from_utf8(v.value).ok()?.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        RequestFraming::Length(head_len as u64 + body)
+    }
+}
+
+/// Position inside a chunked body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    /// Bytes of the head before the body
+    Head(u64),
+    /// Chunk size line, with the size read so far and if the extensions started
+    Size(u64, bool),
+    Data(u64),
+    /// CRLF after the data
+    DataEnd,
+    /// Trailer line, with the number of bytes in it
+    Trailer(usize),
+    Done,
+}
+
+impl Chunk {
+    /// Consumes the bytes of the body. Returns how many belong to it.
+    fn consume(&mut self, data : &[u8]) -> usize {
+        let mut pos = 0;
+        while pos < data.len() {
+            let byte = data[pos];
+            *self = match *self {
+                Chunk::Head(left) => {
+                    let taken = left.min((data.len() - pos) as u64);
+                    pos += taken as usize;
+                    if taken == left { Chunk::Size(0, false) } else { Chunk::Head(left - taken) }
+                },
+                Chunk::Size(size, ext) => {
+                    pos += 1;
+                    match (byte as char).to_digit(16) {
+                        _ if byte == b'\n' && size == 0 => Chunk::Trailer(0),
+                        _ if byte == b'\n' => Chunk::Data(size),
+                        Some(v) if !ext => Chunk::Size(size.saturating_mul(16).saturating_add(v as u64), ext),
+                        _ => Chunk::Size(size, ext || byte == b';'),
+                    }
+                },
+                Chunk::Data(left) => {
+                    let taken = left.min((data.len() - pos) as u64);
+                    pos += taken as usize;
+                    if taken == left { Chunk::DataEnd } else { Chunk::Data(left - taken) }
+                },
+                Chunk::DataEnd => {
+                    pos += 1;
+                    if byte == b'\n' { Chunk::Size(0, false) } else { Chunk::DataEnd }
+                },
+                Chunk::Trailer(len) => {
+                    pos += 1;
+                    match byte {
+                        b'\n' if len == 0 => Chunk::Done,
+                        b'\n' => Chunk::Trailer(0),
+                        b'\r' => Chunk::Trailer(len),
+                        _ => Chunk::Trailer(len + 1),
+                    }
+                },
+                Chunk::Done => return pos
+            };
+            if *self == Chunk::Done {
+                return pos
+            }
+        }
+        pos
+    }
+}
+
+/// Client side of a plain request sent to the explicit HTTP proxy. Only the first request reaches the destination:
+/// the read side ends after its body and anything pipelined behind it is dropped, because it can be addressed to
+/// another host. The client sends it again on a new connection after the `Connection: close` response.
+pub struct SingleRequest<S> {
+    inner : S,
+    length : Option<u64>,
+    chunk : Chunk,
+}
+
+impl<S> SingleRequest<S> {
+    pub fn new(inner : S, framing : RequestFraming) -> Self {
+        match framing {
+            RequestFraming::Length(v) => Self { inner, length : Some(v), chunk : Chunk::Done },
+            RequestFraming::Chunked(head) => Self { inner, length : None, chunk : Chunk::Head(head) },
+        }
+    }
+
+    fn finished(&self) -> bool {
+        match self.length {
+            Some(v) => v == 0,
+            None => self.chunk == Chunk::Done
+        }
+    }
+}
+
+impl<S : Read> Read for SingleRequest<S> {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        if self.finished() {
+            return Ok(0)
+        }
+        let readed = self.inner.read(buf)?;
+        let taken = match &mut self.length {
+            Some(left) => {
+                let taken = (*left).min(readed as u64);
+                *left -= taken;
+                taken as usize
+            },
+            None => self.chunk.consume(&buf[..readed])
+        };
+        if taken < readed {
+            log::debug!("Dropping {} bytes sent after the proxied request", readed - taken);
+        }
+        Ok(taken)
+    }
+}
+
+impl<S : Write> Write for SingleRequest<S> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Server side of a plain request sent to the explicit HTTP proxy. The head of the final response is rewritten with
+/// `Connection: close`, so the client does not reuse the connection for other hosts.
+pub struct CloseResponse<S> {
+    inner : S,
+    /// Bytes read while looking for the head, or the rewritten head not returned yet
+    head : Vec<u8>,
+    pos : usize,
+    rewritten : bool,
+}
+
+impl<S> CloseResponse<S> {
+    pub fn new(inner : S) -> Self {
+        Self { inner, head : Vec::with_capacity(1024), pos : 0, rewritten : false }
+    }
+
+    /// Rewrites the complete heads in the buffer. Interim responses (1xx) are left untouched.
+    fn rewrite(&mut self) {
+        while !self.rewritten {
+            let end = match self.head[self.pos..].windows(4).position(|v| v == b"\r\n\r\n") {
+                Some(v) => self.pos + v + 4,
+                None => return
+            };
+            let head = String::from_utf8_lossy(&self.head[self.pos..end]).to_string();
+            let interim = head.split(' ').nth(1).is_some_and(|v| v.starts_with('1'));
+            if interim {
+                self.pos = end;
+                continue
+            }
+            let mut new_head = String::with_capacity(head.len() + 19);
+            for line in head.trim_end().split("\r\n") {
+                let name = line.split(':').next().unwrap_or_default().trim();
+                if !new_head.is_empty() && HOP_HEADERS.iter().any(|v| name.eq_ignore_ascii_case(v)) {
+                    continue
+                }
+                new_head.push_str(line);
+                new_head.push_str("\r\n");
+            }
+            new_head.push_str("Connection: close\r\n\r\n");
+            self.head.splice(self.pos..end, new_head.bytes());
+            self.rewritten = true;
+        }
+    }
+}
+
+impl<S : Read> Read for CloseResponse<S> {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        while !self.rewritten {
+            let readed = match self.inner.read(&mut chunk) {
+                Ok(v) => v,
+                Err(e) if e.kind() == ErrorKind::WouldBlock && self.pos > 0 => break,
+                Err(e) => return Err(e)
+            };
+            self.head.extend_from_slice(&chunk[..readed]);
+            self.rewrite();
+            if readed == 0 || self.head.len() > MAX_HEAD_SIZE {
+                // Not an HTTP response, relayed as it is
+                self.rewritten = true;
+            }
+        }
+        if !self.head.is_empty() {
+            // Before the rewrite only the interim responses already complete are returned
+            let available = if self.rewritten { self.head.len() } else { self.pos };
+            let len = buf.len().min(available);
+            buf[..len].copy_from_slice(&self.head[..len]);
+            self.head.drain(..len);
+            self.pos = self.pos.saturating_sub(len);
+            return Ok(len)
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S : Write> Write for CloseResponse<S> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+macro_rules! delegate_non_block {
+    ($name:ident) => {
+        impl<S : NonBlock> NonBlock for $name<S> {
+            fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
+                self.inner.set_non_blocking(nonblocking)
+            }
+            fn raw_fd(&self) -> RawFd {
+                self.inner.raw_fd()
+            }
+            fn pending_write(&self) -> bool {
+                self.inner.pending_write()
+            }
+            fn flush_pending(&mut self) -> std::io::Result<()> {
+                self.inner.flush_pending()
+            }
+            fn shutdown_write(&mut self) -> std::io::Result<()> {
+                self.inner.shutdown_write()
+            }
+            fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+                self.inner.set_timeouts(timeout)
+            }
+        }
+    };
+}
+
+delegate_non_block!(SingleRequest);
+delegate_non_block!(CloseResponse);
+
+#[test]
+fn should_close_after_the_response() {
+    let res = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\nContent-Length: 2\r\n\r\nok";
+    let mut stream = CloseResponse::new(&res[..]);
+    let mut out = Vec::new();
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(&b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"[..], &out[..]);
+
+    let req = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4;a=b\r\nbody\r\n0\r\nX-Sum: 1\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+    let mut stream = SingleRequest::new(&req[..], RequestFraming::Chunked(47));
+    let mut out = Vec::new();
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(&req[..req.len() - 22], &out[..]);
+}
@@ -0,0 +1,3 @@
+pub mod server;
+pub mod client;
+pub mod forward;
\ No newline at end of file
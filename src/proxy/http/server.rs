@@ -0,0 +1,167 @@
+use std::{io::{Error, ErrorKind, Read, Write}, net::{IpAddr, SocketAddr}};
+
+use httparse::{Status, EMPTY_HEADER};
+
+use crate::proxy::conn::dst::Destination;
+
+use super::forward::{RequestFraming, HOP_HEADERS};
+
+/// Maximum size of a request head sent to the explicit HTTP proxy
+pub const MAX_HEAD_SIZE : usize = 16_384;
+
+/// Request received by the explicit HTTP proxy
+#[derive(Debug, Clone)]
+pub struct HttpProxyRequest {
+    pub dst : Destination,
+    /// CONNECT tunnel or plain HTTP request with an absolute URI
+    pub connect : bool,
+    /// Bytes that must be sent to the destination before relaying: the request rewritten to origin-form for plain
+    /// requests or anything the client pipelined after the CONNECT head.
+    pub pending : Vec<u8>,
+    /// End of the request relayed for plain requests
+    pub framing : Option<RequestFraming>,
+}
+
+/// Server side of an explicit HTTP proxy (CONNECT and absolute-URI requests).
+pub struct HttpProxyServer<'a, S> {
+    pub conn : &'a mut S,
+}
+
+impl<'a, S> HttpProxyServer<'a, S> where S : Read + Write {
+    pub fn new(conn : &'a mut S) -> Self {
+        Self { conn }
+    }
+
+    /// Reads the request head sent by the client
+    pub fn request(&mut self) -> std::io::Result<HttpProxyRequest> {
+        let mut buffer = Vec::with_capacity(4096);
+        let mut chunk = [0u8; 4096];
+        loop {
+            let readed = self.conn.read(&mut chunk)?;
+            if readed == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed before the request head"))
+            }
+            buffer.extend_from_slice(&chunk[0..readed]);
+            if let Some(req) = parse_request(&buffer)? {
+                return Ok(req)
+            }
+            if buffer.len() > MAX_HEAD_SIZE {
+                self.reply(431, "Request Header Fields Too Large")?;
+                return Err(Error::new(ErrorKind::InvalidData, "Request head too large"))
+            }
+        }
+    }
+
+    pub fn reply_established(&mut self) -> std::io::Result<()> {
+        self.conn.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+    }
+
+    pub fn reply_error(&mut self, err : &Error) -> std::io::Result<()> {
+        match err.kind() {
+            ErrorKind::TimedOut => self.reply(504, "Gateway Timeout"),
+            ErrorKind::QuotaExceeded => self.reply(429, "Too Many Requests"),
+            ErrorKind::InvalidData | ErrorKind::InvalidInput => self.reply(400, "Bad Request"),
+            _ => self.reply(502, "Bad Gateway"),
+        }
+    }
+
+    pub fn reply(&mut self, code : u16, reason : &str) -> std::io::Result<()> {
+        let res = format!("HTTP/1.1 {code} {reason}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        self.conn.write_all(res.as_bytes())
+    }
+}
+
+/// Parses a proxy request head. Returns None if the head is not complete.
+pub fn parse_request(buffer : &[u8]) -> std::io::Result<Option<HttpProxyRequest>> {
+    let mut headers = [EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let body_start = match request.parse(buffer) {
+        Ok(Status::Complete(v)) => v,
+        Ok(Status::Partial) => return Ok(None),
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid proxy request: {e}")))
+    };
+    let method = request.method.unwrap_or_default();
+    let path = request.path.unwrap_or_default();
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let dst = parse_authority(path, 443)?;
+        return Ok(Some(HttpProxyRequest {
+            dst,
+            connect : true,
+            pending : buffer[body_start..].to_vec(),
+            framing : None
+        }))
+    }
+    let uri = match path.get(0..7) {
+        Some(v) if v.eq_ignore_ascii_case("http://") => &path[7..],
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Not an absolute URI: {path}")))
+    };
+    let (authority, origin) = match uri.find('/') {
+        Some(pos) => (&uri[..pos], &uri[pos..]),
+        None => (uri, "/")
+    };
+    let dst = parse_authority(authority, 80)?;
+    let mut pending = Vec::with_capacity(buffer.len());
+    pending.extend_from_slice(format!("{method} {origin} HTTP/1.{}\r\n", request.version.unwrap_or(1)).as_bytes());
+    for header in request.headers.iter() {
+        if HOP_HEADERS.iter().any(|v| header.name.eq_ignore_ascii_case(v)) {
+            continue
+        }
+        pending.extend_from_slice(header.name.as_bytes());
+        pending.extend_from_slice(b": ");
+        pending.extend_from_slice(header.value);
+        pending.extend_from_slice(b"\r\n");
+    }
+    // The connection only carries this request, the next ones can go to another host
+    pending.extend_from_slice(b"Connection: close\r\n\r\n");
+    let framing = RequestFraming::new(pending.len(), request.headers);
+    pending.extend_from_slice(&buffer[body_start..]);
+    Ok(Some(HttpProxyRequest {
+        dst,
+        connect : false,
+        pending,
+        framing : Some(framing)
+    }))
+}
+
+/// Parses `host:port`, `[ipv6]:port` or `host` with a default port
+pub fn parse_authority(authority : &str, default_port : u16) -> std::io::Result<Destination> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid authority: {authority}"));
+    let authority = match authority.rfind('@') {
+        Some(pos) => &authority[pos + 1..],
+        None => authority
+    };
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let end = rest.find(']').ok_or_else(invalid)?;
+        let port = match rest[end + 1..].strip_prefix(':') {
+            Some(v) => v.parse().map_err(|_| invalid())?,
+            None => default_port
+        };
+        (&rest[..end], port)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, default_port)
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid())
+    }
+    Ok(match host.parse::<IpAddr>() {
+        Ok(ip) => Destination::Addr(SocketAddr::new(ip, port)),
+        Err(_) => Destination::Domain(host.to_lowercase(), port)
+    })
+}
+
+#[test]
+fn should_rewrite_absolute_uri() {
+    let req = b"GET http://Example.com:8080/index.html?a=1 HTTP/1.1\r\nHost: example.com:8080\r\nProxy-Connection: keep-alive\r\n\r\n";
+    let req = parse_request(req).unwrap().unwrap();
+    assert!(!req.connect);
+    assert_eq!(Destination::Domain("example.com".into(), 8080), req.dst);
+    assert_eq!(&b"GET /index.html?a=1 HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n"[..], &req.pending[..]);
+
+    let req = parse_request(b"CONNECT [::1]:8443 HTTP/1.1\r\nHost: [::1]:8443\r\n\r\n\x16\x03").unwrap().unwrap();
+    assert!(req.connect);
+    assert_eq!(Destination::Addr("[::1]:8443".parse().unwrap()), req.dst);
+    assert_eq!(&b"\x16\x03"[..], &req.pending[..]);
+}
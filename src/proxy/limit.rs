@@ -0,0 +1,259 @@
+use std::{collections::HashMap, net::IpAddr, sync::{Arc, Mutex}, time::Instant};
+
+use super::conn::dst::Destination;
+
+/// Buckets kept before the full ones, whose sources are not limited at the moment, are forgotten
+const MAX_IDLE_BUCKETS : usize = 4096;
+
+/// Limits of the connections accepted by the proxy. None means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnLimits {
+    /// Concurrent connections
+    pub global : Option<usize>,
+    /// Concurrent connections from the same client IP
+    pub per_source : Option<usize>,
+    /// Concurrent connections to the same destination
+    pub per_destination : Option<usize>,
+    /// New connections per second
+    pub rate : Option<f64>,
+    /// New connections per second from the same client IP
+    pub rate_per_source : Option<f64>,
+}
+
+/// Why a connection was not admitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Global,
+    Source,
+    Destination,
+    Rate,
+    SourceRate,
+}
+
+/// Tracks the open connections and the connection rate. Each admitted connection holds a `ConnPermit` that
+/// releases its slot when dropped.
+pub struct ConnLimiter {
+    limits : ConnLimits,
+    state : Mutex<LimiterState>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    global : usize,
+    sources : HashMap<IpAddr, usize>,
+    destinations : HashMap<Destination, usize>,
+    bucket : Option<TokenBucket>,
+    source_buckets : HashMap<IpAddr, TokenBucket>,
+}
+
+/// Token bucket refilled at `rate` tokens per second, holding up to one second of tokens
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate : f64,
+    capacity : f64,
+    tokens : f64,
+    last : Instant,
+}
+
+enum PermitKey {
+    Source(IpAddr),
+    Destination(Destination),
+}
+
+/// Slot of an admitted connection
+pub struct ConnPermit {
+    limiter : Arc<ConnLimiter>,
+    key : PermitKey,
+}
+
+impl ConnLimiter {
+    pub fn new(limits : ConnLimits) -> Arc<Self> {
+        let state = LimiterState {
+            bucket : limits.rate.map(TokenBucket::new),
+            ..Default::default()
+        };
+        Arc::new(Self {
+            limits,
+            state : Mutex::new(state)
+        })
+    }
+
+    /// Admits a new connection from a client, counting it in the global and per source limits
+    pub fn acquire_source(self : &Arc<Self>, ip : IpAddr) -> Result<ConnPermit, LimitExceeded> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if self.limits.global.is_some_and(|max| state.global >= max) {
+            return Err(LimitExceeded::Global)
+        }
+        let current = state.sources.get(&ip).copied().unwrap_or(0);
+        if self.limits.per_source.is_some_and(|max| current >= max) {
+            return Err(LimitExceeded::Source)
+        }
+        if let Some(rate) = self.limits.rate_per_source {
+            if state.source_buckets.len() >= MAX_IDLE_BUCKETS {
+                state.source_buckets.retain(|_, bucket| !bucket.is_full());
+            }
+            let bucket = state.source_buckets.entry(ip).or_insert_with(|| TokenBucket::new(rate));
+            if !bucket.has_token() {
+                return Err(LimitExceeded::SourceRate)
+            }
+        }
+        if state.bucket.as_mut().is_some_and(|bucket| !bucket.has_token()) {
+            return Err(LimitExceeded::Rate)
+        }
+        // Only charged once every limit admits the connection
+        if let Some(bucket) = state.source_buckets.get_mut(&ip) {
+            bucket.take();
+        }
+        if let Some(bucket) = state.bucket.as_mut() {
+            bucket.take();
+        }
+        state.global += 1;
+        *state.sources.entry(ip).or_insert(0) += 1;
+        Ok(ConnPermit {
+            limiter : self.clone(),
+            key : PermitKey::Source(ip)
+        })
+    }
+
+    /// Admits a connection to a destination
+    pub fn acquire_destination(self : &Arc<Self>, dst : &Destination) -> Result<ConnPermit, LimitExceeded> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let current = state.destinations.get(dst).copied().unwrap_or(0);
+        if self.limits.per_destination.is_some_and(|max| current >= max) {
+            return Err(LimitExceeded::Destination)
+        }
+        *state.destinations.entry(dst.clone()).or_insert(0) += 1;
+        Ok(ConnPermit {
+            limiter : self.clone(),
+            key : PermitKey::Destination(dst.clone())
+        })
+    }
+
+    /// Connections currently admitted
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).global
+    }
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap_or_else(|e| e.into_inner());
+        match &self.key {
+            PermitKey::Source(ip) => {
+                state.global = state.global.saturating_sub(1);
+                release(&mut state.sources, ip);
+            },
+            PermitKey::Destination(dst) => release(&mut state.destinations, dst),
+        }
+    }
+}
+
+fn release<K : std::hash::Hash + Eq>(map : &mut HashMap<K, usize>, key : &K) {
+    if let Some(v) = map.get_mut(key) {
+        *v -= 1;
+        if *v == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+impl TokenBucket {
+    pub fn new(rate : f64) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens : capacity,
+            last : Instant::now()
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+impl LimitExceeded {
+    /// Label used in the metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LimitExceeded::Global => "global",
+            LimitExceeded::Source => "source",
+            LimitExceeded::Destination => "destination",
+            LimitExceeded::Rate => "rate",
+            LimitExceeded::SourceRate => "source_rate",
+        }
+    }
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LimitExceeded::Global => "too many connections",
+            LimitExceeded::Source => "too many connections from the client",
+            LimitExceeded::Destination => "too many connections to the destination",
+            LimitExceeded::Rate => "connection rate exceeded",
+            LimitExceeded::SourceRate => "connection rate of the client exceeded",
+        })
+    }
+}
+
+impl From<LimitExceeded> for std::io::Error {
+    fn from(value : LimitExceeded) -> Self {
+        std::io::Error::new(std::io::ErrorKind::QuotaExceeded, value.to_string())
+    }
+}
+
+#[test]
+fn should_release_slots_and_limit_rate() {
+    let ip : IpAddr = [10, 0, 0, 1].into();
+    let limiter = ConnLimiter::new(ConnLimits {
+        per_source : Some(2),
+        per_destination : Some(1),
+        rate_per_source : Some(3.0),
+        ..Default::default()
+    });
+    let first = limiter.acquire_source(ip).unwrap();
+    let _second = limiter.acquire_source(ip).unwrap();
+    assert!(matches!(limiter.acquire_source(ip), Err(LimitExceeded::Source)));
+    drop(first);
+    assert_eq!(1, limiter.active());
+    // Last token of the bucket
+    let third = limiter.acquire_source(ip).unwrap();
+    drop(third);
+    assert!(matches!(limiter.acquire_source(ip), Err(LimitExceeded::SourceRate)));
+    assert!(limiter.acquire_source([10, 0, 0, 2].into()).is_ok());
+
+    let dst = Destination::Domain("example.com".into(), 443);
+    let permit = limiter.acquire_destination(&dst).unwrap();
+    assert!(matches!(limiter.acquire_destination(&dst), Err(LimitExceeded::Destination)));
+    drop(permit);
+    assert!(limiter.acquire_destination(&dst).is_ok());
+
+    // A connection rejected by the global rate does not spend the token of its client
+    let limiter = ConnLimiter::new(ConnLimits {
+        rate : Some(1.0),
+        rate_per_source : Some(1.0),
+        ..Default::default()
+    });
+    assert!(limiter.acquire_source(ip).is_ok());
+    let other : IpAddr = [10, 0, 0, 2].into();
+    assert!(matches!(limiter.acquire_source(other), Err(LimitExceeded::Rate)));
+    assert!(limiter.state.lock().unwrap().source_buckets[&other].tokens >= 1.0);
+}
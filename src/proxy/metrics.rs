@@ -0,0 +1,157 @@
+use std::{io::ErrorKind, sync::LazyLock, time::Duration};
+
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use super::scap::common::ScapProtocol;
+
+static METRICS : LazyLock<ProxyMetrics> = LazyLock::new(ProxyMetrics::new);
+
+/// Prometheus metrics of the proxy, published in `/metrics`
+pub struct ProxyMetrics {
+    registry : Registry,
+    /// Connections handed to the workers, by listener mode
+    pub accepted : IntCounterVec,
+    /// Connections reset by the listeners, by limit
+    pub rejected : IntCounterVec,
+    /// Connections that failed before relaying any data, by error
+    pub failed : IntCounterVec,
+    pub tls_intercepted : IntCounter,
+    /// TLS connections relayed untouched, by the reason they were not intercepted
+    pub tls_passthrough : IntCounterVec,
+    /// Failed TLS handshakes with the client or the server, by alert or error
+    pub handshake_failures : IntCounterVec,
+    pub certificates_generated : IntCounter,
+    pub certificate_cache_hits : IntCounter,
+    /// Bytes relayed, by direction and protocol
+    pub bytes : IntCounterVec,
+    /// Replies of the upstream SOCKS5 server, by code
+    pub socks5_replies : IntCounterVec,
+    /// Capture events lost because the store was not running
+    pub scap_dropped : IntCounter,
+    /// Capture events that had to wait because the queue was full
+    pub scap_full : IntCounter,
+    pub connection_duration : HistogramVec,
+    /// From the ClientHello to the end of the handshake with the client of an intercepted connection
+    pub handshake_duration : Histogram,
+    pub active : IntGauge,
+    pub scap_queued : IntGauge,
+}
+
+/// Byte counters of the protocol of a connection, resolved once per connection
+#[derive(Clone)]
+pub struct ByteCounters {
+    pub sent : IntCounter,
+    pub received : IntCounter,
+}
+
+pub fn metrics() -> &'static ProxyMetrics {
+    &METRICS
+}
+
+impl ProxyMetrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("oxiproxy".into()), None).expect("Valid prefix");
+        let counter = |name : &str, help : &str| {
+            let v = IntCounter::new(name, help).expect("Valid metric");
+            registry.register(Box::new(v.clone())).expect("Unique metric");
+            v
+        };
+        let counter_vec = |name : &str, help : &str, labels : &[&str]| {
+            let v = IntCounterVec::new(Opts::new(name, help), labels).expect("Valid metric");
+            registry.register(Box::new(v.clone())).expect("Unique metric");
+            v
+        };
+        let gauge = |name : &str, help : &str| {
+            let v = IntGauge::new(name, help).expect("Valid metric");
+            registry.register(Box::new(v.clone())).expect("Unique metric");
+            v
+        };
+        let connection_duration = HistogramVec::new(
+            HistogramOpts::new("connection_duration_seconds", "Duration of the proxied connections").buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0]),
+            &["protocol"]
+        ).expect("Valid metric");
+        registry.register(Box::new(connection_duration.clone())).expect("Unique metric");
+        let handshake_duration = Histogram::with_opts(
+            HistogramOpts::new("tls_handshake_duration_seconds", "Duration of the TLS handshakes of intercepted connections").buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])
+        ).expect("Valid metric");
+        registry.register(Box::new(handshake_duration.clone())).expect("Unique metric");
+        Self {
+            accepted : counter_vec("connections_accepted_total", "Connections handed to the workers", &["mode"]),
+            rejected : counter_vec("connections_rejected_total", "Connections reset by the listeners", &["reason"]),
+            failed : counter_vec("connections_failed_total", "Connections that failed before relaying any data", &["reason"]),
+            tls_intercepted : counter("tls_intercepted_total", "TLS connections intercepted"),
+            tls_passthrough : counter_vec("tls_passthrough_total", "TLS connections relayed untouched", &["reason"]),
+            handshake_failures : counter_vec("tls_handshake_failures_total", "Failed TLS handshakes", &["side", "alert"]),
+            certificates_generated : counter("certificates_generated_total", "Certificate chains cloned"),
+            certificate_cache_hits : counter("certificate_cache_hits_total", "Connections served with an already cloned chain"),
+            bytes : counter_vec("bytes_total", "Bytes relayed. Intercepted TLS counts the decrypted bytes", &["direction", "protocol"]),
+            socks5_replies : counter_vec("socks5_upstream_replies_total", "Replies of the upstream SOCKS5 server", &["code"]),
+            scap_dropped : counter("scap_events_dropped_total", "Capture events lost"),
+            scap_full : counter("scap_queue_full_total", "Capture events that waited for room in the queue"),
+            active : gauge("connections_active", "Connections being served"),
+            scap_queued : gauge("scap_queue_depth", "Capture events waiting to be stored"),
+            connection_duration,
+            handshake_duration,
+            registry,
+        }
+    }
+
+    pub fn byte_counters(&self, protocol : ScapProtocol) -> ByteCounters {
+        ByteCounters {
+            sent : self.bytes.with_label_values(&["sent", protocol.name()]),
+            received : self.bytes.with_label_values(&["received", protocol.name()]),
+        }
+    }
+
+    pub fn observe_connection(&self, protocol : ScapProtocol, duration : Duration) {
+        self.connection_duration.with_label_values(&[protocol.name()]).observe(duration.as_secs_f64());
+    }
+
+    /// Text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::with_capacity(8192);
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Cannot encode the metrics: {e}");
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+/// Label of an error in the failure metrics
+pub fn error_reason(err : &std::io::Error) -> &'static str {
+    match err.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => "timeout",
+        ErrorKind::ConnectionRefused => "refused",
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => "reset",
+        ErrorKind::UnexpectedEof => "eof",
+        ErrorKind::InvalidData | ErrorKind::InvalidInput => "invalid",
+        ErrorKind::QuotaExceeded => "limit",
+        ErrorKind::NotConnected | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => "unreachable",
+        ErrorKind::NotFound => "resolve",
+        ErrorKind::Unsupported => "unsupported",
+        _ => "other"
+    }
+}
+
+/// Label of a failed TLS handshake: the alert, the TLS error or the socket error
+pub fn tls_failure(err : &std::io::Error) -> String {
+    match err.get_ref().and_then(|v| v.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::AlertReceived(alert)) => format!("{alert:?}"),
+        Some(rustls::Error::InvalidCertificate(_)) => "BadCertificate".into(),
+        // Name of the variant without its fields
+        Some(other) => format!("{other:?}").split(['(', ' ', '{']).next().unwrap_or_default().to_string(),
+        None => error_reason(err).into()
+    }
+}
+
+#[test]
+fn should_label_tls_failures() {
+    let alert = std::io::Error::new(ErrorKind::InvalidData, rustls::Error::AlertReceived(rustls::AlertDescription::UnknownCA));
+    assert_eq!("UnknownCA", tls_failure(&alert));
+    let other = std::io::Error::new(ErrorKind::InvalidData, rustls::Error::NoApplicationProtocol);
+    assert_eq!("NoApplicationProtocol", tls_failure(&other));
+    assert_eq!("timeout", tls_failure(&std::io::Error::from(ErrorKind::WouldBlock)));
+    // The other tests pass through pinned domains and connections without SNI
+    metrics().tls_passthrough.with_label_values(&["invalid_hello"]).inc();
+    assert!(metrics().render().contains("oxiproxy_tls_passthrough_total{reason=\"invalid_hello\"} 1"));
+}
@@ -1,81 +1,289 @@
-use std::{collections::BTreeSet, net::{TcpListener, TcpStream}, sync::{Arc, Mutex}};
+use std::{collections::BTreeSet, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{Arc, Mutex}};
 
-use conn::common::ProxyConnectionManager;
+use admin::AdminApi;
+use conn::{common::{ConnTimeouts, ProxyConnectionManager}, dst::Destination, registry::ConnRegistry, stream::{original_dst, reset_connection}, egress::{connector_for, UpstreamConnector}, route::{ReloadableConnector, Route, RoutedConnector}, tproxy::{self, TproxyUdpListener}};
 use crossbeam_channel::bounded;
+use socks5::common::Socks5Credentials;
 use scap::{spawn_scap_store, common::{ScapStore, ScapStoreRef}};
-use tls::store::TlsCertStore;
+use limit::{ConnLimiter, ConnPermit};
+use metrics::metrics;
+use reload::ProxyReloader;
+use shutdown::{ProxyShutdown, ShutdownState};
+use signal::{spawn_signal_handler, SignalSet, SIGHUP, SIGINT, SIGTERM};
+use tls::{operator::OperatorCa, store::TlsCertStore};
 
-use crate::{pool::{ProxyThreadPool, Runner, WorkGen}, ProxyArguments};
+use crate::{config::{ListenerConfig, ProxyConfig}, pool::{ProxyThreadPool, Runner, WorkGen}};
 
 pub mod conn;
 pub mod tls;
 pub mod scap;
 pub mod socks5;
+pub mod http;
+pub mod limit;
+pub mod admin;
+pub mod metrics;
+pub mod reload;
+pub mod shutdown;
+pub mod signal;
 
+/// How the clients reach the proxy and how the original destination is obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenMode {
+    /// Connections redirected by iptables. The destination is obtained with SO_ORIGINAL_DST
+    Redirect,
+    /// Connections and datagrams intercepted by a TPROXY rule. The destination is the local address of the socket
+    Tproxy,
+    /// Explicit SOCKS5 proxy. The destination is taken from the CONNECT request
+    Socks5,
+    /// Explicit HTTP proxy. Accepts CONNECT tunnels and absolute-URI requests
+    Http,
+}
+
+impl ListenMode {
+    /// Lowercase name used in the metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            ListenMode::Redirect => "redirect",
+            ListenMode::Tproxy => "tproxy",
+            ListenMode::Socks5 => "socks5",
+            ListenMode::Http => "http",
+        }
+    }
+}
+
+pub fn start_proxy(config : ProxyConfig) -> std::io::Result<()> {
+    let egress = Arc::new(ReloadableConnector::new(egress_of(&config)?));
+    log::info!("Egress: {:?}", config.egress.mode());
+    run_proxy(config, egress.clone(), Some(egress))
+}
+
+/// Builds the default egress and the one of each route
+fn egress_of(config : &ProxyConfig) -> std::io::Result<Arc<dyn UpstreamConnector>> {
+    let egress = &config.egress;
+    let socks5_credentials = egress.socks5_user.as_ref().map(|user| Socks5Credentials::new(user.clone(), egress.socks5_password.clone().unwrap_or_default()));
+    let timeout = config.timeouts().connect;
+    let default = connector_for(egress.mode(), egress.socks5_server.as_ref(), socks5_credentials.clone(), egress.http_proxy.as_ref(), timeout)?;
+    if config.routes.is_empty() {
+        return Ok(default)
+    }
+    let mut routes = Vec::with_capacity(config.routes.len());
+    for route in &config.routes {
+        let socks5 = route.socks5_server.as_ref().or(egress.socks5_server.as_ref());
+        let http = route.http_proxy.as_ref().or(egress.http_proxy.as_ref());
+        routes.push(Route {
+            patterns : route.patterns(),
+            egress : connector_for(route.egress, socks5, socks5_credentials.clone(), http, timeout)?
+        });
+    }
+    Ok(Arc::new(RoutedConnector { routes, default }))
+}
+
+/// Starts the proxy using a custom connector to reach the destinations. The routes of the configuration are
+/// ignored, also when reloading it.
+pub fn start_proxy_with_connector(config : ProxyConfig, egress : Arc<dyn UpstreamConnector>) -> std::io::Result<()> {
+    run_proxy(config, egress, None)
+}
 
-pub fn start_proxy(args : ProxyArguments) -> std::io::Result<()> {
-    let listener = TcpListener::bind(format!("{}:{}", args.addr, args.port))?;
-    log::info!("Sever listening on {}:{}", args.addr, args.port);
-    let pinned = pinned_domains(&args.pinned_domain);
-    let tls = TlsCertStore::new(&args.root_ca, pinned)?;
+fn run_proxy(config : ProxyConfig, egress : Arc<dyn UpstreamConnector>, reloadable : Option<Arc<ReloadableConnector>>) -> std::io::Result<()> {
+    // Before spawning any thread, so the signals only reach the one waiting for them
+    let signals = SignalSet::new(&[SIGHUP, SIGINT, SIGTERM])?;
+    signals.block()?;
+    let mut listeners = Vec::with_capacity(config.listeners.len());
+    for listener in &config.listeners {
+        let addr = listen_addr(listener)?;
+        let socket = if listener.mode == ListenMode::Tproxy {
+            tproxy::tcp_listener(addr)?
+        } else {
+            TcpListener::bind(addr)?
+        };
+        log::info!("Sever listening on {}:{} ({:?} mode)", listener.addr, listener.port, listener.mode);
+        listeners.push((socket, listener.mode, addr));
+    }
+    let pinned = pinned_domains(&config.pinned_domains);
+    let tls = TlsCertStore::new(config.root_ca.as_deref(), pinned, config.cert_cache.as_deref())?;
+    tls.set_operator(config.operator_ca.as_deref().map(OperatorCa::from_file).transpose()?);
+    tls.set_modes(config.certificates.modes());
     let (scap_sender, scap_receiver) = bounded(1024);
-    let scap = ScapStore::new(scap_sender);
-    spawn_scap_store(scap_receiver, args.trace_folder.as_ref());
-    let (th_sender, th_receiver) = bounded(1024);
-    let proxy_worker = ProxyWorkerSpawner::neew(scap.reference(), tls, args.socks5_server.clone());
-    let mut th_pool = ProxyThreadPool::new(args.workers, th_receiver, proxy_worker);
+    let scap = ScapStore::with_filter(scap_sender, config.capture.filter()?);
+    let store = spawn_scap_store(scap_receiver, config.trace_folder.as_ref())?;
+    for (_, mode, addr) in &listeners {
+        if *mode != ListenMode::Tproxy {
+            continue
+        }
+        let mut udp = TproxyUdpListener::new(tproxy::udp_listener(*addr)?, egress.clone(), scap.reference())?;
+        std::thread::Builder::new().name("TproxyUdp".into()).spawn(move || {
+            if let Err(e) = udp.run() {
+                log::error!("TPROXY UDP listener stopped: {e}");
+            }
+        })?;
+    }
+    let limiter = ConnLimiter::new(config.limits());
+    let registry = ConnRegistry::new();
+    if let Some(addr) = &config.admin {
+        AdminApi::new(registry.clone(), tls.clone(), scap.clone()).spawn(addr)?;
+    }
+    if let Some(addr) = &config.metrics {
+        AdminApi::new(registry.clone(), tls.clone(), scap.clone()).metrics_only().spawn(addr)?;
+    }
+    let state = ShutdownState::new();
+    let proxy_worker = ProxyWorkerSpawner::neew(scap.reference(), tls.clone(), egress, config.timeouts(), limiter.clone(), registry.clone(), state.clone());
+    let mut th_pool = ProxyThreadPool::new(config.workers.min, config.workers.max, config.workers.queue_high_water, proxy_worker);
     th_pool.init()?;
+    let th_pool = Arc::new(th_pool);
+    let mut shutdown = ProxyShutdown {
+        state : state.clone(),
+        listeners : Vec::with_capacity(listeners.len()),
+        limiter : limiter.clone(),
+        registry,
+        scap : scap.clone(),
+        drain : config.shutdown_timeout()
+    };
+    let (shutdown_sender, shutdown_receiver) = bounded(2);
+    spawn_signal_handler(signals, ProxyReloader::new(config, tls, scap, reloadable), shutdown_sender)?;
+    let mut handles = Vec::with_capacity(listeners.len());
+    for (listener, mode, addr) in listeners {
+        shutdown.listeners.push(listener.try_clone()?);
+        let th_pool = th_pool.clone();
+        let limiter = limiter.clone();
+        let state = state.clone();
+        handles.push(std::thread::Builder::new().name(format!("Listener{}", addr.port())).spawn(move || {
+            accept_loop(listener, mode, &th_pool, &limiter, &state)
+        })?);
+    }
+    shutdown.run(shutdown_receiver, handles, store);
+    Ok(())
+}
+
+/// Accepts the connections of a listener and hands them to the pool
+fn accept_loop(listener : TcpListener, mode : ListenMode, th_pool : &ProxyThreadPool<IncomingConn, ProxyWorkerSpawner>, limiter : &Arc<ConnLimiter>, state : &ShutdownState) {
     for stream in listener.incoming() {
+        if state.is_stopping() {
+            if let Ok(stream) = stream {
+                reset_connection(stream);
+            }
+            break
+        }
         match stream {
             Ok(stream) => {
-                let _ = th_sender.send(stream);
+                let peer = match stream.peer_addr() {
+                    Ok(v) => v,
+                    Err(_) => continue
+                };
+                let permit = match limiter.acquire_source(peer.ip()) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::warn!("Rejecting connection from {}: {}", peer, e);
+                        metrics().rejected.with_label_values(&[e.reason()]).inc();
+                        reset_connection(stream);
+                        continue
+                    }
+                };
+                // The destination of the transparent modes is known now, the explicit ones wait for the request
+                let dst_permit = match accepted_destination(&stream, mode) {
+                    Some(dst) => match limiter.acquire_destination(&dst) {
+                        Ok(v) => Some(v),
+                        Err(e) => {
+                            log::warn!("Rejecting connection from {} to {}: {}", peer, dst, e);
+                            metrics().rejected.with_label_values(&[e.reason()]).inc();
+                            reset_connection(stream);
+                            continue
+                        }
+                    },
+                    None => None
+                };
+                if let Err(conn) = th_pool.submit(IncomingConn { stream, mode, permit, dst_permit }) {
+                    log::warn!("Rejecting connection from {}: {} connections waiting for a worker", peer, th_pool.queued());
+                    metrics().rejected.with_label_values(&["queue_full"]).inc();
+                    reset_connection(conn.stream);
+                    continue
+                }
+                metrics().accepted.with_label_values(&[mode.name()]).inc();
             }
             Err(e) => {
                 log::error!("Cannot accept new connection: {}", e);
             }
         }
     }
-    
-    Ok(())
 }
 
+/// Original destination of a transparent connection
+fn accepted_destination(stream : &TcpStream, mode : ListenMode) -> Option<Destination> {
+    let addr = match mode {
+        ListenMode::Redirect => original_dst(stream),
+        ListenMode::Tproxy => stream.local_addr(),
+        ListenMode::Socks5 | ListenMode::Http => return None
+    };
+    addr.inspect_err(|e| log::debug!("Cannot obtain the original destination: {e}")).ok().map(Destination::from)
+}
+
+/// Connection accepted by a listener, waiting for a worker
+pub struct IncomingConn {
+    pub stream : TcpStream,
+    pub mode : ListenMode,
+    /// Released once the connection is finished
+    pub permit : ConnPermit,
+    /// Slot of the destination, taken by the listener in the transparent modes
+    pub dst_permit : Option<ConnPermit>,
+}
 
 pub struct ProxyWorkerSpawner {
     scap : ScapStoreRef,
     tls : TlsCertStore,
-    socks5 : String
+    egress : Arc<dyn UpstreamConnector>,
+    timeouts : ConnTimeouts,
+    limiter : Arc<ConnLimiter>,
+    registry : Arc<ConnRegistry>,
+    shutdown : Arc<ShutdownState>
 }
 pub struct ProxyWorker {
-    proxy : ProxyConnectionManager
+    proxy : ProxyConnectionManager,
+    shutdown : Arc<ShutdownState>
 }
 
 impl ProxyWorkerSpawner {
-    pub fn neew(scap : ScapStoreRef, tls : TlsCertStore, socks5 : String) -> Self {
+    pub fn neew(scap : ScapStoreRef, tls : TlsCertStore, egress : Arc<dyn UpstreamConnector>, timeouts : ConnTimeouts, limiter : Arc<ConnLimiter>, registry : Arc<ConnRegistry>, shutdown : Arc<ShutdownState>) -> Self {
         Self {
             scap,
             tls,
-            socks5
+            egress,
+            timeouts,
+            limiter,
+            registry,
+            shutdown
         }
     }
 }
 
-impl WorkGen<TcpStream> for ProxyWorkerSpawner {
-    fn gen(&self) -> impl Runner<TcpStream> + Send + 'static {
+impl WorkGen<IncomingConn> for ProxyWorkerSpawner {
+    fn gen(&self) -> impl Runner<IncomingConn> + Send + 'static {
         ProxyWorker {
-            proxy : ProxyConnectionManager::new(self.scap.clone(), self.tls.clone(), self.socks5.clone())
+            proxy : ProxyConnectionManager::new(self.scap.clone(), self.tls.clone(), self.egress.clone(), self.timeouts, self.limiter.clone(), self.registry.clone()),
+            shutdown : self.shutdown.clone()
         }
     }
 }
 
-impl Runner<TcpStream> for ProxyWorker {
-    fn run(&mut self, v : TcpStream) {
-        if let Err(e) = runneer_wrapper(&mut self.proxy, v) {
+impl Runner<IncomingConn> for ProxyWorker {
+    fn run(&mut self, v : IncomingConn) {
+        if self.shutdown.is_closing() {
+            // Queued while stopping: there is no time left to serve it
+            reset_connection(v.stream);
+            return
+        }
+        if let Err(e) = runneer_wrapper(&mut self.proxy, v.stream, v.mode, v.dst_permit) {
             log::error!("Error in runner execution: {e}");
         }
     }
 }
 
 
+fn listen_addr(listener : &ListenerConfig) -> std::io::Result<SocketAddr> {
+    (listener.addr.as_str(), listener.port).to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid listen address: {}", listener.addr))
+    })
+}
+
 fn pinned_domains(list : &Vec<String>) -> Arc<Mutex<BTreeSet<String>>> {
     let mut set = BTreeSet::new();
     for v in list {
@@ -84,7 +292,7 @@ fn pinned_domains(list : &Vec<String>) -> Arc<Mutex<BTreeSet<String>>> {
     Arc::new(Mutex::new(set))
 }
 
-fn runneer_wrapper(proxy : &mut ProxyConnectionManager, stream : TcpStream) -> std::io::Result<()> {
+fn runneer_wrapper(proxy : &mut ProxyConnectionManager, stream : TcpStream, mode : ListenMode, dst_permit : Option<ConnPermit>) -> std::io::Result<()> {
     log::debug!("Received connection {:?}", stream.peer_addr()?);
-    proxy.handle_client(stream)
+    proxy.handle_client(stream, mode, dst_permit)
 }
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use crate::config::ProxyConfig;
+
+use super::{conn::route::ReloadableConnector, egress_of, scap::common::ScapStore, tls::{operator::OperatorCa, store::TlsCertStore}};
+
+/// Parts of the running proxy replaced when the configuration is reloaded: the pinned domains, the capture filter,
+/// the routes, the certificate modes and the ROOT and operator CAs. The open connections are not affected.
+pub struct ProxyReloader {
+    config : ProxyConfig,
+    tls : TlsCertStore,
+    scap : ScapStore,
+    /// Only present when the egress is built from the configuration
+    egress : Option<Arc<ReloadableConnector>>,
+}
+
+impl ProxyReloader {
+    pub fn new(config : ProxyConfig, tls : TlsCertStore, scap : ScapStore, egress : Option<Arc<ReloadableConnector>>) -> Self {
+        Self {
+            config,
+            tls,
+            scap,
+            egress
+        }
+    }
+
+    /// Loads the configuration again and applies it. Nothing is replaced if any part of it is invalid.
+    pub fn reload(&mut self) -> std::io::Result<()> {
+        let config = self.config.reload()?;
+        let filter = config.capture.filter()?;
+        let egress = match &self.egress {
+            Some(_) => Some(egress_of(&config)?),
+            None => None
+        };
+        let operator = config.operator_ca.as_deref().map(OperatorCa::from_file).transpose()?;
+        self.tls.reload_ca(config.root_ca.as_deref())?;
+        self.tls.set_operator(operator);
+        self.tls.set_modes(config.certificates.modes());
+        self.tls.replace_pinned(&config.pinned_domains);
+        self.scap.set_filter(filter);
+        if let (Some(current), Some(egress)) = (&self.egress, egress) {
+            current.replace(egress);
+        }
+        let restart = self.config.restart_required(&config);
+        if !restart.is_empty() {
+            log::warn!("Changes to {} are applied after a restart", restart.join(", "));
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+#[test]
+fn should_keep_the_state_and_the_learned_pins() {
+    use std::{collections::BTreeSet, sync::Mutex};
+    use clap::Parser;
+    use crate::{proxy::scap::common::ScapProtocol, ProxyArguments};
+    let dir = std::env::temp_dir().join(format!("oxiproxy-reload-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("ca")).unwrap();
+    let path = dir.join("proxy.toml");
+    std::fs::write(&path, "pinned_domains = [\"a.example\", \"b.example\"]\n[capture]\nprotocols = [\"http\"]\n").unwrap();
+    let args = ProxyArguments::parse_from(["oxiproxy", "--port", "8080", "--addr", "127.0.0.1", "--root-ca", &dir.join("ca").to_string_lossy(), "--config", &path.to_string_lossy()]);
+    let config = ProxyConfig::load(&args).unwrap();
+    let pinned = config.pinned_domains.iter().cloned().collect::<BTreeSet<String>>();
+    let tls = TlsCertStore::new(None, Arc::new(Mutex::new(pinned)), None).unwrap();
+    let (sender, _receiver) = crossbeam_channel::unbounded();
+    let scap = ScapStore::new(sender);
+    scap.set_filter(config.capture.filter().unwrap());
+    let mut reloader = ProxyReloader::new(config, tls.clone(), scap.clone(), None);
+    // Learned from the connections, one of them also in the configuration
+    tls.disable_addr("b.example".to_string());
+    tls.disable_addr("learned.example".to_string());
+
+    std::fs::write(&path, "pinned_domains = \"c.example\"\n[capture]\nprotocols = [\"tls\"]\n").unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(vec!["a.example", "b.example", "learned.example"], tls.pinned_list());
+    assert_eq!(vec![ScapProtocol::Http], scap.filter.read().unwrap().protocols);
+
+    std::fs::write(&path, "pinned_domains = [\"c.example\"]\n[capture]\nprotocols = [\"tls\"]\n").unwrap();
+    reloader.reload().unwrap();
+    assert_eq!(vec!["b.example", "c.example", "learned.example"], tls.pinned_list());
+    assert_eq!(vec![ScapProtocol::Tls], scap.filter.read().unwrap().protocols);
+    // The domains of the configuration are replaced again in the next reload
+    std::fs::write(&path, "pinned_domains = []\n").unwrap();
+    reloader.reload().unwrap();
+    assert_eq!(vec!["b.example", "learned.example"], tls.pinned_list());
+    let _ = std::fs::remove_dir_all(dir);
+}
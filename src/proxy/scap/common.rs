@@ -1,19 +1,25 @@
-use std::{hash::{Hash, Hasher}, io::Write, net::IpAddr, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{hash::{Hash, Hasher}, io::Write, net::IpAddr, sync::{atomic::{AtomicBool, Ordering}, Arc, RwLock}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Sender, TrySendError};
+
+use crate::proxy::metrics::metrics;
 use serde::Serialize;
 
 
 #[derive(Clone)]
 pub struct ScapStore {
     pub channel : Sender<ScapEvent>,
-    pub filter : Arc<ScapFilter>
+    /// Shared with every reference so it can be replaced while the proxy runs
+    pub filter : Arc<RwLock<Arc<ScapFilter>>>,
+    /// Captures can be paused at runtime. The connections already open keep capturing.
+    pub enabled : Arc<AtomicBool>
 }
 
 #[derive(Clone)]
 pub struct ScapStoreRef {
     pub channel : Sender<ScapEvent>,
-    pub filter : Arc<ScapFilter>
+    pub filter : Arc<RwLock<Arc<ScapFilter>>>,
+    pub enabled : Arc<AtomicBool>
 }
 
 #[derive(Debug, Clone)]
@@ -36,7 +42,19 @@ pub enum ScapEvent {
     Connect(ScapConnect),
     Receive(ScapData),
     Send(ScapData),
-    Close(ScapAddresses)
+    Timeout(u64, ScapTimeout),
+    Close(ScapAddresses),
+    /// Stores every open capture and stops the store
+    Shutdown
+}
+
+/// Timeout that ended a connection
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum ScapTimeout {
+    Connect,
+    Handshake,
+    Idle,
+    Lifetime
 }
 
 #[derive(Debug, Clone)]
@@ -59,7 +77,8 @@ pub struct ScapEntry {
     pub address : ScapAddresses,
     pub protocol : ScapProtocol,
     pub received : Vec<u8>,
-    pub send : Vec<u8>
+    pub send : Vec<u8>,
+    pub timeout : Option<ScapTimeout>
 }
 #[derive(Debug, Clone)]
 pub struct  ScapConnect {
@@ -88,7 +107,8 @@ pub enum ScapProtocol {
     Tcp,
     Tls,
     Udp,
-    Dns
+    Dns,
+    Ssh
 }
 
 #[derive(Debug, Clone)]
@@ -101,35 +121,60 @@ impl ScapStore {
     pub fn new(channel : Sender<ScapEvent>) -> Self {
         Self {
             channel,
-            filter : Arc::new(ScapFilter::default())
+            filter : Arc::new(RwLock::new(Arc::new(ScapFilter::default()))),
+            enabled : Arc::new(AtomicBool::new(true))
         }
     }
     pub fn with_filter(channel : Sender<ScapEvent>, filter : ScapFilter) -> Self {
         Self {
             channel,
-            filter : Arc::new(filter)
+            filter : Arc::new(RwLock::new(Arc::new(filter))),
+            enabled : Arc::new(AtomicBool::new(true))
         }
     }
     pub fn reference(&self) -> ScapStoreRef {
-        ScapStoreRef::new(self.channel.clone(), self.filter.clone())
+        ScapStoreRef::new(self.channel.clone(), self.filter.clone(), self.enabled.clone())
+    }
+    pub fn set_enabled(&self, enabled : bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+    /// Events waiting to be processed by the store
+    pub fn queued(&self) -> usize {
+        self.channel.len()
+    }
+    /// Asks the store to write the pending captures and stop. The events already queued are processed first.
+    pub fn shutdown(&self) {
+        send_event(&self.channel, ScapEvent::Shutdown);
+    }
+    /// Replaces the filter. Only the connections opened from now on use it.
+    pub fn set_filter(&self, filter : ScapFilter) {
+        *self.filter.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(filter);
     }
 }
 
 impl ScapStoreRef {
-    fn new(channel : Sender<ScapEvent>, filter : Arc<ScapFilter>) -> Self {
+    fn new(channel : Sender<ScapEvent>, filter : Arc<RwLock<Arc<ScapFilter>>>, enabled : Arc<AtomicBool>) -> Self {
         Self {
             channel,
-            filter
+            filter,
+            enabled
         }
     }
     pub fn sender(&self, protocol : ScapProtocol, remote : (IpAddr, u16), source : (IpAddr, u16)) -> ScapSender {
         let address = ScapAddresses::new(remote, source);
         let hash = address.get_hash();
-        let _ = self.channel.send(ScapEvent::Connect(ScapConnect {
-            address : address.clone(),
-            protocol
-        }));
-        let capture = self.filter.matches(&address);
+        let filter = self.filter.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let capture = self.enabled.load(Ordering::Acquire) && filter.matches(&address) && (filter.protocols.is_empty() || filter.protocols.contains(&protocol));
+        // Only the captured connections are closed, the store would keep the others forever
+        if capture {
+            send_event(&self.channel, ScapEvent::Connect(ScapConnect {
+                address : address.clone(),
+                protocol
+            }));
+        }
         ScapSender {
             address,
             hash,
@@ -139,6 +184,38 @@ impl ScapStoreRef {
     }
 }
 
+/// Queues an event for the store, waiting if the queue is full
+fn send_event(channel : &Sender<ScapEvent>, event : ScapEvent) {
+    let event = match channel.try_send(event) {
+        Ok(_) => return,
+        Err(TrySendError::Full(v)) => {
+            metrics().scap_full.inc();
+            v
+        },
+        Err(TrySendError::Disconnected(_)) => {
+            metrics().scap_dropped.inc();
+            return
+        }
+    };
+    if channel.send(event).is_err() {
+        metrics().scap_dropped.inc();
+    }
+}
+
+impl ScapProtocol {
+    /// Lowercase name used in the metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScapProtocol::Http => "http",
+            ScapProtocol::Tcp => "tcp",
+            ScapProtocol::Tls => "tls",
+            ScapProtocol::Udp => "udp",
+            ScapProtocol::Dns => "dns",
+            ScapProtocol::Ssh => "ssh",
+        }
+    }
+}
+
 impl ScapFilter {
     pub fn matches(&self, addr : &ScapAddresses) -> bool {
         for &(a, p) in &self.src_in {
@@ -158,7 +235,7 @@ impl ScapFilter {
         }
         for &(a, p) in &self.dst_ex {
             if addr.remote == a && (p == 0 || p == addr.rport) {
-                return true
+                return false
             }
         }
         true
@@ -187,7 +264,8 @@ impl ScapEntry {
             address,
             protocol,
             received : Vec::with_capacity(32_000),
-            send : Vec::with_capacity(32_000)
+            send : Vec::with_capacity(32_000),
+            timeout : None
         }
     }
 
@@ -201,20 +279,28 @@ impl ScapEntry {
 
 impl ScapSender {
 
-    pub fn from_server(&self) -> ScapSenderWrt {
+    pub fn from_server(&self) -> ScapSenderWrt<'_> {
         ScapSenderWrt {
             from_client : false,
-            sender : &self,
+            sender : self,
             capture : self.capture
         }
     }
-    pub fn from_client(&self) -> ScapSenderWrt {
+    pub fn from_client(&self) -> ScapSenderWrt<'_> {
         ScapSenderWrt {
             from_client : true,
-            sender : &self,
+            sender : self,
             capture : self.capture
         }
     }
+
+    /// Records the timeout that ended the connection
+    pub fn timeout(&self, timeout : ScapTimeout) {
+        if !self.capture {
+            return
+        }
+        send_event(&self.channel, ScapEvent::Timeout(self.hash, timeout));
+    }
 }
 
 impl Drop for ScapSender {
@@ -222,7 +308,7 @@ impl Drop for ScapSender {
         if !self.capture {
             return
         }
-        let _ = self.channel.send(ScapEvent::Close(self.address.clone()));
+        send_event(&self.channel, ScapEvent::Close(self.address.clone()));
     }
 }
 
@@ -242,11 +328,31 @@ impl<'a> Write for ScapSenderWrt<'a> {
         }else {
             ScapEvent::Receive(data)
         };
-        let _ = self.sender.channel.send(entry);
-        return Ok(buf.len())
+        send_event(&self.sender.channel, entry);
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn should_not_open_entries_for_excluded_connections() {
+    let (sender, receiver) = crossbeam_channel::bounded(16);
+    let store = ScapStore::with_filter(sender, ScapFilter { protocols : vec![ScapProtocol::Http], ..Default::default() });
+    let scap = store.reference();
+    let remote = ([10, 0, 0, 1].into(), 443);
+    let source = ([10, 0, 0, 2].into(), 40000);
+    let excluded = scap.sender(ScapProtocol::Tls, remote, source);
+    excluded.from_client().write_all(b"hello").unwrap();
+    drop(excluded);
+    store.set_enabled(false);
+    drop(scap.sender(ScapProtocol::Http, remote, source));
+    assert!(receiver.is_empty());
+
+    store.set_enabled(true);
+    drop(scap.sender(ScapProtocol::Http, remote, source));
+    assert!(matches!(receiver.try_recv(), Ok(ScapEvent::Connect(_))));
+    assert!(matches!(receiver.try_recv(), Ok(ScapEvent::Close(_))));
+}
@@ -1,7 +1,7 @@
 use httparse::Header;
 use serde::{ser::SerializeSeq, Serialize};
 
-use super::common::{ScapAddresses, ScapProtocol};
+use super::common::{ScapAddresses, ScapProtocol, ScapTimeout};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct FileMetadata <'a>{
@@ -9,7 +9,9 @@ pub struct FileMetadata <'a>{
     pub protocol : ScapProtocol,
     pub meta : ScapProtocolMeta<'a>,
     #[serde(skip_serializing_if="Option::is_none")]
-    pub error : Option<String>
+    pub error : Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub timeout : Option<ScapTimeout>
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -112,6 +114,7 @@ fn should_deserialize() {
         meta : ScapProtocolMeta::Http(meta),
         protocol : ScapProtocol::Http,
         error: None,
+        timeout: None,
     };
     let data = serde_json::to_string_pretty(&request).unwrap();
     println!("{}", data);
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, io::{ErrorKind, Write}, path::PathBuf};
+use std::{borrow::Cow, io::{ErrorKind, Write}, path::{Path, PathBuf}};
 
 use httparse::EMPTY_HEADER;
 
@@ -26,13 +26,14 @@ pub fn process_scap_entry(scap : ScapEntry, traces : Option<&PathBuf>) -> std::i
 
         }),
         protocol : scap.protocol,
-        error : None
+        error : None,
+        timeout : scap.timeout
     };
     
     match process_scap_entry_wrapper(scap, &dst_folder) {
         Ok(v) => Ok(v),
         Err(e) => {
-            let mut file_meta = std::fs::File::create(&dst_folder.join("request.json"))?;
+            let mut file_meta = std::fs::File::create(dst_folder.join("request.json"))?;
             meta.error = Some(e.to_string());
             serde_json::to_writer_pretty(&mut file_meta, &meta).expect("Cannot fail serialization");
             Ok(())
@@ -40,7 +41,7 @@ pub fn process_scap_entry(scap : ScapEntry, traces : Option<&PathBuf>) -> std::i
     }
 }
 
-pub fn process_scap_entry_wrapper(scap : ScapEntry, dst_folder : &PathBuf) -> std::io::Result<()> {
+pub fn process_scap_entry_wrapper(scap : ScapEntry, dst_folder : &Path) -> std::io::Result<()> {
     let mut req_headers = [EMPTY_HEADER; 64];
     let mut request = httparse::Request::new(&mut req_headers);
     let req_body_start = match request.parse(&scap.received) {
@@ -53,12 +54,12 @@ pub fn process_scap_entry_wrapper(scap : ScapEntry, dst_folder : &PathBuf) -> st
         Ok(httparse::Status::Complete(v)) => v,
         _ => return Err(std::io::Error::new(ErrorKind::BrokenPipe, "Cannot process HTTP response: not completed"))
     };
-    let mut file_meta = std::fs::File::create(&dst_folder.join("request.json"))?;
+    let mut file_meta = std::fs::File::create(dst_folder.join("request.json"))?;
     let meta = FileMetadata {
         address : scap.address,
         meta : ScapProtocolMeta::Http(ScapHttpMeta {
             request : ScapHttpReqMeta {
-                headers : HttpHeaders(&request.headers),
+                headers : HttpHeaders(request.headers),
                 method : request.method.unwrap_or("?"),
                 path : request.path.unwrap_or("?"),
                 version : request.version.unwrap_or(0),
@@ -67,7 +68,7 @@ pub fn process_scap_entry_wrapper(scap : ScapEntry, dst_folder : &PathBuf) -> st
             },
             response : ScapHttpResMeta {
                 code : response.code.unwrap_or(0),
-                headers : HttpHeaders(&response.headers),
+                headers : HttpHeaders(response.headers),
                 reason : response.reason.unwrap_or("?"),
                 version : response.version.unwrap_or(0),
                 body_size : (scap.send.len() - req_body_start) as u64,
@@ -75,17 +76,18 @@ pub fn process_scap_entry_wrapper(scap : ScapEntry, dst_folder : &PathBuf) -> st
             }
         }),
         protocol : scap.protocol,
-        error : None
+        error : None,
+        timeout : scap.timeout
     };
     serde_json::to_writer_pretty(&mut file_meta, &meta).expect("Cannot fail serialization");
     if !scap.received[req_body_start..].trim_ascii().is_empty() {
-        let mut req_file  = std::fs::File::create(&dst_folder.join("request.scap"))?;
+        let mut req_file  = std::fs::File::create(dst_folder.join("request.scap"))?;
         req_file.write_all(&scap.received[req_body_start..])?;
     }
 
     if !scap.send[body_start..].trim_ascii().is_empty() {
-        let mut res_file  = std::fs::File::create(&dst_folder.join("response.scap"))?;
-        let body = if body_is_chunked(&response.headers) {
+        let mut res_file  = std::fs::File::create(dst_folder.join("response.scap"))?;
+        let body = if body_is_chunked(response.headers) {
             log::debug!("chunked");
             Cow::Owned(parse_chunked_body(&scap.send[body_start..], false))
         }else {
@@ -113,63 +115,26 @@ fn body_is_chunked(headers : &[httparse::Header]) -> bool {
     false
 }
 
-fn clean_headers(headers : &mut [httparse::Header]) {
-    for header in headers {
-        if header.name.is_empty() {
-            break;
-        }
-        *header = EMPTY_HEADER;
-    }
-}
-
-fn parse_chunked_body_raw(buffer: &[u8]) -> Vec<u8> {
-    let mut body = Vec::with_capacity(buffer.len());
-    let mut remaining_buffer = &buffer[..];
-
-    loop {
-        if let Some((size, size_end)) = find_chunk_size(&remaining_buffer) {
-            if size == 0 {
-                break;
-            }
-            let chunk_start = size_end;
-            let chunk_end = chunk_start + size;
-            if chunk_end <= remaining_buffer.len() {
-                remaining_buffer[chunk_start..chunk_end].iter().for_each(|&c| body.push(c));
-                remaining_buffer = &remaining_buffer[chunk_end + 2..];
-            }else {
-                break
-            }
-        } else {
-            break;
-        }
-    }
-    body
-}
-
 fn parse_chunked_body(buffer: &[u8], utf8 : bool) -> String {
     let mut body = String::with_capacity(buffer.len());
-    let mut remaining_buffer = &buffer[..];
+    let mut remaining_buffer = buffer;
 
-    loop {
-        if let Some((size, size_end)) = find_chunk_size(&remaining_buffer) {
-            if size == 0 {
-                break;
-            }
-            let chunk_start = size_end;
-            let chunk_end = chunk_start + size;
-            if chunk_end <= remaining_buffer.len() {
-                if utf8 {
-                    body.push_str(&String::from_utf8_lossy(&remaining_buffer[chunk_start..chunk_end]));
-                }else {
-                    remaining_buffer[chunk_start..chunk_end].iter().map(|&c| c as char).for_each(|c| body.push(c));
-                }
-                
-                remaining_buffer = &remaining_buffer[chunk_end + 2..];
+    while let Some((size, size_end)) = find_chunk_size(remaining_buffer) {
+        if size == 0 {
+            break;
+        }
+        let chunk_start = size_end;
+        let chunk_end = chunk_start + size;
+        if chunk_end <= remaining_buffer.len() {
+            if utf8 {
+                body.push_str(&String::from_utf8_lossy(&remaining_buffer[chunk_start..chunk_end]));
             }else {
-                break
+                remaining_buffer[chunk_start..chunk_end].iter().map(|&c| c as char).for_each(|c| body.push(c));
             }
-        } else {
-            break;
+            
+            remaining_buffer = &remaining_buffer[chunk_end + 2..];
+        }else {
+            break
         }
     }
     body
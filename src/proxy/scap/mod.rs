@@ -1,30 +1,36 @@
-use std::{borrow::Cow, collections::BTreeMap, hash::{Hash, Hasher}, io::Write, net::IpAddr, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{collections::BTreeMap, thread::JoinHandle};
 
 use common::{ScapEntry, ScapEvent};
-use crossbeam_channel::{Receiver, Sender};
-use http::process_scap_entry;
-use httparse::EMPTY_HEADER;
+use crossbeam_channel::Receiver;
 
 pub mod file;
 pub mod common;
 pub mod http;
 pub mod tcp;
 
-pub fn spawn_scap_store(receiver : Receiver<ScapEvent>, traces : Option<&String>) {
-    let trace_location = traces.map(|v| std::path::PathBuf::from(v));
-    std::thread::spawn(move || {
+/// Stores the captures sent by the connections until a `Shutdown` event is received. The entries still open at
+/// that point are stored as if their connections had been closed. Only the captured connections have an entry,
+/// the ones excluded by the filter or opened while capturing was paused never reach the store.
+pub fn spawn_scap_store(receiver : Receiver<ScapEvent>, traces : Option<&String>) -> std::io::Result<JoinHandle<()>> {
+    let trace_location = traces.map(std::path::PathBuf::from);
+    std::thread::Builder::new().name("ScapStore".into()).spawn(move || {
         let mut store = BTreeMap::new();
         let mut old_initialized: Vec<ScapEntry> = Vec::new();
         loop {
-            let cmd = receiver.recv().unwrap();
+            let cmd = match receiver.recv() {
+                Ok(v) => v,
+                Err(_) => ScapEvent::Shutdown
+            };
             match cmd {
                 ScapEvent::Connect(connect) => {
                     let hash = connect.address.get_hash();
                     let entry = match old_initialized.pop() {
                         Some(mut v) => {
                             v.address = connect.address;
+                            v.protocol = connect.protocol;
                             v.received.clear();
                             v.send.clear();
+                            v.timeout = None;
                             v
                         },
                         None => ScapEntry::new(connect.address, connect.protocol)
@@ -46,24 +52,64 @@ pub fn spawn_scap_store(receiver : Receiver<ScapEvent>, traces : Option<&String>
                     };
                     data.from_client(&mut scap_data.data);
                 },
+                ScapEvent::Timeout(id, timeout) => {
+                    if let Some(data) = store.get_mut(&id) {
+                        data.timeout = Some(timeout);
+                    }
+                },
                 ScapEvent::Close(scap_addresses) => {
                     let scap = match store.remove(&scap_addresses.get_hash()) {
                         Some(v) => v,
                         None => continue
                     };
-                    log::info!("---- scap----");
-                    log::info!("{:?} ({:?})", scap.address, scap.protocol);
-                    let res = match scap.protocol {
-                        common::ScapProtocol::Http => http::process_scap_entry(scap, trace_location.as_ref()),
-                        common::ScapProtocol::Tcp => tcp::process_scap_entry(scap, trace_location.as_ref()),
-                        common::ScapProtocol::Tls => tcp::process_scap_entry(scap, trace_location.as_ref()),
-                        _ => continue
-                    };
-                    if let Err(e) = res {
-                        log::error!("{e}");
-                    }
+                    process_entry(scap, trace_location.as_ref());
                 },
+                ScapEvent::Shutdown => {
+                    log::info!("Storing {} open captures", store.len());
+                    for (_, scap) in std::mem::take(&mut store) {
+                        process_entry(scap, trace_location.as_ref());
+                    }
+                    return
+                }
             }
         }
-    });
+    })
+}
+
+fn process_entry(scap : ScapEntry, trace_location : Option<&std::path::PathBuf>) {
+    log::info!("---- scap----");
+    log::info!("{:?} ({:?})", scap.address, scap.protocol);
+    let res = match scap.protocol {
+        common::ScapProtocol::Http => http::process_scap_entry(scap, trace_location),
+        common::ScapProtocol::Tcp => tcp::process_scap_entry(scap, trace_location),
+        common::ScapProtocol::Tls => tcp::process_scap_entry(scap, trace_location),
+        common::ScapProtocol::Udp => tcp::process_scap_entry(scap, trace_location),
+        common::ScapProtocol::Ssh => tcp::process_scap_entry(scap, trace_location),
+        _ => return
+    };
+    if let Err(e) = res {
+        log::error!("{e}");
+    }
+}
+
+#[test]
+fn should_store_only_captured_connections_on_shutdown() {
+    use common::{ScapFilter, ScapProtocol, ScapStore};
+    let dir = std::env::temp_dir().join(format!("oxiproxy-scap-{}", std::process::id()));
+    let (sender, receiver) = crossbeam_channel::bounded(16);
+    let store = ScapStore::with_filter(sender, ScapFilter { protocols : vec![ScapProtocol::Tcp], ..Default::default() });
+    let handle = spawn_scap_store(receiver, Some(&dir.to_string_lossy().to_string())).unwrap();
+    let scap = store.reference();
+    let source = ([10, 0, 0, 2].into(), 40000);
+    // Still open when the proxy stops
+    let captured = scap.sender(ScapProtocol::Tcp, ([10, 0, 0, 1].into(), 22), source);
+    let excluded = scap.sender(ScapProtocol::Tls, ([10, 0, 0, 1].into(), 443), source);
+    store.set_enabled(false);
+    let paused = scap.sender(ScapProtocol::Tcp, ([10, 0, 0, 1].into(), 80), source);
+    store.shutdown();
+    handle.join().unwrap();
+    let traces : Vec<_> = std::fs::read_dir(&dir).unwrap().map(|v| v.unwrap().file_name().to_string_lossy().to_string()).collect();
+    assert_eq!(vec![captured.hash.to_string()], traces);
+    drop((captured, excluded, paused));
+    let _ = std::fs::remove_dir_all(dir);
 }
@@ -0,0 +1,150 @@
+use std::{net::TcpListener, os::fd::AsRawFd, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::JoinHandle, time::{Duration, Instant}};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+use super::{conn::registry::ConnRegistry, limit::ConnLimiter, scap::common::ScapStore};
+
+/// Time given to the connections to finish after being closed
+const CLOSE_GRACE : Duration = Duration::from_secs(5);
+
+/// Interval between checks of the open connections while draining
+const DRAIN_POLL : Duration = Duration::from_millis(200);
+
+/// Phase of the shutdown, shared with the listeners and the workers
+#[derive(Debug, Default)]
+pub struct ShutdownState {
+    /// The listeners stop accepting connections
+    pub stopping : AtomicBool,
+    /// The open connections are being closed and the queued ones are reset
+    pub closing : AtomicBool,
+}
+
+/// Stops the proxy without losing captures: stops accepting, lets the open connections finish until the drain
+/// timeout, closes the remaining ones and waits for the capture store to write every trace.
+pub struct ProxyShutdown {
+    pub state : Arc<ShutdownState>,
+    /// Copies of the listening sockets, shut down to wake the accept loops
+    pub listeners : Vec<TcpListener>,
+    pub limiter : Arc<ConnLimiter>,
+    pub registry : Arc<ConnRegistry>,
+    pub scap : ScapStore,
+    pub drain : Duration,
+}
+
+impl ShutdownState {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Acquire)
+    }
+}
+
+impl ProxyShutdown {
+    /// Waits for the first signal and stops the proxy. A second signal skips the drain.
+    pub fn run(self, signals : Receiver<()>, accept_loops : Vec<JoinHandle<()>>, store : JoinHandle<()>) {
+        if signals.recv().is_err() {
+            return
+        }
+        log::info!("Shutting down, waiting up to {}s for {} connections", self.drain.as_secs(), self.limiter.active());
+        self.state.stopping.store(true, Ordering::Release);
+        for listener in &self.listeners {
+            // Wakes the thread blocked in accept()
+            unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD) };
+        }
+        for handle in accept_loops {
+            let _ = handle.join();
+        }
+        let deadline = Instant::now() + self.drain;
+        while self.limiter.active() > 0 && Instant::now() < deadline {
+            match signals.recv_timeout(DRAIN_POLL) {
+                Ok(_) => {
+                    log::info!("Second signal received, closing the connections");
+                    break
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => std::thread::sleep(DRAIN_POLL)
+            }
+        }
+        let open = self.limiter.active();
+        if open > 0 {
+            log::info!("Closing {} connections", open);
+            self.state.closing.store(true, Ordering::Release);
+            self.registry.kill_all();
+            let deadline = Instant::now() + CLOSE_GRACE;
+            while self.limiter.active() > 0 && Instant::now() < deadline {
+                std::thread::sleep(DRAIN_POLL);
+            }
+            if self.limiter.active() > 0 {
+                log::warn!("{} connections did not finish", self.limiter.active());
+            }
+        }
+        log::info!("Writing the pending captures");
+        self.scap.shutdown();
+        let _ = store.join();
+        log::info!("Proxy stopped");
+    }
+}
+
+#[test]
+fn should_close_the_open_connections_after_the_drain() {
+    use std::{collections::BTreeSet, io::{Read, Write}, net::TcpStream, sync::Mutex};
+    use crate::proxy::{conn::{common::{ConnTimeouts, ProxyConnectionManager}, egress::DirectConnector}, limit::ConnLimits, scap::spawn_scap_store, tls::store::TlsCertStore, ListenMode};
+    let dir = std::env::temp_dir().join(format!("oxiproxy-shutdown-{}", std::process::id()));
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let scap = ScapStore::new(sender);
+    let store = spawn_scap_store(receiver, Some(&dir.to_string_lossy().to_string())).unwrap();
+    let limiter = ConnLimiter::new(ConnLimits::default());
+    let registry = ConnRegistry::new();
+    let state = ShutdownState::new();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (proxy_addr, origin_addr) = (proxy.local_addr().unwrap(), origin.local_addr().unwrap());
+    let listeners = vec![proxy.try_clone().unwrap()];
+    let accept_loop = {
+        let (scap, limiter, registry, state) = (scap.clone(), limiter.clone(), registry.clone(), state.clone());
+        std::thread::spawn(move || {
+            while let Ok((stream, peer)) = proxy.accept() {
+                if state.is_stopping() {
+                    break
+                }
+                let permit = limiter.acquire_source(peer.ip()).unwrap();
+                let tls = TlsCertStore::new(None, Arc::new(Mutex::new(BTreeSet::new())), None).unwrap();
+                let egress = Arc::new(DirectConnector { timeout : Some(Duration::from_secs(5)) });
+                let mut manager = ProxyConnectionManager::new(scap.reference(), tls, egress, ConnTimeouts::default(), limiter.clone(), registry.clone());
+                std::thread::spawn(move || {
+                    let _ = manager.handle_client(stream, ListenMode::Http, None);
+                    drop(permit);
+                });
+            }
+        })
+    };
+    let mut client = TcpStream::connect(proxy_addr).unwrap();
+    client.write_all(format!("CONNECT {origin_addr} HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n").as_bytes()).unwrap();
+    let mut established = [0u8; 39];
+    client.read_exact(&mut established).unwrap();
+    client.write_all(b"ping").unwrap();
+    let (mut server, _) = origin.accept().unwrap();
+    let mut ping = [0u8; 4];
+    server.read_exact(&mut ping).unwrap();
+
+    // The connection stays open during the whole drain and is closed after it
+    let (signals, signal_receiver) = crossbeam_channel::unbounded();
+    signals.send(()).unwrap();
+    let shutdown = ProxyShutdown { state : state.clone(), listeners, limiter : limiter.clone(), registry, scap, drain : Duration::from_millis(300) };
+    let started = Instant::now();
+    shutdown.run(signal_receiver, vec![accept_loop], store);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < CLOSE_GRACE);
+    assert!(state.is_closing());
+    assert_eq!(0, limiter.active());
+    assert_eq!(0, server.read(&mut ping).unwrap());
+    // Its capture was written before returning
+    assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,75 @@
+use std::mem::MaybeUninit;
+
+use crossbeam_channel::Sender;
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+use super::reload::ProxyReloader;
+
+/// Set of signals delivered synchronously to a dedicated thread instead of interrupting any thread of the proxy.
+/// The signals must be blocked before spawning the other threads so they inherit the mask.
+pub struct SignalSet {
+    set : libc::sigset_t,
+}
+
+impl SignalSet {
+    pub fn new(signals : &[libc::c_int]) -> std::io::Result<Self> {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        if unsafe { libc::sigemptyset(set.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        let mut set = unsafe { set.assume_init() };
+        for signal in signals {
+            if unsafe { libc::sigaddset(&mut set, *signal) } != 0 {
+                return Err(std::io::Error::last_os_error())
+            }
+        }
+        Ok(Self { set })
+    }
+
+    /// Blocks the signals in the current thread and the ones spawned from it
+    pub fn block(&self) -> std::io::Result<()> {
+        let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &self.set, std::ptr::null_mut()) };
+        if res != 0 {
+            return Err(std::io::Error::from_raw_os_error(res))
+        }
+        Ok(())
+    }
+
+    /// Waits until one of the signals is received and returns it
+    pub fn wait(&self) -> std::io::Result<libc::c_int> {
+        let mut signal = 0;
+        let res = unsafe { libc::sigwait(&self.set, &mut signal) };
+        if res != 0 {
+            return Err(std::io::Error::from_raw_os_error(res))
+        }
+        Ok(signal)
+    }
+}
+
+/// Handles the signals of the proxy: SIGHUP reloads the configuration and SIGTERM or SIGINT are forwarded to the
+/// shutdown. The signals must be blocked in every thread.
+pub fn spawn_signal_handler(signals : SignalSet, mut reloader : ProxyReloader, shutdown : Sender<()>) -> std::io::Result<()> {
+    std::thread::Builder::new().name("Signals".into()).spawn(move || {
+        loop {
+            match signals.wait() {
+                Ok(SIGHUP) => {
+                    log::info!("SIGHUP received, reloading the configuration");
+                    match reloader.reload() {
+                        Ok(_) => log::info!("Configuration reloaded"),
+                        Err(e) => log::error!("Cannot reload the configuration, keeping the current one: {e}")
+                    }
+                },
+                Ok(SIGTERM | SIGINT) => {
+                    let _ = shutdown.try_send(());
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    log::error!("Cannot wait for signals: {e}");
+                    return
+                }
+            }
+        }
+    })?;
+    Ok(())
+}
@@ -1,55 +1,88 @@
-use std::{io::{Error, ErrorKind, Read, Write}, net::{SocketAddr, TcpStream, UdpSocket}};
+use std::{io::{Error, ErrorKind, Read, Write}, net::{SocketAddr, TcpStream, UdpSocket}, os::fd::{AsRawFd, RawFd}, time::Duration};
 
-use crate::proxy::conn::stream::NonBlock;
+use crate::proxy::{conn::{dst::Destination, stream::NonBlock}, metrics::metrics};
 
-use super::common::{Socks5Greeting, Socks5MethodSelection, Socks5Request, Socks5Response, CMD_CONNECT, REP_ADDRESS_TYPE_NOT_SUPPORTED, REP_COMMAND_NOT_SUPPORTED, REP_CONNECTION_NOT_ALLOWED, REP_CONNECTION_REFUSED, REP_GENERAL_SOCKS_SERVER_FAILURE, REP_HOST_UNRECHABLE, REP_NETWORK_UNRECHABLE, REP_SUCCEEDED, REP_TTL_EXPIRED, SOCKS5_VERSION};
+use super::common::{Socks5Address, Socks5Credentials, Socks5Greeting, Socks5UdpHeader, CMD_UDP_ASSOCIATE, Socks5MethodSelection, Socks5Request, Socks5Response, Socks5UserPassResponse, CMD_CONNECT, NO_ACCEPTABLE_METHODS, NO_AUTHENTICATION, USERNAME_PASSWORD, USER_PASS_SUCCESS, REP_ADDRESS_TYPE_NOT_SUPPORTED, REP_COMMAND_NOT_SUPPORTED, REP_CONNECTION_NOT_ALLOWED, REP_CONNECTION_REFUSED, REP_GENERAL_SOCKS_SERVER_FAILURE, REP_HOST_UNRECHABLE, REP_NETWORK_UNRECHABLE, REP_SUCCEEDED, REP_TTL_EXPIRED, SOCKS5_VERSION};
 
 pub struct Socks5Client {
-    pub dst : SocketAddr,
+    pub dst : Destination,
     pub conn : TcpStream,
     pub buffer : Vec<u8>,
+    pub credentials : Option<Socks5Credentials>,
 }
 
 pub struct Socks5UdpClient {
-    pub dst : SocketAddr,
+    pub dst : Destination,
     pub conn : TcpStream,
     pub udp : UdpSocket,
+    pub relay : SocketAddr,
     pub buffer : Vec<u8>,
 }
 
 impl Socks5Client {
-    pub fn connect(addr : &str, dst : SocketAddr) -> std::io::Result<Self> {
+    pub fn connect(addr : &str, dst : Destination) -> std::io::Result<Self> {
         let stream = TcpStream::connect(addr)?;
-        let buffer = vec![0; 4096];
-        Ok(Self {
-            buffer,
-            conn : stream,
-            dst
-        })
+        Ok(Self::from_stream(stream, dst))
+    }
+
+    /// Uses an already established connection to the SOCKS5 server
+    pub fn from_stream(conn : TcpStream, dst : Destination) -> Self {
+        Self {
+            buffer : vec![0; 4096],
+            conn,
+            dst,
+            credentials : None
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials : Option<Socks5Credentials>) -> Self {
+        self.credentials = credentials;
+        self
     }
 
     pub fn greet(&mut self) -> std::io::Result<Socks5MethodSelection> {
         log::debug!("Greetings");
         let hello = Socks5Greeting {
             no_auth : true,
-            user_pass : false,
+            user_pass : self.credentials.is_some(),
             version : SOCKS5_VERSION
         };
         hello.write_to(&mut self.conn)?;
         let method = Socks5MethodSelection::read_from(&mut self.conn, &mut self.buffer)?;
+        match method.method {
+            NO_AUTHENTICATION => {},
+            USERNAME_PASSWORD => self.authenticate()?,
+            NO_ACCEPTABLE_METHODS => return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 server accepted none of the offered authentication methods")),
+            v => return Err(Error::new(ErrorKind::Unsupported, format!("SOCKS5 server selected an unsupported authentication method: {v}"))),
+        }
         log::debug!("Autenticado con el servidor");
         Ok(method)
     }
 
+    /// Username/Password sub-negotiation (RFC 1929)
+    fn authenticate(&mut self) -> std::io::Result<()> {
+        let credentials = match &self.credentials {
+            Some(v) => v,
+            None => return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 server requires credentials"))
+        };
+        log::debug!("Authenticating as {}", credentials.username);
+        credentials.write_to(&mut self.conn)?;
+        let res = Socks5UserPassResponse::read_from(&mut self.conn, &mut self.buffer)?;
+        if res.status != USER_PASS_SUCCESS {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("SOCKS5 authentication failed for user {}", credentials.username)))
+        }
+        Ok(())
+    }
+
     pub fn tcp_proxy(&mut self) -> std::io::Result<()> {
         let req = Socks5Request {
             version : SOCKS5_VERSION,
             cmd : CMD_CONNECT,
             rsv : 0x0,
-            dst_addr : self.dst.ip().into(),
+            dst_addr : self.dst.to_socks5(),
             dst_port : self.dst.port()
         };
-        log::debug!("Sending Socks5Request to: {}", self.dst.ip());
+        log::debug!("Sending Socks5Request to: {}", self.dst);
         req.write_to(&mut self.conn)?;
         log::debug!("Receiving response");
         let res = Socks5Response::read_from(&mut self.conn, &mut self.buffer)?;
@@ -66,31 +99,45 @@ impl Socks5Client {
         Ok(())
     }
 
+    /// Opens a UDP ASSOCIATE. The control connection must be kept open while the association is in use
     pub fn udp_proxy(mut self) -> std::io::Result<Socks5UdpClient> {
+        let udp = UdpSocket::bind(SocketAddr::new(self.conn.local_addr()?.ip(), 0))?;
+        let local = udp.local_addr()?;
         let req = Socks5Request {
             version : SOCKS5_VERSION,
-            cmd : CMD_CONNECT,
+            cmd : CMD_UDP_ASSOCIATE,
             rsv : 0x0,
-            dst_addr : self.dst.ip().into(),
-            dst_port : self.dst.port()
+            dst_addr : local.ip().into(),
+            dst_port : local.port()
         };
+        log::debug!("Sending UDP ASSOCIATE from: {}", local);
         req.write_to(&mut self.conn)?;
         let res = Socks5Response::read_from(&mut self.conn, &mut self.buffer)?;
         Self::raise_response(&res)?;
-        let udp = UdpSocket::bind("127.0.0.1:0")?;
+        let relay_ip = match &res.bnd_addr {
+            Socks5Address::Domain(_) => self.conn.peer_addr()?.ip(),
+            v if v.to_ip_addr().is_unspecified() => self.conn.peer_addr()?.ip(),
+            v => v.to_ip_addr()
+        };
+        let relay = SocketAddr::new(relay_ip, res.bnd_port);
+        log::debug!("UDP relay at: {}", relay);
+        udp.connect(relay)?;
         let clnt = Socks5UdpClient {
-            buffer : self.buffer,
+            buffer : vec![0; 65_536],
             conn : self.conn,
             dst : self.dst,
-            udp
+            udp,
+            relay
         };
         Ok(clnt)
     }
 
     pub fn raise_response(res : &Socks5Response) -> std::io::Result<()> {
+        metrics().socks5_replies.with_label_values(&[&res.reply.to_string()]).inc();
         Err(match res.reply {
             REP_SUCCEEDED => return Ok(()),
-            REP_GENERAL_SOCKS_SERVER_FAILURE => Error::new(ErrorKind::Interrupted, "General server failure"),
+            // Not Interrupted: the relay retries those, and the server refused the connection for good
+            REP_GENERAL_SOCKS_SERVER_FAILURE => Error::other("General server failure"),
             REP_CONNECTION_NOT_ALLOWED => Error::new(ErrorKind::ConnectionRefused, "Connection not allowed"),
             REP_NETWORK_UNRECHABLE => Error::new(ErrorKind::NotConnected, "Network unrechable"),
             REP_HOST_UNRECHABLE => Error::new(ErrorKind::NotConnected, "Host unrechable"),
@@ -98,7 +145,7 @@ impl Socks5Client {
             REP_TTL_EXPIRED => Error::new(ErrorKind::NotConnected, "TTL Expired"),
             REP_COMMAND_NOT_SUPPORTED => Error::new(ErrorKind::Unsupported, "Command not supported"),
             REP_ADDRESS_TYPE_NOT_SUPPORTED => Error::new(ErrorKind::InvalidInput, "Address type not supported"),
-            _ => Error::new(ErrorKind::Interrupted, "General server failure"),
+            v => Error::other(format!("Unknown SOCKS5 reply: {v}")),
         })
     }
 
@@ -127,4 +174,75 @@ impl NonBlock for Socks5Client {
     fn set_non_blocking(&self, nonblocking : bool) -> std::io::Result<()> {
         self.conn.set_nonblocking(nonblocking)
     }
-}
\ No newline at end of file
+
+    fn raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.conn.shutdown_write()
+    }
+
+    fn set_timeouts(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        self.conn.set_timeouts(timeout)
+    }
+
+    fn spliceable(&self) -> bool {
+        true
+    }
+}
+
+impl Socks5UdpClient {
+    /// Sends a datagram to the destination through the relay
+    pub fn send_to(&mut self, dst : &Destination, data : &[u8]) -> std::io::Result<usize> {
+        let header = Socks5UdpHeader {
+            frag : 0,
+            dst_addr : dst.to_socks5(),
+            dst_port : dst.port()
+        };
+        let mut datagram = Vec::with_capacity(data.len() + 22);
+        header.write_to(&mut datagram)?;
+        datagram.extend_from_slice(data);
+        self.udp.send(&datagram)?;
+        Ok(data.len())
+    }
+
+    /// Receives a datagram from the relay, removing the SOCKS5 header
+    pub fn recv_from(&mut self, buf : &mut [u8]) -> std::io::Result<(Destination, usize)> {
+        let readed = self.udp.recv(&mut self.buffer)?;
+        let (header, pos) = Socks5UdpHeader::read_from(&self.buffer[0..readed])?;
+        if header.frag != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Fragmented datagrams are not supported"))
+        }
+        let len = (readed - pos).min(buf.len());
+        buf[0..len].copy_from_slice(&self.buffer[pos..pos + len]);
+        Ok((Destination::from_socks5(&header.dst_addr, header.dst_port), len))
+    }
+}
+#[test]
+fn should_require_credentials_when_the_server_asks_for_them() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 3];
+        conn.read_exact(&mut greeting).unwrap();
+        conn.write_all(&[SOCKS5_VERSION, USERNAME_PASSWORD]).unwrap();
+    });
+    let mut client = Socks5Client::connect(&addr.to_string(), Destination::Domain("example.com".into(), 443)).unwrap();
+    let err = client.greet().unwrap_err();
+    assert_eq!(ErrorKind::PermissionDenied, err.kind());
+    assert_eq!("SOCKS5 server requires credentials", err.to_string());
+    server.join().unwrap();
+}
+
+#[test]
+fn should_fail_for_good_on_server_errors() {
+    let response = |reply : u8| Socks5Response { version : SOCKS5_VERSION, reply, rsv : 0, bnd_addr : Socks5Address::V4([0, 0, 0, 0].into()), bnd_port : 0 };
+    assert!(Socks5Client::raise_response(&response(REP_SUCCEEDED)).is_ok());
+    assert_eq!(ErrorKind::ConnectionRefused, Socks5Client::raise_response(&response(REP_CONNECTION_REFUSED)).unwrap_err().kind());
+    assert_eq!(ErrorKind::Other, Socks5Client::raise_response(&response(REP_GENERAL_SOCKS_SERVER_FAILURE)).unwrap_err().kind());
+    let err = Socks5Client::raise_response(&response(0x42)).unwrap_err();
+    assert_eq!(ErrorKind::Other, err.kind());
+    assert_eq!("Unknown SOCKS5 reply: 66", err.to_string());
+}
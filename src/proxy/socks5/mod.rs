@@ -1,2 +1,3 @@
 pub mod common;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod server;
\ No newline at end of file
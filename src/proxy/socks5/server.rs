@@ -0,0 +1,122 @@
+use std::{io::{Error, ErrorKind, Read, Write}, net::SocketAddr};
+
+use crate::proxy::conn::dst::Destination;
+
+use super::common::{Socks5Greeting, Socks5MethodSelection, Socks5Request, Socks5Response, CMD_CONNECT, CMD_UDP_ASSOCIATE, NO_ACCEPTABLE_METHODS, NO_AUTHENTICATION, REP_ADDRESS_TYPE_NOT_SUPPORTED, REP_COMMAND_NOT_SUPPORTED, REP_CONNECTION_NOT_ALLOWED, REP_CONNECTION_REFUSED, REP_GENERAL_SOCKS_SERVER_FAILURE, REP_HOST_UNRECHABLE, REP_NETWORK_UNRECHABLE, REP_TTL_EXPIRED, SOCKS5_VERSION};
+
+/// Command requested by a SOCKS5 client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Socks5Command {
+    Connect(Destination),
+    /// Address the client will send the datagrams from. It can be unspecified if the client does not know it yet.
+    UdpAssociate(Destination),
+}
+
+/// Server side of the SOCKS5 handshake, used when oxiproxy is configured as an explicit SOCKS5 proxy.
+pub struct Socks5Server<'a, S> {
+    pub conn : &'a mut S,
+    pub buffer : &'a mut [u8],
+}
+
+impl<'a, S> Socks5Server<'a, S> where S : Read + Write {
+    pub fn new(conn : &'a mut S, buffer : &'a mut [u8]) -> Self {
+        Self { conn, buffer }
+    }
+
+    /// Reads the client greeting and selects the authentication method. Only no-auth is supported.
+    pub fn greet(&mut self) -> std::io::Result<()> {
+        let hello = Socks5Greeting::read_from(self.conn, self.buffer)?;
+        if hello.version != SOCKS5_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid SOCKS version: {}", hello.version)))
+        }
+        if !hello.no_auth {
+            Socks5MethodSelection { version : SOCKS5_VERSION, method : NO_ACCEPTABLE_METHODS }.write_to(self.conn)?;
+            return Err(Error::new(ErrorKind::PermissionDenied, "Client does not support no-auth method"))
+        }
+        Socks5MethodSelection { version : SOCKS5_VERSION, method : NO_AUTHENTICATION }.write_to(self.conn)
+    }
+
+    /// Reads the client request. CONNECT and UDP ASSOCIATE are accepted.
+    pub fn request(&mut self) -> std::io::Result<Socks5Command> {
+        let req = Socks5Request::read_from(self.conn, self.buffer)?;
+        log::debug!("Socks5Request {} to: {}:{}", req.cmd, req.dst_addr, req.dst_port);
+        let dst = Destination::from_socks5(&req.dst_addr, req.dst_port);
+        match req.cmd {
+            CMD_CONNECT => Ok(Socks5Command::Connect(dst)),
+            CMD_UDP_ASSOCIATE => Ok(Socks5Command::UdpAssociate(dst)),
+            _ => {
+                self.reply(REP_COMMAND_NOT_SUPPORTED, None)?;
+                Err(Error::new(ErrorKind::Unsupported, format!("Command not supported: {}", req.cmd)))
+            }
+        }
+    }
+
+    pub fn reply(&mut self, reply : u8, bnd : Option<SocketAddr>) -> std::io::Result<()> {
+        let bnd = bnd.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let res = Socks5Response {
+            version : SOCKS5_VERSION,
+            reply,
+            rsv : 0x0,
+            bnd_addr : bnd.ip().into(),
+            bnd_port : bnd.port()
+        };
+        res.write_to(self.conn)
+    }
+
+    pub fn reply_error(&mut self, err : &Error) -> std::io::Result<()> {
+        self.reply(reply_of_error(err), None)
+    }
+}
+
+/// Maps an upstream connection error to the SOCKS5 reply code. Inverse of `Socks5Client::raise_response`.
+pub fn reply_of_error(err : &Error) -> u8 {
+    match err.kind() {
+        ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        ErrorKind::PermissionDenied | ErrorKind::QuotaExceeded => REP_CONNECTION_NOT_ALLOWED,
+        ErrorKind::NetworkUnreachable => REP_NETWORK_UNRECHABLE,
+        ErrorKind::HostUnreachable | ErrorKind::NotFound | ErrorKind::NotConnected => REP_HOST_UNRECHABLE,
+        ErrorKind::TimedOut => REP_TTL_EXPIRED,
+        ErrorKind::Unsupported => REP_COMMAND_NOT_SUPPORTED,
+        ErrorKind::InvalidInput => REP_ADDRESS_TYPE_NOT_SUPPORTED,
+        _ => REP_GENERAL_SOCKS_SERVER_FAILURE,
+    }
+}
+
+#[cfg(test)]
+struct TestConn {
+    input : std::io::Cursor<Vec<u8>>,
+    output : Vec<u8>
+}
+
+#[cfg(test)]
+impl Read for TestConn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for TestConn {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn should_accept_domain_connect() {
+    let mut input = vec![SOCKS5_VERSION, 1, NO_AUTHENTICATION];
+    input.extend_from_slice(&[SOCKS5_VERSION, CMD_CONNECT, 0, 0x03, 11]);
+    input.extend_from_slice(b"example.com");
+    input.extend_from_slice(&443u16.to_be_bytes());
+    let mut conn = TestConn { input : std::io::Cursor::new(input), output : Vec::new() };
+    let mut buffer = vec![0; 512];
+    let mut server = Socks5Server::new(&mut conn, &mut buffer);
+    server.greet().unwrap();
+    let cmd = server.request().unwrap();
+    assert_eq!(Socks5Command::Connect(Destination::Domain("example.com".into(), 443)), cmd);
+    assert_eq!(&[SOCKS5_VERSION, NO_AUTHENTICATION], &conn.output[..]);
+}
@@ -0,0 +1,149 @@
+use std::{fs::{File, OpenOptions}, io::{ErrorKind, Read, Write}, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use rcgen::KeyPair;
+use ring::digest::{digest, SHA256};
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+use serde::{Deserialize, Serialize};
+
+/// Upstream chain a generated certificate was cloned from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertOrigin {
+    /// SHA-256 of the certificate of the server
+    pub upstream : String,
+    /// Common name of the ROOT CA that signs the cloned chain
+    pub ca : String,
+    /// SHA-256 of the public key of that ROOT CA
+    pub ca_key : String,
+    /// When the first certificate of the cloned chain expires, in seconds since the epoch
+    pub not_after : i64,
+}
+
+/// Generated chain read from the cache
+pub struct CachedCert {
+    pub name : String,
+    pub origin : CertOrigin,
+    /// End certificate followed by the cloned intermediates
+    pub chain : Vec<CertificateDer<'static>>,
+    /// Key of each certificate of the chain, in the same order
+    pub keys : Vec<KeyPair>,
+}
+
+/// Folder keeping the generated certificates between restarts. Each name has a folder with the chain cloned from
+/// the current certificate of the server: `<name>/<fingerprint>.pem` (chain), `.key` (keys) and `.json` (origin).
+pub struct CertCache {
+    dir : PathBuf,
+}
+
+impl CertCache {
+    pub fn new(dir : &str) -> std::io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Stores the chain of a name, replacing the one cloned from a previous certificate of the server
+    pub fn store(&self, name : &str, origin : &CertOrigin, chain : &[CertificateDer<'_>], keys : &[&KeyPair]) -> std::io::Result<()> {
+        let dir = self.name_dir(name)?;
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        let certs : Vec<pem::Pem> = chain.iter().map(|v| pem::Pem::new("CERTIFICATE", v.to_vec())).collect();
+        File::create(dir.join(format!("{}.pem", origin.upstream)))?.write_all(pem::encode_many(&certs).as_bytes())?;
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(dir.join(format!("{}.key", origin.upstream)))?;
+        for key in keys {
+            file.write_all(key.serialize_pem().as_bytes())?;
+        }
+        // Written last: entries without origin are incomplete and ignored
+        let json = serde_json::to_vec_pretty(origin).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        File::create(dir.join(format!("{}.json", origin.upstream)))?.write_all(&json)?;
+        Ok(())
+    }
+
+    /// Reads every stored chain. Invalid entries are skipped.
+    pub fn load(&self) -> std::io::Result<Vec<CachedCert>> {
+        let mut entries = Vec::new();
+        for dir in std::fs::read_dir(&self.dir)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue
+            }
+            let name = dir.file_name().to_string_lossy().to_string();
+            for file in std::fs::read_dir(dir.path())? {
+                let path = file?.path();
+                if path.extension().is_none_or(|v| v != "json") {
+                    continue
+                }
+                match read_entry(&name, &path) {
+                    Ok(v) => entries.push(v),
+                    Err(e) => log::warn!("Ignoring cached certificate {}: {e}", path.to_string_lossy())
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn remove(&self, name : &str) -> std::io::Result<()> {
+        match std::fs::remove_dir_all(self.name_dir(name)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(())
+        }
+    }
+
+    fn name_dir(&self, name : &str) -> std::io::Result<PathBuf> {
+        let valid = |c : char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '*');
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(valid) {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Invalid name for the certificate cache: {name}")))
+        }
+        Ok(self.dir.join(name.to_lowercase()))
+    }
+}
+
+fn read_entry(name : &str, origin_path : &Path) -> std::io::Result<CachedCert> {
+    let invalid = |e : String| std::io::Error::new(ErrorKind::InvalidData, e);
+    let origin : CertOrigin = serde_json::from_slice(&std::fs::read(origin_path)?).map_err(|e| invalid(e.to_string()))?;
+    let chain = std::fs::read(origin_path.with_extension("pem"))?;
+    let chain = CertificateDer::pem_slice_iter(&chain).collect::<Result<Vec<_>, _>>().map_err(|e| invalid(e.to_string()))?;
+    let mut keys = String::new();
+    File::open(origin_path.with_extension("key"))?.read_to_string(&mut keys)?;
+    let keys = pem::parse_many(&keys).map_err(|e| invalid(e.to_string()))?;
+    let keys = keys.iter().map(|v| KeyPair::from_pem(&pem::encode(v))).collect::<Result<Vec<_>, _>>().map_err(|e| invalid(e.to_string()))?;
+    if chain.is_empty() || chain.len() != keys.len() {
+        return Err(invalid(format!("{} certificates and {} keys", chain.len(), keys.len())))
+    }
+    Ok(CachedCert { name : name.to_string(), origin, chain, keys })
+}
+
+/// Hex encoded SHA-256 of the data
+pub fn fingerprint(data : &[u8]) -> String {
+    digest(&SHA256, data).as_ref().iter().map(|v| format!("{v:02x}")).collect()
+}
+
+/// Seconds since the epoch
+pub fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_secs() as i64).unwrap_or_default()
+}
+
+#[test]
+fn should_store_and_load_cached_chains() {
+    use rcgen::CertificateParams;
+    let dir = std::env::temp_dir().join(format!("oxiproxy-cache-{}", std::process::id()));
+    let cache = CertCache::new(&dir.to_string_lossy()).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["example.com".to_string()]).unwrap().self_signed(&key).unwrap();
+    let mut origin = CertOrigin { upstream : fingerprint(b"old"), ca : "Root".into(), ca_key : fingerprint(b"key"), not_after : unix_now() };
+    cache.store("example.com", &origin, &[cert.der().clone()], &[&key]).unwrap();
+    // A new certificate of the server replaces the previous chain
+    origin.upstream = fingerprint(b"new");
+    cache.store("example.com", &origin, &[cert.der().clone()], &[&key]).unwrap();
+    assert!(cache.store("../etc", &origin, &[cert.der().clone()], &[&key]).is_err());
+    let entries = cache.load().unwrap();
+    assert_eq!(1, entries.len());
+    assert_eq!("example.com", entries[0].name);
+    assert_eq!(origin, entries[0].origin);
+    assert_eq!(cert.der(), &entries[0].chain[0]);
+    assert_eq!(key.serialize_der(), entries[0].keys[0].serialize_der());
+    cache.remove("example.com").unwrap();
+    assert!(cache.load().unwrap().is_empty());
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -1,23 +1,32 @@
-use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs::File, io::{ErrorKind, Read}, path::PathBuf, sync::Arc};
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs::File, io::{ErrorKind, Read}, path::{Path, PathBuf}, sync::Arc};
 
 use rcgen::{Certificate, CertificateParams, KeyPair};
 use rustls::sign::CertifiedKey;
 use rustls_pki_types::{pem::PemObject, CertificateDer};
 
 
-use super::common_name_of_params;
+use super::{cache::CertOrigin, common_name_of_params};
 
 /// Stores End-Certificates
 pub struct CertDb {
     idx_name : BTreeMap<String, Arc<CertifiedKey>>,
-    idx_hash : HashMap<Vec<u8>, Arc<CertifiedKey>>
+    idx_hash : HashMap<Vec<u8>, Arc<CertifiedKey>>,
+    /// Upstream chain each certificate was cloned from
+    origins : BTreeMap<String, CertOrigin>
+}
+
+impl Default for CertDb {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CertDb {
     pub fn new() -> Self {
         Self {
             idx_hash : HashMap::new(),
-            idx_name : BTreeMap::new()
+            idx_name : BTreeMap::new(),
+            origins : BTreeMap::new()
         }
     }
 
@@ -30,18 +39,47 @@ impl CertDb {
     }
 
     pub fn get_by_name(&self, name : &str) -> Option<Arc<CertifiedKey>> {
-        self.idx_name.get(name).map(|v| v.clone())
+        self.idx_name.get(name).cloned()
     }
     pub fn get_by_hash(&self, data : &[u8]) -> Option<Arc<CertifiedKey>> {
-        self.idx_hash.get(data).map(|v| v.clone())
+        self.idx_hash.get(data).cloned()
     }
 
     pub fn insert(&mut self, name : String, cert : Arc<CertifiedKey>) {
+        self.remove(&name);
         self.idx_name.insert(name, cert.clone());
-        if let Some(v) = cert.end_entity_cert().ok() {
+        if let Ok(v) = cert.end_entity_cert() {
             self.idx_hash.insert(v.to_vec(), cert);
         }
     }
+
+    pub fn set_origin(&mut self, name : String, origin : CertOrigin) {
+        self.origins.insert(name, origin);
+    }
+
+    pub fn origin(&self, name : &str) -> Option<&CertOrigin> {
+        self.origins.get(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.idx_name.keys().cloned().collect()
+    }
+
+    /// Forgets the certificate of a name, so it is generated again on the next connection
+    pub fn remove(&mut self, name : &str) -> bool {
+        self.origins.remove(name);
+        let cert = match self.idx_name.remove(name) {
+            Some(v) => v,
+            None => return false
+        };
+        if let Ok(v) = cert.end_entity_cert() {
+            // Other names can share the same certificate
+            if !self.idx_name.values().any(|other| Arc::ptr_eq(other, &cert)) {
+                self.idx_hash.remove(v.as_ref());
+            }
+        }
+        true
+    }
 }
 
 
@@ -106,10 +144,15 @@ impl CaDb {
     }
 
     pub fn get_by_name(&self, name : &str) -> Option<(Arc<Certificate>, Arc<KeyPair>)> {
-        self.idx_name.get(name).map(|v| v.clone())
+        self.idx_name.get(name).cloned()
     }
     pub fn get_by_hash(&self, data : &[u8]) -> Option<(Arc<Certificate>, Arc<KeyPair>)> {
-        self.idx_hash.get(data).map(|v| v.clone())
+        self.idx_hash.get(data).cloned()
+    }
+
+    /// Checks if both databases hold the same names and keys
+    pub fn same_keys(&self, other : &CaDb) -> bool {
+        self.idx_name.keys().eq(other.idx_name.keys()) && self.idx_hash.len() == other.idx_hash.len() && self.idx_hash.keys().all(|k| other.idx_hash.contains_key(k))
     }
 
     pub fn insert(&mut self, cert : Arc<Certificate>, key : Arc<KeyPair>) {
@@ -118,7 +161,7 @@ impl CaDb {
     }
 
     fn insert_name(&mut self, cert : &Arc<Certificate>, key : &Arc<KeyPair>) -> Option<()>{
-        let name = common_name_of_params(&cert.params())?;
+        let name = common_name_of_params(cert.params())?;
         if let Some(v) = self.idx_name.insert(name.clone(), (cert.clone(), key.clone())) {
             log::warn!("There was alredy a certificate with name {name} in CertDB {:?}:\n{}", self.name, v.0.pem());
         }
@@ -127,6 +170,6 @@ impl CaDb {
 }
 
 
-fn invalid_certificate(pth : &PathBuf) -> std::io::Error {
+fn invalid_certificate(pth : &Path) -> std::io::Error {
     std::io::Error::new(ErrorKind::InvalidData, format!("Invalid certificate {}", pth.to_string_lossy()))
 }
\ No newline at end of file
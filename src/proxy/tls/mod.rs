@@ -8,13 +8,15 @@ pub mod verify;
 pub mod sign;
 pub mod db;
 pub mod store;
+pub mod cache;
+pub mod operator;
 
-pub fn from_arc_to_static<'a>(r: &'a Arc<Certificate>) -> &'static Certificate {
+pub fn from_arc_to_static(r: &Arc<Certificate>) -> &'static Certificate {
     let r = r.as_ref();
     unsafe { std::mem::transmute(r) }
 }
 
-pub fn from_arc_to_static_der<'a>(r: &'a Arc<Certificate>) -> CertificateDer<'static> {
+pub fn from_arc_to_static_der(r: &Arc<Certificate>) -> CertificateDer<'static> {
     let r = r.as_ref();
     let crt : &'static Certificate = unsafe { std::mem::transmute(r) };
     CertificateDer::from_slice(crt.der().as_ref())
@@ -0,0 +1,163 @@
+use std::{path::Path, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+use rcgen::{Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::sign::CertifiedKey;
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+
+use crate::proxy::metrics::metrics;
+
+use super::{db::CertDb, sign::signing_key};
+
+/// Validity of the generated certificates, below the 398 days accepted by browsers
+const LEAF_VALIDITY : Duration = Duration::from_secs(397 * 24 * 3600);
+
+/// The certificates are valid from a day before being generated, in case the clock of the client is late
+const CLOCK_SKEW : Duration = Duration::from_secs(24 * 3600);
+
+/// Single CA installed by the operator in the clients. It signs a certificate for each name instead of cloning
+/// the chain of the server.
+pub struct OperatorCa {
+    cert : Certificate,
+    key : KeyPair,
+    /// Certificate as read from the file, to detect changes when reloading
+    der : CertificateDer<'static>,
+    certs : Mutex<CertDb>,
+}
+
+impl OperatorCa {
+    /// Reads the PEM certificate of the CA. The key is read from the file with the same name and `.key` extension.
+    pub fn from_file(path : &str) -> std::io::Result<Self> {
+        let cert_path = Path::new(path);
+        let key_path = cert_path.with_extension("key");
+        let der = CertificateDer::from_pem_file(cert_path).map_err(|e| invalid(format!("Invalid operator CA {path}: {e}")))?;
+        let key = KeyPair::from_pem(&std::fs::read_to_string(&key_path)?).map_err(|e| invalid(format!("Invalid operator CA key {}: {e}", key_path.to_string_lossy())))?;
+        let (_, parsed) = x509_parser::parse_x509_certificate(&der).map_err(|e| invalid(format!("Invalid operator CA {path}: {e}")))?;
+        // Signing with another key would produce leaves the clients cannot verify
+        if parsed.public_key().raw != key.public_key_der() {
+            return Err(invalid(format!("The key {} does not belong to the operator CA {path}", key_path.to_string_lossy())))
+        }
+        let params = CertificateParams::from_ca_cert_der(&der).map_err(|e| invalid(format!("Invalid operator CA {path}: {e}")))?;
+        if !matches!(params.is_ca, IsCa::Ca(_)) {
+            return Err(invalid(format!("The operator CA {path} is not a CA certificate")))
+        }
+        // Only the parameters of this certificate are used to sign: subject, key identifier and validity
+        let cert = params.self_signed(&key).map_err(|e| invalid(format!("Cannot use the operator CA {path}: {e}")))?;
+        Ok(Self {
+            cert,
+            key,
+            der,
+            certs : Mutex::new(CertDb::new())
+        })
+    }
+
+    /// Checks if both hold the same CA certificate
+    pub fn same_cert(&self, other : &OperatorCa) -> bool {
+        self.der == other.der
+    }
+
+    /// Returns the certificate of a name, signing it the first time
+    pub fn resolve(&self, name : &str) -> Option<Arc<CertifiedKey>> {
+        let mut certs = self.certs.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(v) = certs.get_by_name(name) {
+            metrics().certificate_cache_hits.inc();
+            return Some(v)
+        }
+        let certkey = match self.sign(name) {
+            Some(v) => v,
+            None => {
+                log::warn!("Cannot sign a certificate for {name} with the operator CA");
+                return None
+            }
+        };
+        metrics().certificates_generated.inc();
+        certs.insert(name.to_string(), certkey.clone());
+        Some(certkey)
+    }
+
+    pub fn generated(&self) -> Vec<String> {
+        self.certs.lock().unwrap_or_else(|e| e.into_inner()).names()
+    }
+
+    pub fn evict(&self, name : &str) -> bool {
+        self.certs.lock().unwrap_or_else(|e| e.into_inner()).remove(name)
+    }
+
+    fn sign(&self, name : &str) -> Option<Arc<CertifiedKey>> {
+        // The name goes to the SAN, as a DNS name or an IP address
+        let mut params = CertificateParams::new(vec![name.to_string()]).ok()?;
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::ExplicitNoCa;
+        // The key is ECDSA, so it only signs
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        params.serial_number = Some(random_serial()?.into());
+        let now = SystemTime::now();
+        params.not_before = (now - CLOCK_SKEW).into();
+        params.not_after = (now + LEAF_VALIDITY).into();
+        if params.not_after > self.cert.params().not_after {
+            params.not_after = self.cert.params().not_after;
+        }
+        let key = KeyPair::generate().ok()?;
+        let cert = params.signed_by(&key, &self.cert, &self.key).ok()?;
+        log::debug!("Signed a certificate for {name} with the operator CA");
+        Some(Arc::new(CertifiedKey::new(vec![cert.der().clone()], signing_key(&key)?)))
+    }
+}
+
+/// Positive serial number of 16 random bytes
+pub fn random_serial() -> Option<Vec<u8>> {
+    let mut serial = vec![0u8; 16];
+    SystemRandom::new().fill(&mut serial).ok()?;
+    serial[0] &= 0x7f;
+    Some(serial)
+}
+
+fn invalid(msg : String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn should_sign_server_certificates() {
+    use rcgen::{BasicConstraints, SanType};
+    let dir = std::env::temp_dir().join(format!("oxiproxy-operator-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "Operator CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    std::fs::write(dir.join("ca.key"), key.serialize_pem()).unwrap();
+    let operator = OperatorCa::from_file(&dir.join("ca.pem").to_string_lossy()).unwrap();
+    let certkey = operator.resolve("api.example.com").unwrap();
+    assert!(Arc::ptr_eq(&certkey, &operator.resolve("api.example.com").unwrap()));
+    let leaf = CertificateParams::from_ca_cert_der(&certkey.cert[0]).unwrap();
+    assert_eq!(IsCa::ExplicitNoCa, leaf.is_ca);
+    assert!(matches!(&leaf.subject_alt_names[..], [SanType::DnsName(v)] if v.as_str() == "api.example.com"));
+    assert_eq!(vec![ExtendedKeyUsagePurpose::ServerAuth], leaf.extended_key_usages);
+    assert_eq!(vec![KeyUsagePurpose::DigitalSignature], leaf.key_usages);
+
+    let (_, leaf) = x509_parser::parse_x509_certificate(&certkey.cert[0]).unwrap();
+    let (_, ca) = x509_parser::parse_x509_certificate(ca.der()).unwrap();
+    let key_usage = leaf.key_usage().unwrap().unwrap().value;
+    assert!(key_usage.digital_signature() && !key_usage.key_encipherment());
+    let eku = leaf.extended_key_usage().unwrap().unwrap().value;
+    assert!(eku.server_auth && !eku.client_auth && eku.other.is_empty());
+    let ski = ca.extensions().iter().find_map(|v| match v.parsed_extension() {
+        x509_parser::extensions::ParsedExtension::SubjectKeyIdentifier(v) => Some(v.0),
+        _ => None
+    }).unwrap();
+    let aki = leaf.extensions().iter().find_map(|v| match v.parsed_extension() {
+        x509_parser::extensions::ParsedExtension::AuthorityKeyIdentifier(v) => v.key_identifier.as_ref().map(|v| v.0),
+        _ => None
+    }).unwrap();
+    assert_eq!(ski, aki);
+
+    // A key of another CA is rejected
+    std::fs::write(dir.join("ca.key"), KeyPair::generate().unwrap().serialize_pem()).unwrap();
+    let err = OperatorCa::from_file(&dir.join("ca.pem").to_string_lossy()).err().unwrap();
+    assert!(err.to_string().contains("ca.key does not belong"), "{err}");
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -1,66 +1,160 @@
 use std::{
     collections::{BTreeSet, LinkedList},
-    net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
 };
 
-use rcgen::{Certificate, CertificateParams, Ia5String, IsCa, KeyPair};
-use rustls::{
-    server::ResolvesServerCert, sign::SigningKey, ClientConfig, ClientConnection, StreamOwned,
-};
-use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use rcgen::{Certificate, CertificateParams, CustomExtension, IsCa, KeyPair};
+use rustls::{sign::CertifiedKey, ClientConnection};
+use rustls_pki_types::CertificateDer;
+use x509_parser::oid_registry::{OID_CT_LIST_SCT, OID_X509_EXT_AUTHORITY_KEY_IDENTIFIER};
+
+use crate::proxy::metrics::metrics;
 
 use super::{
-    common_name_of_cert, common_name_of_params, db::{CaDb, CertDb}, from_arc_to_static, from_arc_to_static_der, sign::SignKeyWrapper
+    cache::{fingerprint, unix_now, CachedCert, CertCache, CertOrigin}, common_name_of_cert, common_name_of_params, db::{CaDb, CertDb}, from_arc_to_static, from_arc_to_static_der, sign::{key_pair_like, signing_key, SignKeyWrapper}
 };
 
+/// Cloned chains expiring sooner are cloned again if the server has a newer one
+const REFRESH_MARGIN: i64 = 7 * 24 * 3600;
+
 pub struct CertResolver {
     store: Arc<Mutex<CertDb>>,
     /// Pre generated ROOT CA list
-    ca: Arc<CaDb>,
+    ca: RwLock<Arc<CaDb>>,
     inter: Arc<Mutex<CaDb>>,
     pinned: Arc<Mutex<BTreeSet<String>>>,
-    cconfig: Arc<ClientConfig>,
+    /// Keeps the generated chains between restarts
+    cache: Option<CertCache>,
 }
 
 impl CertResolver {
-    pub fn new(cconfig: Arc<ClientConfig>, ca: Arc<CaDb>, pinned: Arc<Mutex<BTreeSet<String>>>) -> Self {
-        Self {
+    pub fn new(ca: Arc<CaDb>, pinned: Arc<Mutex<BTreeSet<String>>>, cache: Option<CertCache>) -> Self {
+        let resolver = Self {
             store: Arc::new(Mutex::new(CertDb::new())),
-            ca,
+            ca: RwLock::new(ca),
             inter: Arc::new(Mutex::new(CaDb::new("Interm".into()))),
             pinned,
-            cconfig,
-        }
+            cache,
+        };
+        resolver.load_cache();
+        resolver
     }
 }
 
-impl ResolvesServerCert for CertResolver {
-    fn resolve(
-        &self,
-        client_hello: rustls::server::ClientHello<'_>,
-    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
-        let name = client_hello.server_name()?;
+impl CertResolver {
+    /// Returns the cloned chain of the server, generating it from the certificates of the upstream connection
+    /// the first time. The server is pinned if its chain cannot be cloned.
+    pub fn resolve(&self, conn: &ClientConnection, name: &str) -> Option<Arc<rustls::sign::CertifiedKey>> {
         let store = self.store.lock().ok()?;
-        let store = if !store.contains_name(name) {
+        let stale = store.origin(name).is_some_and(|v| is_stale(v, conn));
+        let store = if !store.contains_name(name) || stale {
             drop(store);
-            let conn = self.connect_to_real_server(name)?;
+            if stale {
+                log::info!("The chain of {name} changed or expires soon, cloning it again");
+            }
             log::debug!("Process {name} certs");
-            if let None = self.process_conn_certs(&conn, name) {
+            if self.process_conn_certs(conn, name).is_none() {
                 log::debug!("No certs processed??");
                 self.set_server_as_pinned(name)?;
                 return None
             };
             log::debug!("Certs correctly processed");
+            metrics().certificates_generated.inc();
             self.store.lock().ok()?
         } else {
+            metrics().certificate_cache_hits.inc();
             store
         };
         store.get_by_name(name)
     }
-}
 
-impl CertResolver {
+    /// Replaces the ROOT CA list. The generated certificates are forgotten if the CA keys changed, as they are
+    /// signed with the old ones.
+    pub fn set_ca(&self, ca: CaDb) {
+        let mut guard = self.ca.write().unwrap_or_else(|e| e.into_inner());
+        if guard.same_keys(&ca) {
+            *guard = Arc::new(ca);
+            return
+        }
+        *guard = Arc::new(ca);
+        drop(guard);
+        log::info!("ROOT CA keys changed, discarding the generated certificates");
+        *self.store.lock().unwrap_or_else(|e| e.into_inner()) = CertDb::new();
+        *self.inter.lock().unwrap_or_else(|e| e.into_inner()) = CaDb::new("Interm".into());
+    }
+
+    /// Names with a generated certificate
+    pub fn generated(&self) -> Vec<String> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).names()
+    }
+
+    /// Discards the generated certificate of a name
+    pub fn evict(&self, name: &str) -> bool {
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.remove(name) {
+                log::warn!("Cannot remove {name} from the certificate cache: {e}");
+            }
+        }
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).remove(name)
+    }
+
+    /// Loads the chains of the cache signed by the current ROOT CAs
+    fn load_cache(&self) {
+        let cache = match &self.cache {
+            Some(v) => v,
+            None => return
+        };
+        let entries = match cache.load() {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Cannot read the certificate cache: {e}");
+                return
+            }
+        };
+        let ca = self.ca.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let total = entries.len();
+        let mut loaded = 0;
+        for entry in entries {
+            let name = entry.name.clone();
+            match self.insert_cached(&ca, entry) {
+                Some(_) => loaded += 1,
+                None => log::debug!("Ignoring the cached chain of {name}: expired or signed by another ROOT CA")
+            }
+        }
+        log::info!("Loaded {loaded} of {total} cached certificates");
+    }
+
+    fn insert_cached(&self, ca: &CaDb, entry: CachedCert) -> Option<()> {
+        if entry.origin.not_after <= unix_now() {
+            return None
+        }
+        let (mut issuer, mut issuer_key) = ca.get_by_name(&entry.origin.ca)?;
+        if fingerprint(&issuer_key.public_key_der()) != entry.origin.ca_key {
+            return None
+        }
+        let keys: Vec<Arc<KeyPair>> = entry.keys.into_iter().map(Arc::new).collect();
+        // rcgen cannot sign with a parsed certificate, so the intermediates are signed again with the same keys to
+        // clone other servers. The stored chain is the one presented for this name.
+        let mut inter = self.inter.lock().ok()?;
+        for (der, key) in entry.chain.iter().zip(&keys).skip(1).rev() {
+            let params = clone_params(der)?;
+            let cert = Arc::new(params.signed_by(key.as_ref(), &issuer, &issuer_key).ok()?);
+            let known = common_name_of_cert(&cert)
+                .and_then(|v| inter.get_by_name(&v))
+                .is_some_and(|(v, _)| v.params().not_after == cert.params().not_after);
+            if !known {
+                inter.insert(cert.clone(), key.clone());
+            }
+            (issuer, issuer_key) = (cert, key.clone());
+        }
+        drop(inter);
+        let key = signing_key(&keys[0])?;
+        let certkey = Arc::new(CertifiedKey::new(entry.chain, key));
+        let mut store = self.store.lock().ok()?;
+        store.insert(entry.name.clone(), certkey);
+        store.set_origin(entry.name, entry.origin);
+        Some(())
+    }
 
     fn set_server_as_pinned(&self, name : &str) -> Option<()> {
         let mut guard = self.pinned.lock().ok()?;
@@ -68,19 +162,6 @@ impl CertResolver {
         None
     }
 
-    pub fn connect_to_real_server(&self, name: &str) -> Option<ClientConnection> {
-        log::debug!("Connecting to: {name}");
-        let sn = ServerName::try_from(name.to_string()).ok()?;
-        let mut conn = ClientConnection::new(self.cconfig.clone(), sn).ok()?;
-        let mut stream = TcpStream::connect((name, 443)).ok()?;
-        if let Err(e) = conn.complete_io(&mut stream) {
-            log::trace!("Connection to {name} CompleteIO error: {e}");
-            return None;
-        }
-        let real_server = StreamOwned::new(conn, stream);
-        Some(real_server.conn)
-    }
-
     pub fn process_conn_certs(&self, conn: &ClientConnection, name : &str) -> Option<()> {
         let certs = conn.peer_certificates()?;
         log::debug!("Cert number: {}", certs.len());
@@ -109,25 +190,40 @@ impl CertResolver {
                 None => {
                     let (prev_cert, prev_key) = cert_keys.front()?;
                     let (prev_cert, prev_key) =
-                        clone_real_cert(cert, &prev_cert, &prev_key)?;
+                        clone_real_cert(cert, prev_cert, prev_key)?;
                     (prev_cert, Arc::new(prev_key))
                 }
             };
             cert_keys.push_front((int_cert, int_key));
         }
-        let _ = cert_keys.pop_back()?; //ROOT CA
+        let (root, root_key) = cert_keys.pop_back()?; //ROOT CA
         let end_cert = iter.next()?;
         let (prev_cert, prev_key) = cert_keys.front()?;
-        let (server_cert, server_key) = clone_end_cert(end_cert, name, prev_cert, prev_key)?;
+        let (server_cert, server_key) = clone_end_cert(end_cert, prev_cert, prev_key)?;
         let server_key = Arc::new(server_key);
         let mut int_certs = Vec::new();
         for (int_cert, _) in &cert_keys {
             int_certs.push(int_cert);
         }
         log::debug!("Intermediate certs: {}", int_certs.len());
-        let server_certkey = self.to_certkey(server_cert, server_key, int_certs)?;
+        let not_after = int_certs.iter().map(|v| v.params().not_after).chain([server_cert.params().not_after]).min()?;
+        let origin = CertOrigin {
+            upstream: fingerprint(end_cert),
+            ca: common_name_of_cert(&root)?,
+            ca_key: fingerprint(&root_key.public_key_der()),
+            not_after: not_after.unix_timestamp(),
+        };
+        let server_certkey = self.to_certkey(server_cert, server_key.clone(), int_certs)?;
+        if let Some(cache) = &self.cache {
+            let mut keys = vec![server_key.as_ref()];
+            keys.extend(cert_keys.iter().map(|(_, v)| v.as_ref()));
+            if let Err(e) = cache.store(name, &origin, &server_certkey.cert, &keys) {
+                log::warn!("Cannot store the certificate of {name} in the cache: {e}");
+            }
+        }
         let mut guard = self.store.lock().ok()?;
         guard.insert(name.to_string(), server_certkey);
+        guard.set_origin(name.to_string(), origin);
         drop(guard);
 
         let mut guard = self.inter.lock().ok()?;
@@ -145,16 +241,17 @@ impl CertResolver {
         if certp.is_ca == IsCa::ExplicitNoCa || certp.is_ca == IsCa::NoCa {
             return None
         }
+        let ca = self.ca.read().ok()?.clone();
         if let Some(sn) = &certp.serial_number {
             log::debug!("SN of CA: {:?}", sn.as_ref());
-            if let Some(v) = self.ca.get_by_hash(sn.as_ref()) {
+            if let Some(v) = ca.get_by_hash(sn.as_ref()) {
                 return Some(v);
             }
         }
         
         let common_name = common_name_of_params(&certp)?;
         log::debug!("Common name of CA: {common_name}");
-        self.ca.get_by_name(&common_name)
+        ca.get_by_name(&common_name)
     }
 
     pub fn get_intermediate_cert(
@@ -167,7 +264,8 @@ impl CertResolver {
             return Some(v);
         }
         let common_name = common_name_of_params(&certp)?;
-        guard.get_by_name(&common_name)
+        // A clone of another version of the intermediate is cloned again
+        guard.get_by_name(&common_name).filter(|(v, _)| v.params().not_after == certp.not_after)
     }
 
     pub fn to_certkey(
@@ -181,9 +279,7 @@ impl CertResolver {
 
         let der = cert.der();
         let der = CertificateDer::from_slice(der.as_ref());
-        let key = PrivateKeyDer::from_pem_slice(key_pair.serialize_pem().as_bytes()).ok()?;
-        let key: Arc<dyn SigningKey> =
-            rustls::crypto::aws_lc_rs::sign::any_ecdsa_type(&key).ok()?;
+        let key = signing_key(&key_pair)?;
         let mut chain = Vec::with_capacity(3);
         chain.push(der);
         for crt in int_certs {
@@ -204,10 +300,60 @@ impl std::fmt::Debug for CertResolver {
 }
 
 
+/// Checks if the server presents another certificate than the one cloned, or a chain lasting longer when the
+/// cloned one is about to expire
+fn is_stale(origin: &CertOrigin, conn: &ClientConnection) -> bool {
+    let certs = match conn.peer_certificates() {
+        Some(v) if !v.is_empty() => v,
+        _ => return false
+    };
+    if fingerprint(&certs[0]) != origin.upstream {
+        return true
+    }
+    if origin.not_after > unix_now() + REFRESH_MARGIN {
+        return false
+    }
+    // The last certificate is signed by one of the ROOT CAs
+    let upstream = certs[..certs.len() - 1].iter()
+        .filter_map(|v| CertificateParams::from_ca_cert_der(v).ok())
+        .map(|v| v.not_after.unix_timestamp())
+        .min();
+    upstream.is_some_and(|v| v > origin.not_after)
+}
+
+/// Parameters to sign a copy of a certificate. Every extension is copied byte for byte with its criticality, so the
+/// clone keeps the SANs, usages, basic constraints, policies, AIA and CRL URLs even if rcgen cannot parse them.
+/// rcgen only writes the authority key identifier, from the key identifier of the cloned issuer, and the SCTs are
+/// dropped because they are signed over the original certificate.
+pub fn clone_params(cert: &CertificateDer<'_>) -> Option<CertificateParams> {
+    let mut params = CertificateParams::from_ca_cert_der(cert).ok()?;
+    let (_, x509) = x509_parser::parse_x509_certificate(cert).ok()?;
+    params.subject_alt_names.clear();
+    params.key_usages.clear();
+    params.extended_key_usages.clear();
+    params.name_constraints = None;
+    params.is_ca = IsCa::NoCa;
+    params.use_authority_key_identifier_extension = false;
+    for ext in x509.extensions() {
+        if ext.oid == OID_X509_EXT_AUTHORITY_KEY_IDENTIFIER {
+            params.use_authority_key_identifier_extension = true;
+            continue
+        }
+        if ext.oid == OID_CT_LIST_SCT {
+            continue
+        }
+        let oid: Vec<u64> = ext.oid.iter()?.collect();
+        let mut custom = CustomExtension::from_oid_content(&oid, ext.value.to_vec());
+        custom.set_criticality(ext.critical);
+        params.custom_extensions.push(custom);
+    }
+    Some(params)
+}
+
 pub fn clone_ca_cert(cert: &CertificateDer<'_>) -> Option<(Arc<Certificate>, KeyPair)> {
-    let cert = CertificateParams::from_ca_cert_der(cert).ok()?;
+    let keypair = key_pair_like(cert)?;
+    let cert = clone_params(cert)?;
     let _ = cert.serial_number.as_ref()?;
-    let keypair = KeyPair::generate().ok()?;
     let cert = cert.self_signed(&keypair).ok()?;
     Some((Arc::new(cert), keypair))
 }
@@ -217,33 +363,21 @@ pub fn clone_real_cert(
     prev_cert: &Certificate,
     prev_key: &KeyPair,
 ) -> Option<(Arc<Certificate>, KeyPair)> {
-    let cert = CertificateParams::from_ca_cert_der(cert).ok()?;
-    let keypair = KeyPair::generate().ok()?;
+    let keypair = key_pair_like(cert)?;
+    let cert = clone_params(cert)?;
     let cert = cert.signed_by(&keypair, prev_cert, prev_key).ok()?;
-    log::info!("INT CERT:\n{}", cert.pem());
-    log::info!("INT KEY:\n{}", keypair.serialize_pem());
+    log::trace!("Cloned intermediate certificate:\n{}", cert.pem());
     Some((Arc::new(cert), keypair))
 }
 
 pub fn clone_end_cert(
     cert: &CertificateDer<'_>,
-    name : &str,
     prev_cert: &Certificate,
     prev_key: &KeyPair,
 ) -> Option<(Arc<Certificate>, KeyPair)> {
-    let mut cert = CertificateParams::from_ca_cert_der(cert).ok()?;
-    cert.use_authority_key_identifier_extension = true;
-    //cert.key_usages.push(rcgen::KeyUsagePurpose::DigitalSignature);
-    //cert.extended_key_usages.push(rcgen::ExtendedKeyUsagePurpose::ServerAuth);
-    //cert.subject_alt_names.push(rcgen::SanType::DnsName(Ia5String::try_from(name).ok()?));
-    if name.contains("*."){
-        let new_name = name.replace("*.", "");
-        cert.subject_alt_names.push(rcgen::SanType::DnsName(Ia5String::try_from(new_name).ok()?));
-    }
-    cert.is_ca = IsCa::NoCa;
-    let keypair = KeyPair::generate().ok()?;
+    let keypair = key_pair_like(cert)?;
+    let cert = clone_params(cert)?;
     let cert = cert.signed_by(&keypair, prev_cert, prev_key).ok()?;
-    log::info!("CERT:\n{}", cert.pem());
-    log::info!("KEY:\n{}", keypair.serialize_pem());
+    log::trace!("Cloned certificate:\n{}", cert.pem());
     Some((Arc::new(cert), keypair))
-}
\ No newline at end of file
+}
@@ -1,7 +1,74 @@
 use std::sync::Arc;
 
-use rcgen::{Certificate, KeyPair};
+use rcgen::{Certificate, KeyPair, RsaKeySize};
 use rustls::sign::{Signer, SigningKey};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use x509_parser::{oid_registry::{OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_PKCS1_RSAENCRYPTION, OID_SIG_ED25519}, public_key::PublicKey};
+
+/// Key algorithm of a generated certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyType {
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+}
+
+impl KeyType {
+    pub fn generate(&self) -> Result<KeyPair, rcgen::Error> {
+        match self {
+            KeyType::EcdsaP256 => KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256),
+            KeyType::EcdsaP384 => KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384),
+            KeyType::Ed25519 => KeyPair::generate_for(&rcgen::PKCS_ED25519),
+            KeyType::Rsa2048 => KeyPair::generate_rsa_for(&rcgen::PKCS_RSA_SHA256, RsaKeySize::_2048),
+            KeyType::Rsa3072 => KeyPair::generate_rsa_for(&rcgen::PKCS_RSA_SHA256, RsaKeySize::_3072),
+            KeyType::Rsa4096 => KeyPair::generate_rsa_for(&rcgen::PKCS_RSA_SHA256, RsaKeySize::_4096),
+        }
+    }
+
+    /// Key type of the public key of a certificate. RSA keys of other sizes get the closest supported size.
+    pub fn of_cert(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+        let spki = &cert.tbs_certificate.subject_pki;
+        let algorithm = &spki.algorithm.algorithm;
+        if *algorithm == OID_PKCS1_RSAENCRYPTION {
+            let size = match spki.parsed().ok()? {
+                PublicKey::RSA(v) => v.key_size(),
+                _ => return None
+            };
+            return Some(match size {
+                0..=2048 => KeyType::Rsa2048,
+                2049..=3072 => KeyType::Rsa3072,
+                _ => KeyType::Rsa4096,
+            })
+        }
+        if *algorithm == OID_SIG_ED25519 {
+            return Some(KeyType::Ed25519)
+        }
+        if *algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+            let curve = spki.algorithm.parameters.as_ref()?.as_oid().ok()?;
+            if curve == OID_EC_P256 {
+                return Some(KeyType::EcdsaP256)
+            }
+            if curve == OID_NIST_EC_P384 {
+                return Some(KeyType::EcdsaP384)
+            }
+        }
+        None
+    }
+}
+
+/// Generates a key of the same type and size as the one of the certificate, so the clone cannot be told apart by
+/// its key. Unsupported keys are replaced by a P-256 key.
+pub fn key_pair_like(cert: &CertificateDer<'_>) -> Option<KeyPair> {
+    let key_type = KeyType::of_cert(cert).unwrap_or_else(|| {
+        log::debug!("Unsupported key algorithm, using ECDSA P-256 for the clone");
+        KeyType::EcdsaP256
+    });
+    key_type.generate().ok()
+}
 
 #[allow(unused)]
 pub struct SignKeyWrapper {
@@ -34,4 +101,24 @@ impl std::fmt::Debug for SignKeyWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SignKeyWrapper").finish()
     }
-}
\ No newline at end of file
+}
+
+/// Key used by rustls to sign the handshakes with a generated certificate
+pub fn signing_key(key_pair: &KeyPair) -> Option<Arc<dyn SigningKey>> {
+    let key = PrivateKeyDer::from_pem_slice(key_pair.serialize_pem().as_bytes()).ok()?;
+    rustls::crypto::aws_lc_rs::sign::any_supported_type(&key).ok()
+}
+
+#[test]
+fn should_mirror_the_key_type() {
+    use rcgen::CertificateParams;
+    for key_type in [KeyType::Rsa3072, KeyType::EcdsaP384, KeyType::Ed25519] {
+        let key = key_type.generate().unwrap();
+        let cert = CertificateParams::new(vec!["example.com".to_string()]).unwrap().self_signed(&key).unwrap();
+        assert_eq!(Some(key_type), KeyType::of_cert(cert.der()));
+        let clone = key_pair_like(cert.der()).unwrap();
+        let cloned = CertificateParams::new(vec!["example.com".to_string()]).unwrap().self_signed(&clone).unwrap();
+        assert_eq!(Some(key_type), KeyType::of_cert(cloned.der()));
+        assert!(signing_key(&clone).is_some());
+    }
+}
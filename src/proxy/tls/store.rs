@@ -1,22 +1,54 @@
-use std::{collections::{BTreeMap, BTreeSet}, sync::{Arc, Mutex}};
+use std::{collections::BTreeSet, sync::{Arc, Mutex, RwLock}};
 
-use rcgen::generate_simple_self_signed;
-use rustls::{ ClientConfig, ServerConfig};
+use rustls::{sign::{CertifiedKey, SingleCertAndKey}, ClientConfig, ClientConnection, ServerConfig};
 
-use crate::proxy::tls::db::CaDb;
+use crate::proxy::{conn::{dst::Destination, route::DstPattern}, tls::db::CaDb};
 
-use super::{resolv::CertResolver, verify::AnyVerifier};
+use super::{cache::CertCache, operator::OperatorCa, resolv::CertResolver, verify::AnyVerifier};
+
+/// How the certificate presented to the client is obtained
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CertMode {
+    /// Clone the chain of the server with the cloned ROOT CAs
+    #[default]
+    Clone,
+    /// Sign a certificate for the SNI with the operator CA
+    Operator,
+}
+
+/// Certificate mode of each destination
+#[derive(Debug, Clone, Default)]
+pub struct CertModes {
+    pub default : CertMode,
+    /// The first pattern matching the destination or the SNI selects the mode
+    pub rules : Vec<(DstPattern, CertMode)>,
+}
 
 #[derive(Clone)]
 pub struct TlsCertStore {
-    pub sconfig: Arc<ServerConfig>,
+    pub resolver: Arc<CertResolver>,
     pub cconfig: Arc<ClientConfig>,
-    pub pinned: Arc<Mutex<BTreeSet<String>>>
+    pub pinned: Arc<Mutex<BTreeSet<String>>>,
+    /// Pinned domains that come only from the configuration, replaced when it is reloaded
+    pub configured: Arc<Mutex<BTreeSet<String>>>,
+    pub operator: Arc<RwLock<Option<Arc<OperatorCa>>>>,
+    pub modes: Arc<RwLock<Arc<CertModes>>>,
+}
+
+impl CertModes {
+    pub fn mode_of(&self, dst: &Destination, name: &str) -> CertMode {
+        let sni = Destination::Domain(name.to_string(), dst.port());
+        self.rules.iter().find(|(pattern, _)| pattern.matches(dst) || pattern.matches(&sni)).map(|(_, mode)| *mode).unwrap_or(self.default)
+    }
 }
 
 impl TlsCertStore {
-    pub fn new(ca_location: &str, pinned : Arc<Mutex<BTreeSet<String>>>) -> std::io::Result<Self> {
-        let db = CaDb::from_dir("CA".into(), ca_location)?;
+    /// Creates the store with the ROOT CAs of a folder, if any. The generated certificates are kept in `cache`, if
+    /// given. The initial `pinned` domains are the ones of the configuration.
+    pub fn new(ca_location: Option<&str>, pinned : Arc<Mutex<BTreeSet<String>>>, cache: Option<&str>) -> std::io::Result<Self> {
+        let db = ca_db(ca_location)?;
+        let cache = cache.map(CertCache::new).transpose()?;
         let verifier = Arc::new(AnyVerifier{});
         let cconfig = Arc::new(
             ClientConfig::builder()
@@ -24,18 +56,125 @@ impl TlsCertStore {
                 .with_custom_certificate_verifier(verifier.clone())
                 .with_no_client_auth(),
         );
-        let resolver = Arc::new(CertResolver::new(cconfig.clone(), Arc::new(db), pinned.clone()));
-        let conf = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_cert_resolver(resolver.clone());
-        let sconfig = Arc::new(conf);
+        let resolver = Arc::new(CertResolver::new(Arc::new(db), pinned.clone(), cache));
+        let configured = pinned.lock().unwrap_or_else(|e| e.into_inner()).clone();
 
         Ok(Self {
             cconfig,
-            sconfig,
-            pinned
+            resolver,
+            pinned,
+            configured: Arc::new(Mutex::new(configured)),
+            operator: Arc::new(RwLock::new(None)),
+            modes: Arc::new(RwLock::new(Arc::new(CertModes::default())))
         })
     }
+
+    /// Certificate presented to the client for `name`: cloned from the chain of the server or signed by the
+    /// operator CA, depending on the destination
+    pub fn certificate(&self, dst: &Destination, conn: &ClientConnection, name: &str) -> Option<Arc<CertifiedKey>> {
+        let mode = self.modes.read().unwrap_or_else(|e| e.into_inner()).mode_of(dst, name);
+        match mode {
+            CertMode::Clone => self.resolver.resolve(conn, name),
+            CertMode::Operator => {
+                let operator = self.operator.read().unwrap_or_else(|e| e.into_inner()).clone();
+                match operator {
+                    Some(v) => v.resolve(name),
+                    None => {
+                        log::warn!("No operator CA to sign the certificate of {name}");
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces the operator CA. The signed certificates are kept if the CA did not change.
+    pub fn set_operator(&self, operator: Option<OperatorCa>) {
+        let mut guard = self.operator.write().unwrap_or_else(|e| e.into_inner());
+        if let (Some(current), Some(new)) = (guard.as_ref(), operator.as_ref()) {
+            if current.same_cert(new) {
+                return
+            }
+        }
+        *guard = operator.map(Arc::new);
+    }
+
+    pub fn set_modes(&self, modes: CertModes) {
+        *self.modes.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(modes);
+    }
+
+    /// Names with a generated certificate, cloned or signed by the operator CA
+    pub fn generated(&self) -> Vec<String> {
+        let mut names = self.resolver.generated();
+        if let Some(operator) = self.operator.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            names.extend(operator.generated());
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Discards the generated certificates of a name
+    pub fn evict(&self, name: &str) -> bool {
+        let operator = match self.operator.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            Some(v) => v.evict(name),
+            None => false
+        };
+        self.resolver.evict(name) || operator
+    }
+
+    /// Configuration for the client handshake of a single connection, presenting the cloned chain and the ALPN
+    /// protocol negotiated with the real server
+    pub fn server_config(&self, key: Arc<CertifiedKey>, alpn: Option<Vec<u8>>) -> Arc<ServerConfig> {
+        let mut conf = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SingleCertAndKey::from(key)));
+        conf.alpn_protocols = alpn.into_iter().collect();
+        Arc::new(conf)
+    }
+
+    /// Configuration for the upstream handshake offering the ALPN protocols of the client
+    pub fn client_config(&self, alpn: Vec<Vec<u8>>) -> Arc<ClientConfig> {
+        if alpn.is_empty() {
+            return self.cconfig.clone()
+        }
+        let mut conf = self.cconfig.as_ref().clone();
+        conf.alpn_protocols = alpn;
+        Arc::new(conf)
+    }
+    /// Reads again the ROOT CA folder and replaces the CA used to sign the cloned chains
+    pub fn reload_ca(&self, ca_location: Option<&str>) -> std::io::Result<()> {
+        self.resolver.set_ca(ca_db(ca_location)?);
+        Ok(())
+    }
+
+    /// Replaces the domains pinned by the configuration, keeping the ones learned from the connections or pinned
+    /// from the admin API even if the previous configuration also had them
+    pub fn replace_pinned(&self, current: &[String]) {
+        let mut g = self.pinned.lock().unwrap_or_else(|e| e.into_inner());
+        let mut configured = self.configured.lock().unwrap_or_else(|e| e.into_inner());
+        for v in std::mem::take(&mut *configured) {
+            g.remove(&v);
+        }
+        for v in current {
+            let v = v.to_lowercase();
+            if g.insert(v.clone()) {
+                configured.insert(v);
+            }
+        }
+    }
+
+    pub fn pinned_list(&self) -> Vec<String> {
+        self.pinned.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    /// Intercepts again a pinned domain or IP. Returns false if it was not pinned.
+    pub fn enable_addr(&self, addr: &str) -> bool {
+        let mut g = self.pinned.lock().unwrap_or_else(|e| e.into_inner());
+        self.configured.lock().unwrap_or_else(|e| e.into_inner()).remove(addr);
+        g.remove(addr)
+    }
+
     pub fn disable_addr(&self, addr: String) {
         let mut g = match self.pinned.lock() {
             Ok(v) => v,
@@ -46,6 +185,8 @@ impl TlsCertStore {
                 p
             }
         };
+        // Learned, it survives the reloads of the configuration
+        self.configured.lock().unwrap_or_else(|e| e.into_inner()).remove(&addr);
         g.insert(addr);
     }
 
@@ -61,4 +202,12 @@ impl TlsCertStore {
         };
         g.contains(addr)
     }
-}
\ No newline at end of file
+}
+
+/// ROOT CAs used to clone the chains. Without them only the operator CA can be used.
+fn ca_db(location: Option<&str>) -> std::io::Result<CaDb> {
+    match location {
+        Some(v) => CaDb::from_dir("CA".into(), v),
+        None => Ok(CaDb::new("CA".into()))
+    }
+}
@@ -0,0 +1,54 @@
+use std::{io::{BufRead, BufReader, Read, Write}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex}, time::Duration};
+
+use oxiproxy::{config::ProxyConfig, start_proxy_with_connector, Destination, UpstreamConnector, UpstreamStream};
+
+/// Sends every connection to a local echo server, recording the requested destinations
+struct EchoConnector {
+    origin : String,
+    requested : Arc<Mutex<Vec<String>>>,
+}
+
+impl UpstreamConnector for EchoConnector {
+    fn connect(&self, dst : &Destination) -> std::io::Result<Box<dyn UpstreamStream>> {
+        self.requested.lock().unwrap().push(dst.to_string());
+        Ok(Box::new(TcpStream::connect(&self.origin)?))
+    }
+}
+
+#[test]
+fn should_reach_the_destinations_with_a_custom_connector() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let (mut conn, _) = origin.accept().unwrap();
+        let mut buf = [0u8; 64];
+        let readed = conn.read(&mut buf).unwrap();
+        conn.write_all(&buf[..readed]).unwrap();
+    });
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ProxyConfig::from_toml(&format!("[[listener]]\nmode = \"http\"\naddr = \"127.0.0.1\"\nport = {port}\n")).unwrap();
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let connector = Arc::new(EchoConnector { origin : origin_addr, requested : requested.clone() });
+    // Runs until the process ends
+    std::thread::spawn(move || start_proxy_with_connector(config, connector));
+
+    let mut client = (0..50).find_map(|_| {
+        std::thread::sleep(Duration::from_millis(20));
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    }).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    client.write_all(b"CONNECT service.internal:7000 HTTP/1.1\r\nHost: service.internal:7000\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("HTTP/1.1 200"), "{line}");
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    client.write_all(b"ping\n").unwrap();
+    let mut echo = [0u8; 5];
+    reader.read_exact(&mut echo).unwrap();
+    assert_eq!(b"ping\n", &echo);
+    assert_eq!(vec!["service.internal:7000".to_string()], *requested.lock().unwrap());
+}